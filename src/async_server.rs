@@ -0,0 +1,576 @@
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(test)]
+use std::net::SocketAddr;
+
+#[cfg(test)]
+use tokio::net::ToSocketAddrs;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::{Handle, RuntimeFlavor},
+    time,
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    proxy::TrustedProxies,
+    request::{Framing, Limits, ParsedRequest, Request, RequestParser},
+    response_writer::ResponseWriter,
+    server::{self, ConnCounter, ConnCtrl, ConnInfo, Handler, Timeouts},
+    status_code_registry::ReasonPhrase,
+    stream::PeerAddr,
+    timed_stream::{RequestTimeout, MIN_RATE_GRACE},
+};
+
+/// Handles requests on a tokio runtime. Requests are received completely
+/// before `handle` is called.
+pub trait AsyncHandler: Send + Sync {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) -> impl Future<Output = ()> + Send;
+
+    /// See [`Handler::check_request`].
+    fn check_request(&self, _r: &Request) -> Result<(), ReasonPhrase> {
+        Ok(())
+    }
+}
+
+/// Runs a synchronous handler on a tokio worker thread. Requires the
+/// multi-threaded runtime, on others requests fail with 500.
+pub struct Blocking<H>(pub H);
+
+impl<H> AsyncHandler for Blocking<H>
+where
+    H: Handler + Send + Sync,
+{
+    async fn handle(&self, w: &mut ResponseWriter, r: &mut Request<'_>) {
+        // `block_in_place` panics on the current-thread runtime.
+        if Handle::current().runtime_flavor() != RuntimeFlavor::MultiThread {
+            error!("blocking handlers need the multi-threaded tokio runtime");
+            w.set_reason_phrase(ReasonPhrase::InternalServerError);
+            return;
+        }
+        tokio::task::block_in_place(|| self.0.handle(w, r))
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.0.check_request(r)
+    }
+}
+
+/// Serves connections as tasks on the current tokio runtime. Responses are
+/// buffered before they are sent.
+#[derive(Debug)]
+pub struct AsyncServer {
+    listener: TcpListener,
+    limits: Limits,
+    timeouts: Timeouts,
+    trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl AsyncServer {
+    #[cfg(test)]
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::from_listener(TcpListener::bind(addr).await?))
+    }
+
+    /// Takes over a listener of the standard library. Must be called on the
+    /// runtime.
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener).map(Self::from_listener)
+    }
+
+    fn from_listener(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            trusted_proxies: Arc::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// See [`Server::set_trusted_proxies`](crate::server::Server::set_trusted_proxies).
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: TrustedProxies) {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(self, handler: impl AsyncHandler + 'static) {
+        let handler = Arc::new(handler);
+        let conn_counter = Arc::new(ConnCounter::default());
+        let limits = self.limits;
+        let timeouts = Arc::new(self.timeouts);
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(?err);
+                    continue;
+                }
+            };
+
+            let handler = handler.clone();
+            let conn_counter = conn_counter.clone();
+            let timeouts = timeouts.clone();
+            let id = server::next_conn_id();
+            let info = ConnInfo {
+                id,
+                peer_addr: Some(PeerAddr::Tcp(peer_addr)),
+                local_addr: stream.local_addr().ok(),
+                client_addr: Some(peer_addr.ip()),
+                trusted_proxies: self.trusted_proxies.clone(),
+                ..Default::default()
+            };
+            let span = server::create_conn_span(id, Ok(PeerAddr::Tcp(peer_addr)));
+            let task = async move {
+                let Some(_slot) =
+                    conn_counter.acquire(PeerAddr::Tcp(peer_addr), limits.conns_per_ip)
+                else {
+                    warn!("too many connections");
+                    let _ = reject_connection(stream).await;
+                    return;
+                };
+
+                info!("new conn");
+                let conn = Conn::new(stream, info, limits);
+                if let Err(err) = conn.run(&timeouts, &*handler).await {
+                    error!(?err);
+                }
+                info!("conn end");
+            };
+            tokio::spawn(task.instrument(span));
+        }
+    }
+}
+
+/// Answers with 503 and closes the connection.
+async fn reject_connection(mut stream: TcpStream) -> io::Result<()> {
+    let mut w = ResponseWriter::new_empty();
+    w.set_reason_phrase(ReasonPhrase::ServiceUnavailable);
+    w.add_connection_header("close");
+    let mut output = vec![];
+    w.write_to(&mut output)?;
+    stream.write_all(&output).await?;
+    linger(stream).await;
+    Ok(())
+}
+
+/// Closes the sending side and drains what the client still sends, so that
+/// the last response isn't lost to a reset.
+async fn linger(mut stream: TcpStream) {
+    const MAX_LINGER: usize = 256 * 1024;
+    const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let drain = async {
+        let mut buf = [0; 4096];
+        let mut total = 0;
+        while total < MAX_LINGER {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => total += n,
+            }
+        }
+    };
+    let _ = time::timeout(LINGER_TIMEOUT, drain).await;
+}
+
+struct Conn {
+    stream: TcpStream,
+    info: ConnInfo,
+    limits: Limits,
+    parser: RequestParser,
+    input: Vec<u8>,
+    request_count: usize,
+    pipeline_depth: usize,
+}
+
+/// Outcome of receiving a request.
+enum Received {
+    Request(ParsedRequest),
+    /// The connection ended between requests.
+    Closed,
+    /// The request was answered before it was received completely.
+    Answered,
+}
+
+impl Conn {
+    fn new(stream: TcpStream, info: ConnInfo, limits: Limits) -> Self {
+        Self {
+            stream,
+            info,
+            limits,
+            parser: RequestParser::new(limits),
+            input: vec![],
+            request_count: 0,
+            pipeline_depth: 0,
+        }
+    }
+
+    async fn run(mut self, timeouts: &Timeouts, handler: &impl AsyncHandler) -> io::Result<()> {
+        self.serve(timeouts, handler).await?;
+        linger(self.stream).await;
+        Ok(())
+    }
+
+    /// Answers requests until the connection has to be closed.
+    async fn serve(&mut self, timeouts: &Timeouts, handler: &impl AsyncHandler) -> io::Result<()> {
+        loop {
+            let parsed = match self.receive(timeouts, handler).await {
+                Ok(Received::Request(parsed)) => parsed,
+                Ok(Received::Closed) | Ok(Received::Answered) => return Ok(()),
+                Err(err) => {
+                    debug!(?err);
+                    let Some(reason_phrase) = server::error_reason_phrase(&err) else {
+                        return Ok(());
+                    };
+                    let mut w = ResponseWriter::new_empty();
+                    w.set_reason_phrase(reason_phrase);
+                    w.add_connection_header("close");
+                    return self.write(w).await;
+                }
+            };
+
+            if self.respond(parsed, timeouts, handler).await? == ConnCtrl::Close {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn receive(
+        &mut self,
+        timeouts: &Timeouts,
+        handler: &impl AsyncHandler,
+    ) -> anyhow::Result<Received> {
+        self.pipeline_depth = if self.input.is_empty() {
+            1
+        } else {
+            self.pipeline_depth + 1
+        };
+
+        if self.input.is_empty() {
+            match time::timeout(timeouts.idle, self.read()).await {
+                Ok(Ok(0)) => return Ok(Received::Closed),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_) => {
+                    debug!("idle timeout");
+                    return Ok(Received::Closed);
+                }
+            }
+        }
+        self.request_count += 1;
+
+        let mut read_timeout = timeouts.header;
+        let mut deadline = Instant::now() + timeouts.header_deadline;
+        // Start of the body, and the input length at that time.
+        let mut body_start: Option<(Instant, usize)> = None;
+        loop {
+            if let Some(parsed) = self.parser.parse(&self.input)? {
+                self.input.drain(..parsed.len);
+                return Ok(Received::Request(parsed));
+            }
+
+            if body_start.is_none() {
+                if let Some((head, request_line_end, framing)) = self.parser.pending_head() {
+                    body_start = Some((Instant::now(), self.input.len() - head.len()));
+                    read_timeout = timeouts.body;
+                    deadline = Instant::now() + timeouts.body_deadline;
+
+                    let mut head = head.to_owned();
+                    if !self
+                        .check_expectation(&mut head, request_line_end, framing, handler)
+                        .await?
+                    {
+                        return Ok(Received::Answered);
+                    }
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let n = match time::timeout(read_timeout.min(remaining), self.read()).await {
+                Ok(n) => n?,
+                Err(_) => Err(RequestTimeout)?,
+            };
+            if n == 0 {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+            }
+
+            if let Some((start, start_len)) = body_start {
+                let elapsed = start.elapsed();
+                let received = (self.input.len() - start_len) as f64;
+                if elapsed >= MIN_RATE_GRACE
+                    && received < timeouts.min_body_rate as f64 * elapsed.as_secs_f64()
+                {
+                    Err(RequestTimeout)?
+                }
+            }
+        }
+    }
+
+    /// Answers `Expect: 100-continue`. Returns `false` if the request has
+    /// been rejected.
+    async fn check_expectation(
+        &mut self,
+        head: &mut str,
+        request_line_end: usize,
+        framing: Framing,
+        handler: &impl AsyncHandler,
+    ) -> anyhow::Result<bool> {
+        let r = server::parse_head(head, request_line_end)?;
+        let mut w = ResponseWriter::new_empty();
+        match server::check_expectation(&r, framing, &self.limits, |r| handler.check_request(r)) {
+            Ok(false) => return Ok(true),
+            Ok(true) => w.set_reason_phrase(ReasonPhrase::Continue),
+            Err(reason_phrase) => {
+                info!(?r, ?reason_phrase, "expectation rejected");
+                w.set_http_version(r.get_http_version());
+                w.set_reason_phrase(reason_phrase);
+                w.add_connection_header("close");
+                self.write(w).await?;
+                return Ok(false);
+            }
+        }
+        self.write(w).await?;
+        Ok(true)
+    }
+
+    async fn respond(
+        &mut self,
+        parsed: ParsedRequest,
+        timeouts: &Timeouts,
+        handler: &impl AsyncHandler,
+    ) -> io::Result<ConnCtrl> {
+        let ParsedRequest {
+            mut head,
+            request_line_end,
+            body,
+            ..
+        } = parsed;
+        let mut r = match server::parse_head(&mut head, request_line_end) {
+            Ok(r) => r,
+            Err(err) => {
+                debug!(?err);
+                let mut w = ResponseWriter::new_empty();
+                w.set_reason_phrase(ReasonPhrase::BadRequest);
+                w.add_connection_header("close");
+                self.write(w).await?;
+                return Ok(ConnCtrl::Close);
+            }
+        };
+        if let Some(body) = body {
+            r.set_body(body);
+        }
+        self.info.apply(&mut r, self.request_count - 1);
+        let http_version = r.get_http_version();
+
+        let span = server::create_req_span(&r);
+        async {
+            info!(?r);
+            let conn_ctrl = server::request_conn_ctrl(
+                &r,
+                self.request_count,
+                self.pipeline_depth,
+                &self.limits,
+            );
+            let mut w = ResponseWriter::new_empty();
+            handler.handle(&mut w, &mut r).await;
+            drop(r);
+            server::reject_event_stream(&mut w);
+
+            let conn_ctrl = server::finish_response(
+                &mut w,
+                http_version,
+                conn_ctrl,
+                self.request_count,
+                timeouts,
+                &self.limits,
+            );
+            self.write(w).await?;
+            Ok(conn_ctrl)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn read(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 16 * 1024];
+        let n = self.stream.read(&mut buf).await?;
+        self.input.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    async fn write(&mut self, w: ResponseWriter) -> io::Result<()> {
+        let mut output = vec![];
+        w.write_to(&mut output)?;
+        self.stream.write_all(&output).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, thread, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time,
+    };
+
+    use crate::{
+        request::{Limits, Request},
+        response_writer::ResponseWriter,
+        server::{Backend, Server, Site, SiteHandle, Timeouts},
+        status_code_registry::ReasonPhrase,
+    };
+
+    use super::{AsyncHandler, AsyncServer, Blocking};
+
+    struct DelayedEcho;
+
+    impl AsyncHandler for DelayedEcho {
+        async fn handle(&self, w: &mut ResponseWriter, r: &mut Request<'_>) {
+            time::sleep(Duration::from_millis(10)).await;
+            let body = r.read_body().unwrap().unwrap_or_default().to_vec();
+            let body = format!(
+                "{} {}",
+                r.get_request_target(),
+                String::from_utf8(body).unwrap()
+            );
+            w.set_body_str(&body);
+            w.set_reason_phrase(ReasonPhrase::OK);
+        }
+    }
+
+    async fn start_server(handler: impl AsyncHandler + 'static, timeouts: Timeouts) -> SocketAddr {
+        let mut server = AsyncServer::bind("localhost:0").await.unwrap();
+        server.set_limits(Limits::default());
+        server.set_timeouts(timeouts);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run(handler));
+        addr
+    }
+
+    async fn send(addr: SocketAddr, req: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(req).await.unwrap();
+        let mut resp = String::new();
+        time::timeout(Duration::from_secs(5), stream.read_to_string(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+        resp
+    }
+
+    fn response_bodies(resp: &str) -> Vec<&str> {
+        resp.split("HTTP/1.1 ")
+            .skip(1)
+            .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_handler() {
+        let addr = start_server(DelayedEcho, Timeouts::default()).await;
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              POST /snd HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              PUT /trd HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+              GET /fth HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            response_bodies(&resp),
+            ["/fst ", "/snd hello", "/trd abc", "/fth "]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_blocking_handler() {
+        let handler = Blocking(|w: &mut ResponseWriter, r: &mut Request| {
+            w.set_body_str(r.get_request_target());
+            w.set_reason_phrase(ReasonPhrase::OK);
+        });
+        let addr = start_server(handler, Timeouts::default()).await;
+        let resp = send(addr, b"GET /blocking HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(response_bodies(&resp), ["/blocking"]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_blocking_handler_current_thread() {
+        let handler = Blocking(|w: &mut ResponseWriter, _: &mut Request| {
+            w.set_reason_phrase(ReasonPhrase::OK);
+        });
+        let addr = start_server(handler, Timeouts::default()).await;
+        let resp = send(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert!(
+            resp.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_backend() {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_backend(Backend::Async);
+        let addr = server.local_addr();
+        let site = SiteHandle::new(Site {
+            handler: Box::new(|w: &mut ResponseWriter, r: &mut Request| {
+                w.set_body_str(r.get_request_target());
+                w.set_reason_phrase(ReasonPhrase::OK);
+            }),
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+        });
+        thread::spawn(move || server.run_site(&site));
+
+        let url = format!("http://{}/async", addr);
+        let resp = reqwest::blocking::get(url).unwrap();
+        assert_eq!(resp.text().unwrap(), "/async");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_errors() {
+        let timeouts = Timeouts {
+            header_deadline: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let addr = start_server(DelayedEcho, timeouts).await;
+
+        let resp = send(addr, b"GET /fst HTTP/1.1\r\n\r\nGET /snd\r\n\r\n").await;
+        assert!(
+            resp.contains("\r\n\r\n/fst HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            resp
+        );
+
+        let resp = send(addr, b"GET / HTTP/1.1\r\n").await;
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+    }
+}
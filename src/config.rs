@@ -0,0 +1,456 @@
+//! The TOML configuration file. Every section and key is optional.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    content_coding::CompressionLevel,
+    middleware::gzip_compressor,
+    proxy::{TrustedProxies, TrustedProxy},
+    request::Limits,
+    server::{Backend, Timeouts},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    #[cfg(feature = "tls")]
+    pub tls: TlsConfig,
+    #[serde(rename = "mount")]
+    pub mounts: Vec<Mount>,
+    pub compression: CompressionConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// TCP addresses to listen on.
+    pub listen: Vec<String>,
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket file, best written in octal like `0o660`.
+    pub unix_socket_mode: Option<u32>,
+    pub backend: Backend,
+    pub proxy_protocol: bool,
+    /// IP addresses, CIDR ranges or `unix`.
+    pub trusted_proxies: Vec<String>,
+    /// Directory with error page templates named `<status code>.html`.
+    pub error_pages: Option<PathBuf>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub sni: Vec<SniConfig>,
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: Option<crate::tls::ClientAuthMode>,
+}
+
+/// A certificate for a server name.
+#[cfg(feature = "tls")]
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniConfig {
+    pub name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A directory served under a URL prefix.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    /// Starts and ends with a slash, like `/files/`.
+    pub prefix: String,
+    pub directory: PathBuf,
+    /// Accepts uploads with `POST`.
+    #[serde(default)]
+    pub writable: bool,
+    /// Maximum size of an upload after decoding its `Content-Encoding`.
+    #[serde(default = "default_max_decoded_body_size")]
+    pub max_decoded_body_size: usize,
+}
+
+pub fn default_max_decoded_body_size() -> usize {
+    64 * 1024 * 1024
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub min_size: usize,
+    pub level: CompressionLevel,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        let gzip_config = gzip_compressor::Config::default();
+        Self {
+            min_size: gzip_config.min_size,
+            level: gzip_config.level,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn gzip_config(&self) -> gzip_compressor::Config {
+        gzip_compressor::Config {
+            min_size: self.min_size,
+            level: self.level,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line records with their source location.
+    #[default]
+    Pretty,
+    /// One line per record.
+    Compact,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let toml = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        Self::parse(&toml).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    pub fn parse(toml: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn trusted_proxies(&self) -> anyhow::Result<TrustedProxies> {
+        let proxies = self
+            .server
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<TrustedProxy>()
+                    .with_context(|| format!("server.trusted_proxies: `{}`", proxy))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(TrustedProxies::new(proxies))
+    }
+
+    /// The sections that differ from the `running` configuration but only
+    /// take effect after a restart. Of `[server]`, only `error_pages` can be
+    /// reloaded.
+    pub fn restart_sections(&self, running: &Config) -> Vec<&'static str> {
+        let mut sections = vec![];
+        let server = ServerConfig {
+            error_pages: running.server.error_pages.clone(),
+            ..self.server.clone()
+        };
+        if server != running.server {
+            sections.push("server");
+        }
+        #[cfg(feature = "tls")]
+        if self.tls != running.tls {
+            sections.push("tls");
+        }
+        if self.logging != running.logging {
+            sections.push("logging");
+        }
+        sections
+    }
+
+    /// Catches mistakes that would otherwise only show once a request
+    /// runs into them.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.server.unix_socket_mode.is_some() && self.server.unix_socket.is_none() {
+            bail!("server.unix_socket_mode is set without server.unix_socket");
+        }
+        #[cfg(not(unix))]
+        if self.server.unix_socket.is_some() {
+            bail!("server.unix_socket: Unix sockets are not supported on this platform");
+        }
+        self.trusted_proxies()?;
+        if let Some(error_pages) = &self.server.error_pages {
+            check_dir(error_pages).context("server.error_pages")?;
+        }
+
+        let limits = [
+            ("max_requests", self.limits.max_requests),
+            ("pipeline_depth", self.limits.pipeline_depth),
+            ("conns_per_ip", self.limits.conns_per_ip),
+        ];
+        for (name, limit) in limits {
+            if limit == 0 {
+                bail!("limits.{} must be at least 1", name);
+            }
+        }
+
+        // These end up as socket read timeouts, which can't be zero.
+        let timeouts = [
+            ("idle", self.timeouts.idle),
+            ("header", self.timeouts.header),
+            ("body", self.timeouts.body),
+            ("header_deadline", self.timeouts.header_deadline),
+            ("body_deadline", self.timeouts.body_deadline),
+            ("upgraded", self.timeouts.upgraded),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout.is_zero() {
+                bail!("timeouts.{} must be greater than zero", name);
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        self.validate_tls()?;
+
+        let mut prefixes = HashSet::new();
+        for mount in &self.mounts {
+            let prefix = &mount.prefix;
+            if prefix == "/" || !prefix.starts_with('/') || !prefix.ends_with('/') {
+                bail!(
+                    "mount {}: the prefix must start and end with a slash and not be just /",
+                    prefix
+                );
+            }
+            if !prefixes.insert(prefix) {
+                bail!("mount {}: the prefix is mounted twice", prefix);
+            }
+            check_dir(&mount.directory).with_context(|| format!("mount {}", prefix))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn validate_tls(&self) -> anyhow::Result<()> {
+        let tls = &self.tls;
+        if tls.cert.is_some() != tls.key.is_some() {
+            bail!("tls.cert and tls.key have to be given together");
+        }
+        if tls.client_auth.is_some() && tls.client_ca.is_none() {
+            bail!("tls.client_auth is set without tls.client_ca");
+        }
+        let enabled = tls.cert.is_some() || !tls.sni.is_empty();
+        if tls.client_ca.is_some() && !enabled {
+            bail!("tls.client_ca is set without a certificate to serve HTTPS with");
+        }
+        Ok(())
+    }
+}
+
+fn check_dir(path: &Path) -> anyhow::Result<()> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("cannot access {}", path.display()))?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::{content_coding::CompressionLevel, server::Backend};
+
+    use super::{Config, LogFormat, LogLevel};
+
+    #[test]
+    fn test_parse() {
+        let dir = TempDir::new("config").unwrap();
+        let toml = format!(
+            r#"
+            [server]
+            listen = ["127.0.0.1:8080", "[::1]:8080"]
+            unix_socket = "/run/server.sock"
+            unix_socket_mode = 0o660
+            backend = "event-loop"
+            trusted_proxies = ["10.0.0.0/8", "unix"]
+
+            [limits]
+            body_size = 1024
+
+            [timeouts]
+            idle = "1m 30s"
+            header = "500ms"
+
+            [[mount]]
+            prefix = "/static/"
+            directory = "{dir}"
+
+            [[mount]]
+            prefix = "/uploads/"
+            directory = "{dir}"
+            writable = true
+
+            [compression]
+            level = "best"
+
+            [logging]
+            level = "debug"
+            format = "compact"
+            "#,
+            dir = dir.path().display()
+        );
+        let config = Config::parse(&toml).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.server.listen, ["127.0.0.1:8080", "[::1]:8080"]);
+        assert_eq!(config.server.unix_socket_mode, Some(0o660));
+        assert_eq!(config.server.backend, Backend::EventLoop);
+        assert_eq!(config.limits.body_size, 1024);
+        assert_eq!(config.limits.max_requests, 100);
+        assert_eq!(config.timeouts.idle, Duration::from_secs(90));
+        assert_eq!(config.timeouts.header, Duration::from_millis(500));
+        assert_eq!(config.timeouts.body, Duration::from_secs(10));
+        assert_eq!(config.mounts.len(), 2);
+        assert!(!config.mounts[0].writable);
+        assert!(config.mounts[1].writable);
+        assert_eq!(config.mounts[1].max_decoded_body_size, 64 * 1024 * 1024);
+        assert_eq!(config.compression.level, CompressionLevel::Best);
+        assert_eq!(config.logging.level, LogLevel::Debug);
+        assert_eq!(config.logging.format, LogFormat::Compact);
+
+        Config::parse("").unwrap().validate().unwrap();
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let tests = [
+            ("[server]\nlisten = 8080\n", "invalid type"),
+            ("[limits]\nbody_sise = 1\n", "unknown field `body_sise`"),
+            ("[timeouts]\nidle = \"5 parsecs\"\n", "expected a duration"),
+            (
+                "[server]\nbackend = \"forked\"\n",
+                "unknown variant `forked`",
+            ),
+            ("[[mount]]\nprefix = \"/a/\"\n", "missing field `directory`"),
+        ];
+        for (toml, want) in tests {
+            let err = format!("{:#}", Config::parse(toml).unwrap_err());
+            assert!(err.contains(want), "{}: {}", toml, err);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = TempDir::new("config").unwrap();
+        let dir = dir.path().display();
+        let tests = [
+            (
+                "[server]\nunix_socket_mode = 0o600\n".to_owned(),
+                "server.unix_socket_mode is set without server.unix_socket",
+            ),
+            (
+                "[server]\ntrusted_proxies = [\"10.0.0.0/33\"]\n".to_owned(),
+                "server.trusted_proxies: `10.0.0.0/33`",
+            ),
+            (
+                "[limits]\nmax_requests = 0\n".to_owned(),
+                "limits.max_requests must be at least 1",
+            ),
+            (
+                "[timeouts]\nheader = \"0s\"\n".to_owned(),
+                "timeouts.header must be greater than zero",
+            ),
+            (
+                "[timeouts]\nidle = \"0s\"\n".to_owned(),
+                "timeouts.idle must be greater than zero",
+            ),
+            (
+                format!("[[mount]]\nprefix = \"/\"\ndirectory = \"{}\"\n", dir),
+                "mount /: the prefix must start and end with a slash",
+            ),
+            (
+                format!(
+                    "[[mount]]\nprefix = \"/a/\"\ndirectory = \"{0}\"\n\
+                     [[mount]]\nprefix = \"/a/\"\ndirectory = \"{0}\"\n",
+                    dir
+                ),
+                "mount /a/: the prefix is mounted twice",
+            ),
+            (
+                format!(
+                    "[[mount]]\nprefix = \"/a/\"\ndirectory = \"{}/missing\"\n",
+                    dir
+                ),
+                "mount /a/: cannot access",
+            ),
+        ];
+        for (toml, want) in tests {
+            let err = format!(
+                "{:#}",
+                Config::parse(&toml).unwrap().validate().unwrap_err()
+            );
+            assert!(err.contains(want), "{}: {}", toml, err);
+        }
+    }
+
+    #[test]
+    fn test_restart_sections() {
+        let running = Config::parse("[server]\nlisten = [\"[::]:4221\"]\n").unwrap();
+        let tests = [
+            ("[server]\nlisten = [\"[::]:4221\"]\n", vec![]),
+            (
+                "[server]\nlisten = [\"[::]:4221\"]\nerror_pages = \"pages\"\n\
+                 [limits]\nbody_size = 100\n",
+                vec![],
+            ),
+            ("[server]\nlisten = [\"[::]:8080\"]\n", vec!["server"]),
+            (
+                "[server]\nlisten = [\"[::]:4221\"]\nbackend = \"event-loop\"\n\
+                 [logging]\nlevel = \"debug\"\n",
+                vec!["server", "logging"],
+            ),
+        ];
+        for (toml, want) in tests {
+            let config = Config::parse(toml).unwrap();
+            assert_eq!(config.restart_sections(&running), want, "{}", toml);
+        }
+    }
+}
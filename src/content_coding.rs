@@ -0,0 +1,261 @@
+use std::io::{self, Read};
+
+use clap::ValueEnum;
+use flate2::{
+    read::{GzEncoder, MultiGzDecoder, ZlibDecoder, ZlibEncoder},
+    Compression,
+};
+use serde::Deserialize;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+use thiserror::Error;
+
+// https://www.iana.org/assignments/http-parameters/http-parameters.xhtml#content-coding
+
+/// Content codings in order of server preference.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, EnumIter)]
+pub enum ContentCoding {
+    Br,
+    Zstd,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+/// Compression level, mapped onto the native scale of each coding.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionLevel {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl ContentCoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Br => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+            ContentCoding::Identity => "identity",
+        }
+    }
+
+    /// Wraps `r` in a reader that yields the encoded data.
+    pub fn encoder<'a>(
+        &self,
+        r: impl Read + Send + 'a,
+        level: CompressionLevel,
+    ) -> io::Result<Box<dyn Read + Send + 'a>> {
+        use CompressionLevel::*;
+
+        let flate2_level = match level {
+            Fast => Compression::fast(),
+            Default => Compression::default(),
+            Best => Compression::best(),
+        };
+
+        Ok(match self {
+            ContentCoding::Br => {
+                let quality = match level {
+                    Fast => 1,
+                    Default => 5,
+                    Best => 11,
+                };
+                Box::new(brotli::CompressorReader::new(r, 4096, quality, 22))
+            }
+            ContentCoding::Zstd => {
+                let level = match level {
+                    Fast => 1,
+                    Default => 3,
+                    Best => 19,
+                };
+                Box::new(zstd::stream::read::Encoder::new(r, level)?)
+            }
+            ContentCoding::Gzip => Box::new(GzEncoder::new(r, flate2_level)),
+            ContentCoding::Deflate => Box::new(ZlibEncoder::new(r, flate2_level)),
+            ContentCoding::Identity => Box::new(r),
+        })
+    }
+
+    pub fn encode(&self, data: &[u8], level: CompressionLevel) -> io::Result<Vec<u8>> {
+        let mut encoded = vec![];
+        self.encoder(data, level)?.read_to_end(&mut encoded)?;
+        Ok(encoded)
+    }
+
+    /// Wraps `r` in a reader that yields the decoded data.
+    pub fn decoder<'a>(&self, r: impl Read + Send + 'a) -> io::Result<Box<dyn Read + Send + 'a>> {
+        Ok(match self {
+            ContentCoding::Br => Box::new(brotli::Decompressor::new(r, 4096)),
+            ContentCoding::Zstd => Box::new(zstd::stream::read::Decoder::new(r)?),
+            ContentCoding::Gzip => Box::new(MultiGzDecoder::new(r)),
+            ContentCoding::Deflate => Box::new(ZlibDecoder::new(r)),
+            ContentCoding::Identity => Box::new(r),
+        })
+    }
+}
+
+impl TryFrom<&str> for ContentCoding {
+    type Error = ();
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "br" => Ok(ContentCoding::Br),
+            "zstd" => Ok(ContentCoding::Zstd),
+            "gzip" | "x-gzip" => Ok(ContentCoding::Gzip),
+            "deflate" => Ok(ContentCoding::Deflate),
+            "identity" => Ok(ContentCoding::Identity),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("not acceptable")]
+pub struct NotAcceptable;
+
+/// Parses an `Accept-Encoding` element like `gzip;q=0.5`.
+/// Returns `None` if the element is malformed.
+fn parse_element(element: &str) -> Option<(&str, f32)> {
+    let mut it = element.split(';');
+    let coding = it.next()?.trim();
+    if coding.is_empty() {
+        return None;
+    }
+
+    let mut q = 1.0;
+    for param in it {
+        let (k, v) = param.split_once('=')?;
+        if k.trim().eq_ignore_ascii_case("q") {
+            q = v.trim().parse::<f32>().ok()?;
+            if !(0.0..=1.0).contains(&q) {
+                return None;
+            }
+        }
+    }
+    Some((coding, q))
+}
+
+/// Selects the content coding for a response according to the elements of
+/// the request's `Accept-Encoding` header (RFC 9110, section 12.5.3).
+/// A missing header selects identity.
+pub fn negotiate<'a>(
+    accept_encoding: Option<impl Iterator<Item = &'a str>>,
+) -> Result<ContentCoding, NotAcceptable> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Ok(ContentCoding::Identity);
+    };
+
+    let mut explicit = vec![];
+    let mut wildcard = None;
+    for (coding, q) in accept_encoding.filter_map(parse_element) {
+        if coding == "*" {
+            wildcard = Some(q);
+        } else if let Ok(coding) = ContentCoding::try_from(coding) {
+            explicit.push((coding, q));
+        }
+    }
+
+    let qvalue = |coding: ContentCoding| {
+        if let Some((_, q)) = explicit.iter().find(|(c, _)| *c == coding) {
+            return *q;
+        }
+        match (coding, wildcard) {
+            (_, Some(q)) => q,
+            // Identity is acceptable unless it is excluded explicitly.
+            (ContentCoding::Identity, None) => 0.001,
+            (_, None) => 0.0,
+        }
+    };
+
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for coding in ContentCoding::iter() {
+        let q = qvalue(coding);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding).ok_or(NotAcceptable)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use strum::IntoEnumIterator;
+
+    use super::{negotiate, CompressionLevel, ContentCoding};
+
+    #[test]
+    fn test_round_trip() {
+        let data = "Hello World!".repeat(100);
+        for coding in ContentCoding::iter() {
+            let encoded = coding
+                .encode(data.as_bytes(), CompressionLevel::default())
+                .unwrap();
+            let mut decoded = String::new();
+            coding
+                .decoder(encoded.as_slice())
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data, "{:?}", coding);
+        }
+    }
+
+    fn check(accept_encoding: &str, want: Option<ContentCoding>) {
+        let elements = accept_encoding.split(',').map(|element| element.trim());
+        assert_eq!(
+            negotiate(Some(elements)).ok(),
+            want,
+            "Accept-Encoding: {}",
+            accept_encoding
+        );
+    }
+
+    #[test]
+    fn test_negotiate_missing_header() {
+        let got = negotiate(None::<std::iter::Empty<&str>>).unwrap();
+        assert_eq!(got, ContentCoding::Identity);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        use ContentCoding::*;
+
+        let tests = [
+            ("gzip", Some(Gzip)),
+            ("GZIP", Some(Gzip)),
+            ("x-gzip", Some(Gzip)),
+            ("deflate", Some(Deflate)),
+            ("br", Some(Br)),
+            ("zstd", Some(Zstd)),
+            ("invalid-encoding-1, gzip, invalid-encoding-2", Some(Gzip)),
+            ("invalid-encoding", Some(Identity)),
+            ("", Some(Identity)),
+            // q-values
+            ("gzip;q=0", Some(Identity)),
+            ("gzip; q=0.5, br;q=0.4", Some(Gzip)),
+            ("gzip;q=0.5, br;q=0.5", Some(Br)),
+            ("gzip;q=2", Some(Identity)),
+            ("gzip;q=abc, deflate", Some(Deflate)),
+            // wildcard
+            ("*", Some(Br)),
+            ("*;q=0.5, gzip", Some(Gzip)),
+            ("*;q=0, gzip;q=0.1", Some(Gzip)),
+            // identity
+            ("identity", Some(Identity)),
+            ("identity;q=0", None),
+            ("*;q=0", None),
+            ("*;q=0, identity", Some(Identity)),
+            ("identity;q=0, gzip;q=0", None),
+            ("identity;q=0, gzip", Some(Gzip)),
+        ];
+
+        for (accept_encoding, want) in tests {
+            check(accept_encoding, want);
+        }
+    }
+}
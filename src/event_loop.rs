@@ -0,0 +1,773 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{self, Shutdown},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Registry, Token,
+};
+use tracing::{debug, error, info, warn, Span};
+
+use crate::{
+    proxy::TrustedProxies,
+    request::{Framing, Limits},
+    request::{ParsedRequest, RequestParser},
+    response_writer::ResponseWriter,
+    server::{self, ConnCounter, ConnCtrl, ConnInfo, ConnSlot, Handler, Timeouts},
+    status_code_registry::ReasonPhrase,
+    stream::PeerAddr,
+    timed_stream::{RequestTimeout, MIN_RATE_GRACE},
+};
+
+const LISTENER: Token = Token(0);
+/// How often deadlines are checked.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+/// Reading and answering requests pause while this much of the responses
+/// is waiting to be sent.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Receiving requests.
+    Active,
+    /// Sending the last response before closing.
+    Closing,
+    /// Draining the input after the sending side has been shut down.
+    Lingering(Instant),
+    Closed,
+}
+
+struct Conn<'a> {
+    stream: TcpStream,
+    span: Span,
+    _slot: ConnSlot<'a>,
+    info: ConnInfo,
+    parser: RequestParser,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    state: State,
+    request_count: usize,
+    pipeline_depth: usize,
+    /// Time data was last received or sent.
+    last_active: Instant,
+    /// Start of the request being received.
+    request_start: Option<Instant>,
+    /// Start of the body being received, and the input length at that time.
+    body_start: Option<(Instant, usize)>,
+    expectation_checked: bool,
+    /// Received requests are waiting for the output to be sent.
+    paused: bool,
+    /// The client has finished sending.
+    eof: bool,
+}
+
+/// Serves the connections of `listener` on the current thread with
+/// non-blocking sockets. Requests are received completely before the
+/// handler is called and responses are buffered, so handlers must not block.
+pub fn run(
+    listener: &net::TcpListener,
+    limits: &Limits,
+    timeouts: &Timeouts,
+    conn_counter: &ConnCounter,
+    trusted_proxies: &Arc<TrustedProxies>,
+    handler: &(impl Handler + ?Sized),
+) -> io::Result<()> {
+    let listener = listener.try_clone()?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+    let mut events = Events::with_capacity(1024);
+    let mut conns = HashMap::new();
+    let mut next_token = LISTENER.0 + 1;
+    let mut last_sweep = Instant::now();
+
+    loop {
+        if let Err(err) = poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                accept(
+                    &listener,
+                    poll.registry(),
+                    &mut conns,
+                    &mut next_token,
+                    limits,
+                    conn_counter,
+                    trusted_proxies,
+                );
+                continue;
+            }
+
+            let Some(conn) = conns.get_mut(&event.token()) else {
+                continue;
+            };
+            conn.on_ready(handler, limits, timeouts);
+            if conn.state == State::Closed {
+                close(poll.registry(), &mut conns, event.token());
+            }
+        }
+
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            last_sweep = Instant::now();
+            let mut closed = vec![];
+            for (token, conn) in &mut conns {
+                conn.check_deadlines(timeouts);
+                if conn.state == State::Closed {
+                    closed.push(*token);
+                }
+            }
+            for token in closed {
+                close(poll.registry(), &mut conns, token);
+            }
+        }
+    }
+}
+
+fn accept<'a>(
+    listener: &TcpListener,
+    registry: &Registry,
+    conns: &mut HashMap<Token, Conn<'a>>,
+    next_token: &mut usize,
+    limits: &Limits,
+    conn_counter: &'a ConnCounter,
+    trusted_proxies: &Arc<TrustedProxies>,
+) {
+    loop {
+        let (mut stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(err) => {
+                error!(?err);
+                return;
+            }
+        };
+
+        let Some(slot) = conn_counter.acquire(PeerAddr::Tcp(peer_addr), limits.conns_per_ip) else {
+            warn!(?peer_addr, "too many connections");
+            let mut w = ResponseWriter::new_empty();
+            w.set_reason_phrase(ReasonPhrase::ServiceUnavailable);
+            w.add_connection_header("close");
+            // Best effort, the socket doesn't block.
+            let _ = w.write_to(&mut stream);
+            continue;
+        };
+
+        let token = Token(*next_token);
+        *next_token += 1;
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        if let Err(err) = registry.register(&mut stream, token, interest) {
+            error!(?err);
+            continue;
+        }
+
+        let id = server::next_conn_id();
+        let span = server::create_conn_span(id, Ok(PeerAddr::Tcp(peer_addr)));
+        span.in_scope(|| info!("new conn"));
+        let info = ConnInfo {
+            id,
+            peer_addr: Some(PeerAddr::Tcp(peer_addr)),
+            local_addr: stream.local_addr().ok(),
+            client_addr: Some(peer_addr.ip()),
+            trusted_proxies: trusted_proxies.clone(),
+            ..Default::default()
+        };
+        conns.insert(token, Conn::new(stream, span, slot, info, limits));
+    }
+}
+
+fn close(registry: &Registry, conns: &mut HashMap<Token, Conn>, token: Token) {
+    if let Some(mut conn) = conns.remove(&token) {
+        let _ = registry.deregister(&mut conn.stream);
+        conn.span.in_scope(|| info!("conn end"));
+    }
+}
+
+impl<'a> Conn<'a> {
+    fn new(
+        stream: TcpStream,
+        span: Span,
+        slot: ConnSlot<'a>,
+        info: ConnInfo,
+        limits: &Limits,
+    ) -> Self {
+        Self {
+            stream,
+            span,
+            _slot: slot,
+            info,
+            parser: RequestParser::new(*limits),
+            input: vec![],
+            output: vec![],
+            state: State::Active,
+            request_count: 0,
+            pipeline_depth: 0,
+            last_active: Instant::now(),
+            request_start: None,
+            body_start: None,
+            expectation_checked: false,
+            paused: false,
+            eof: false,
+        }
+    }
+
+    fn on_ready(
+        &mut self,
+        handler: &(impl Handler + ?Sized),
+        limits: &Limits,
+        timeouts: &Timeouts,
+    ) {
+        let span = self.span.clone();
+        let _guard = span.enter();
+
+        // The readiness events are edge-triggered, so reading and writing
+        // continue until the socket would block.
+        loop {
+            self.flush();
+            match self.state {
+                State::Active if self.output.len() < MAX_PENDING_OUTPUT => {}
+                State::Lingering(_) => return self.drain(),
+                _ => return,
+            }
+
+            if self.paused {
+                self.process(handler, limits, timeouts);
+                continue;
+            }
+            match self.receive() {
+                Ok(true) => self.process(handler, limits, timeouts),
+                // Pending responses are still sent.
+                Ok(false) if self.eof => self.state = State::Closing,
+                Ok(false) => {
+                    self.flush();
+                    return;
+                }
+                Err(err) => {
+                    debug!(?err);
+                    self.state = State::Closed;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads what has been received. Returns `false` if nothing was
+    /// available.
+    fn receive(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut buf = [0; 16 * 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => {
+                self.eof = true;
+                Ok(false)
+            }
+            Ok(n) => {
+                self.input.extend_from_slice(&buf[..n]);
+                self.last_active = Instant::now();
+                self.request_start.get_or_insert(self.last_active);
+                Ok(true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Answers the requests that have been received completely.
+    fn process(&mut self, handler: &(impl Handler + ?Sized), limits: &Limits, timeouts: &Timeouts) {
+        // Requests that arrive before the previous response has been sent
+        // are pipelined.
+        if self.output.is_empty() {
+            self.pipeline_depth = 0;
+        }
+
+        self.paused = false;
+        while self.state == State::Active {
+            if self.output.len() >= MAX_PENDING_OUTPUT {
+                self.paused = true;
+                return;
+            }
+            match self.parser.parse(&self.input) {
+                Ok(Some(parsed)) => {
+                    self.input.drain(..parsed.len);
+                    self.request_count += 1;
+                    self.pipeline_depth += 1;
+                    self.request_start = (!self.input.is_empty()).then(Instant::now);
+                    self.body_start = None;
+                    self.expectation_checked = false;
+
+                    if self.respond(parsed, handler, limits, timeouts) == ConnCtrl::Close {
+                        self.state = State::Closing;
+                    }
+                }
+                Ok(None) => {
+                    self.on_pending_head(handler, limits);
+                    return;
+                }
+                Err(err) => {
+                    self.respond_error(err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respond(
+        &mut self,
+        parsed: ParsedRequest,
+        handler: &(impl Handler + ?Sized),
+        limits: &Limits,
+        timeouts: &Timeouts,
+    ) -> ConnCtrl {
+        let ParsedRequest {
+            mut head,
+            request_line_end,
+            body,
+            ..
+        } = parsed;
+        let mut r = match server::parse_head(&mut head, request_line_end) {
+            Ok(r) => r,
+            Err(err) => {
+                self.respond_error(err);
+                return ConnCtrl::Close;
+            }
+        };
+        if let Some(body) = body {
+            r.set_body(body);
+        }
+        self.info.apply(&mut r, self.request_count - 1);
+        let http_version = r.get_http_version();
+
+        let span = server::create_req_span(&r);
+        let _guard = span.enter();
+        info!(?r);
+
+        let conn_ctrl =
+            server::request_conn_ctrl(&r, self.request_count, self.pipeline_depth, limits);
+        let mut w = ResponseWriter::new_empty();
+        handler.handle(&mut w, &mut r);
+        drop(r);
+        server::reject_event_stream(&mut w);
+
+        let conn_ctrl = server::finish_response(
+            &mut w,
+            http_version,
+            conn_ctrl,
+            self.request_count,
+            timeouts,
+            limits,
+        );
+        if let Err(err) = w.write_to(&mut self.output) {
+            // The response is incomplete.
+            error!(?err);
+            self.state = State::Closed;
+        }
+        conn_ctrl
+    }
+
+    /// Answers `Expect: 100-continue` once the head of a request with a
+    /// body has been received.
+    fn on_pending_head(&mut self, handler: &(impl Handler + ?Sized), limits: &Limits) {
+        let Some((head, request_line_end, framing)) = self.parser.pending_head() else {
+            return;
+        };
+        if self.body_start.is_none() {
+            let head_len = head.len();
+            self.body_start = Some((Instant::now(), self.input.len() - head_len));
+        }
+        if self.expectation_checked || framing == Framing::None {
+            return;
+        }
+        self.expectation_checked = true;
+
+        let mut head = head.to_owned();
+        let r = match server::parse_head(&mut head, request_line_end) {
+            Ok(r) => r,
+            Err(err) => return self.respond_error(err),
+        };
+        let mut w = ResponseWriter::new_empty();
+        match server::check_expectation(&r, framing, limits, |r| handler.check_request(r)) {
+            Ok(false) => return,
+            Ok(true) => w.set_reason_phrase(ReasonPhrase::Continue),
+            Err(reason_phrase) => {
+                info!(?r, ?reason_phrase, "expectation rejected");
+                w.set_http_version(r.get_http_version());
+                w.set_reason_phrase(reason_phrase);
+                w.add_connection_header("close");
+                self.state = State::Closing;
+            }
+        }
+        let _ = w.write_to(&mut self.output);
+    }
+
+    /// Answers a request that cannot be processed and closes the
+    /// connection.
+    fn respond_error(&mut self, err: anyhow::Error) {
+        debug!(?err);
+        let Some(reason_phrase) = server::error_reason_phrase(&err) else {
+            self.state = State::Closed;
+            return;
+        };
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(reason_phrase);
+        w.add_connection_header("close");
+        let _ = w.write_to(&mut self.output);
+        self.state = State::Closing;
+    }
+
+    /// Sends as much of the pending output as possible.
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => {
+                    self.state = State::Closed;
+                    break;
+                }
+                Ok(n) => {
+                    written += n;
+                    self.last_active = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    debug!(?err);
+                    self.state = State::Closed;
+                    break;
+                }
+            }
+        }
+        self.output.drain(..written);
+
+        if self.state == State::Closing && self.output.is_empty() {
+            // Like the threaded server, drain what the client still sends
+            // so that the last response isn't lost to a reset.
+            if self.stream.shutdown(Shutdown::Write).is_err() {
+                self.state = State::Closed;
+                return;
+            }
+            self.state = State::Lingering(Instant::now() + LINGER_TIMEOUT);
+            self.drain();
+        }
+    }
+
+    fn drain(&mut self) {
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        self.state = State::Closed;
+    }
+
+    fn check_deadlines(&mut self, timeouts: &Timeouts) {
+        let span = self.span.clone();
+        let _guard = span.enter();
+
+        match self.state {
+            State::Lingering(deadline) if Instant::now() >= deadline => {
+                self.state = State::Closed;
+                return;
+            }
+            State::Active | State::Closing => {}
+            _ => return,
+        }
+
+        // Clients that stop reading the responses are dropped like idle
+        // ones.
+        let inactive = self.last_active.elapsed();
+        if !self.output.is_empty() || self.state == State::Closing {
+            if inactive > timeouts.idle {
+                debug!("send timeout");
+                self.state = State::Closed;
+            }
+            return;
+        }
+
+        let Some(request_start) = self.request_start else {
+            if inactive > timeouts.idle {
+                debug!("idle timeout");
+                self.state = State::Closed;
+            }
+            return;
+        };
+
+        let timed_out = match self.body_start {
+            None => {
+                inactive > timeouts.header || request_start.elapsed() > timeouts.header_deadline
+            }
+            Some((body_start, start_len)) => {
+                let elapsed = body_start.elapsed();
+                let received = self.input.len().saturating_sub(start_len) as f64;
+                let min_received = timeouts.min_body_rate as f64 * elapsed.as_secs_f64();
+                inactive > timeouts.body
+                    || elapsed > timeouts.body_deadline
+                    || (elapsed >= MIN_RATE_GRACE && received < min_received)
+            }
+        };
+        if timed_out {
+            warn!("request timeout");
+            self.respond_error(RequestTimeout.into());
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        request::{Limits, Request},
+        response_writer::ResponseWriter,
+        server::{Backend, Server, Timeouts},
+        status_code_registry::ReasonPhrase,
+    };
+
+    fn start_server(limits: Limits, timeouts: Timeouts) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_backend(Backend::EventLoop);
+        server.set_limits(limits);
+        server.set_timeouts(timeouts);
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                if r.get_request_target() == "/large" {
+                    w.set_body_str(&"a".repeat(256 * 1024));
+                    w.set_reason_phrase(ReasonPhrase::OK);
+                    return;
+                }
+                let body = r.read_body().unwrap().unwrap_or_default().to_vec();
+                let body = format!(
+                    "{} {}",
+                    r.get_request_target(),
+                    String::from_utf8(body).unwrap()
+                );
+                w.set_body_str(&body);
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    fn send(addr: SocketAddr, req: &[u8]) -> String {
+        let mut stream = connect(addr);
+        stream.write_all(req).unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        buf
+    }
+
+    fn response_bodies(resp: &str) -> Vec<&str> {
+        resp.split("HTTP/1.1 ")
+            .skip(1)
+            .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_requests() {
+        let addr = start_server(Limits::default(), Timeouts::default());
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              POST /snd HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              PUT /trd HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+              GET /fth HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(
+            response_bodies(&resp),
+            ["/fst ", "/snd hello", "/trd abc", "/fth "]
+        );
+    }
+
+    #[test]
+    fn test_pipelined_output_over_limit() {
+        let limits = Limits {
+            pipeline_depth: 64,
+            ..Default::default()
+        };
+        let addr = start_server(limits, Timeouts::default());
+        let mut stream = connect(addr);
+
+        // Each response is a quarter of MAX_PENDING_OUTPUT.
+        let count = 48;
+        let req = "GET /large HTTP/1.1\r\n\r\n".repeat(count)
+            + "GET /last HTTP/1.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).unwrap();
+        // The responses pile up until the client reads them.
+        thread::sleep(Duration::from_millis(200));
+
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        let bodies = response_bodies(&resp);
+        assert_eq!(bodies.len(), count + 1);
+        assert!(bodies[..count].iter().all(|body| body.len() == 256 * 1024));
+        assert_eq!(bodies[count], "/last ");
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let addr = start_server(Limits::default(), Timeouts::default());
+        let mut stream = connect(addr);
+        let req = b"POST /fst HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+        for b in req {
+            stream.write_all(&[*b]).unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert_eq!(response_bodies(&resp), ["/fst hello"]);
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let addr = start_server(Limits::default(), Timeouts::default());
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        stream.write_all(b"hello").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert_eq!(response_bodies(&resp), ["/ hello"]);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let addr = start_server(Limits::default(), Timeouts::default());
+        let resp = send(addr, b"GET /fst HTTP/1.1\r\n\r\nGET /snd\r\n\r\n");
+        assert!(
+            resp.contains("\r\n\r\n/fst HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let addr = start_server(
+            Limits::default(),
+            Timeouts {
+                idle: Duration::from_millis(100),
+                header_deadline: Duration::from_millis(300),
+                ..Default::default()
+            },
+        );
+
+        // idle connections are closed without a response
+        let resp = send(addr, b"");
+        assert_eq!(resp, "");
+
+        let mut stream = connect(addr);
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_many_idle_connections() {
+        let addr = start_server(
+            Limits {
+                conns_per_ip: 1000,
+                ..Default::default()
+            },
+            Timeouts::default(),
+        );
+        let idle: Vec<_> = (0..500).map(|_| connect(addr)).collect();
+
+        let resp = send(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(response_bodies(&resp), ["/ "]);
+        drop(idle);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // benchmark
+
+    /// Requests per second of keep-alive clients for each backend.
+    /// Run with `cargo test --release bench_backends -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_backends() {
+        const CLIENTS: usize = 32;
+        const REQUESTS: usize = 2000;
+
+        for backend in [Backend::Threaded, Backend::EventLoop] {
+            let mut server = Server::bind("localhost:0").unwrap();
+            server.set_backend(backend);
+            server.set_limits(Limits {
+                max_requests: REQUESTS + 1,
+                conns_per_ip: CLIENTS,
+                ..Default::default()
+            });
+            let addr = server.local_addr();
+            thread::spawn(move || {
+                server.run(|w: &mut ResponseWriter, _: &mut Request| {
+                    w.set_body_str("hello");
+                    w.set_reason_phrase(ReasonPhrase::OK);
+                });
+            });
+
+            let start = Instant::now();
+            thread::scope(|s| {
+                for _ in 0..CLIENTS {
+                    s.spawn(|| {
+                        let mut stream = connect(addr);
+                        let mut buf = [0; 1024];
+                        for _ in 0..REQUESTS {
+                            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                            let n = stream.read(&mut buf).unwrap();
+                            assert!(buf[..n].ends_with(b"hello"));
+                        }
+                    });
+                }
+            });
+            let rate = (CLIENTS * REQUESTS) as f64 / start.elapsed().as_secs_f64();
+            println!("{:?}: {:.0} requests/s", backend, rate);
+        }
+    }
+}
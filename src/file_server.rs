@@ -1,14 +1,18 @@
 use std::{
-    fs,
-    io::ErrorKind,
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
     path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use thiserror::Error;
 use tracing::{error, info, warn};
 
 use crate::{
-    request::Request, response_writer::ResponseWriter, server::Handler,
+    request::{LimitExceeded, Request},
+    response_writer::ResponseWriter,
+    server::Handler,
     status_code_registry::ReasonPhrase,
 };
 
@@ -63,14 +67,61 @@ pub fn new_file_writer(base_path: impl Into<PathBuf>) -> impl Handler {
             }
         }
 
-        if let Err(err) = fs::write(path, r.get_body().unwrap()) {
-            error!("{}", err);
-            w.set_reason_phrase(ReasonPhrase::InternalServerError);
+        let Some(mut body) = r.take_body() else {
+            w.set_reason_phrase(ReasonPhrase::BadRequest);
             return;
+        };
+
+        match write_atomically(&path, &mut body) {
+            Ok(()) => w.set_reason_phrase(ReasonPhrase::Created),
+            Err(err) if is_body_too_large(&err) => {
+                warn!("{}", err);
+                w.set_reason_phrase(ReasonPhrase::ContentTooLarge);
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::InvalidData
+                ) =>
+            {
+                warn!("{}", err);
+                w.set_reason_phrase(ReasonPhrase::BadRequest);
+            }
+            Err(err) => {
+                error!("{}", err);
+                w.set_reason_phrase(ReasonPhrase::InternalServerError);
+            }
         }
+    }
+}
+
+fn is_body_too_large(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<LimitExceeded>())
+        .is_some()
+}
 
-        w.set_reason_phrase(ReasonPhrase::Created);
+/// Streams `body` into a temporary file next to `path` and moves it into
+/// place once complete, so that readers and concurrent writers never see a
+/// partially written file.
+fn write_atomically(path: &Path, body: &mut impl Read) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let res = File::create(&tmp_path)
+        .and_then(|mut file| io::copy(body, &mut file))
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
     }
+    res
 }
 
 #[derive(Error, Debug)]
@@ -148,7 +199,7 @@ mod tests {
 
     #[test]
     fn test_file_retriever() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         let tmp_dir = TempDir::new("").unwrap();
@@ -176,7 +227,7 @@ mod tests {
 
     #[test]
     fn test_file_writer() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         let tmp_dir = TempDir::new("").unwrap();
@@ -203,7 +254,7 @@ mod tests {
 
     #[test]
     fn test_concurrent_writes() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         let tmp_dir = TempDir::new("").unwrap();
@@ -1,4 +1,4 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 
 use crate::multi_map::{MultiMap, Value};
 
@@ -46,10 +46,22 @@ impl<'a> Headers<'a> {
         Ok(Self::new(mm))
     }
 
+    /// Iterates over the fields, joining the values of each with commas.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, String)> + '_ {
+        (&self.0).into_iter().map(|(k, v)| {
+            let values: Vec<_> = v.into_iter().copied().collect();
+            (*k, values.join(", "))
+        })
+    }
+
     pub fn get_scalar(&self, key: &str) -> anyhow::Result<Option<&str>> {
         Ok(self.0.get_scalar(key.to_lowercase().as_str())?.copied())
     }
 
+    pub fn remove(&mut self, key: &str) {
+        self.0.remove(key.to_lowercase().as_str());
+    }
+
     pub fn get_iter(&self, key: &str) -> Option<impl Iterator<Item = &str> + '_> {
         self.0
             .get_value_iter(key.to_lowercase().as_str())
@@ -64,20 +76,63 @@ impl<'a> Headers<'a> {
         self.get_iter("connection")
     }
 
-    pub fn get_content_length(&self) -> anyhow::Result<Option<usize>> {
-        match self
-            .get_scalar("content-length")?
-            .map(|length| length.parse::<usize>())
-        {
-            Some(Ok(length)) => Ok(Some(length)),
-            Some(Err(err)) => Err(err)?,
-            None => Ok(None),
+    pub fn get_content_encoding(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("content-encoding")
+    }
+
+    /// Fails unless there is at most one value consisting of digits only.
+    pub fn get_content_length(&self) -> anyhow::Result<Option<u64>> {
+        let Some(length) = self.get_scalar("content-length")? else {
+            return Ok(None);
+        };
+        if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("invalid content length");
         }
+        Ok(Some(length.parse::<u64>()?))
+    }
+
+    pub fn get_transfer_encoding(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("transfer-encoding")
+    }
+
+    pub fn get_expect(&self) -> anyhow::Result<Option<&str>> {
+        self.get_scalar("expect")
     }
 
     pub fn get_user_agent(&self) -> anyhow::Result<Option<&str>> {
         self.get_scalar("user-agent")
     }
+
+    /// The id of the last event a reconnecting event stream client received.
+    /// As ids may contain commas, the values are joined again.
+    pub fn get_last_event_id(&self) -> Option<String> {
+        self.get_iter("last-event-id")
+            .map(|it| it.collect::<Vec<_>>().join(", "))
+    }
+
+    pub fn get_upgrade(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("upgrade")
+    }
+
+    pub fn get_sec_websocket_key(&self) -> anyhow::Result<Option<&str>> {
+        self.get_scalar("sec-websocket-key")
+    }
+
+    pub fn get_sec_websocket_version(&self) -> anyhow::Result<Option<&str>> {
+        self.get_scalar("sec-websocket-version")
+    }
+
+    pub fn get_forwarded(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("forwarded")
+    }
+
+    pub fn get_x_forwarded_for(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("x-forwarded-for")
+    }
+
+    pub fn get_x_forwarded_proto(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        self.get_iter("x-forwarded-proto")
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,319 @@
+//! Framing layer of HTTP/2 (RFC 9113, section 4).
+
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+/// The client connection preface (RFC 9113, section 3.4).
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY_FLAG: u8 = 0x20;
+
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+pub const MAX_MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+pub const DEFAULT_WINDOW_SIZE: i64 = 65535;
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+const HEADER_LEN: usize = 9;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+/// An error that ends the whole connection with a `GOAWAY` frame.
+#[derive(Error, Debug, Copy, Clone)]
+#[error("connection error: {0:?}")]
+pub struct ConnectionError(pub ErrorCode);
+
+#[derive(Debug)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn rst_stream(stream_id: u32, error_code: ErrorCode) -> Self {
+        let payload = (error_code as u32).to_be_bytes().to_vec();
+        Self::new(RST_STREAM, 0, stream_id, payload)
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> Self {
+        Self::new(
+            WINDOW_UPDATE,
+            0,
+            stream_id,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+
+    pub fn goaway(last_stream_id: u32, error_code: ErrorCode) -> Self {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend((error_code as u32).to_be_bytes());
+        Self::new(GOAWAY, 0, 0, payload)
+    }
+
+    pub fn settings(settings: &[(u16, u32)]) -> Self {
+        let mut payload = vec![];
+        for (id, value) in settings {
+            payload.extend(id.to_be_bytes());
+            payload.extend(value.to_be_bytes());
+        }
+        Self::new(SETTINGS, 0, 0, payload)
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Removes the padding of `DATA` and `HEADERS` frames and, for the
+    /// latter, the priority fields. Returns the number of removed bytes.
+    pub fn strip_padding(&mut self) -> Result<usize, ConnectionError> {
+        let len = self.payload.len();
+        let mut start = 0;
+        let mut pad_len = 0;
+        if self.has_flag(PADDED) {
+            pad_len = *self
+                .payload
+                .first()
+                .ok_or(ConnectionError(ErrorCode::FrameSizeError))? as usize;
+            start = 1;
+        }
+        if self.kind == HEADERS && self.has_flag(PRIORITY_FLAG) {
+            start += 5;
+        }
+        if start + pad_len > len {
+            return Err(ConnectionError(ErrorCode::ProtocolError));
+        }
+        self.payload.truncate(len - pad_len);
+        self.payload.drain(..start);
+        Ok(len - self.payload.len())
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        buf.push(self.kind);
+        buf.push(self.flags);
+        buf.extend(self.stream_id.to_be_bytes());
+        buf.extend(&self.payload);
+        writer.write_all(&buf)
+    }
+}
+
+/// Parses the payload of a `SETTINGS` frame.
+pub fn parse_settings(payload: &[u8]) -> Result<Vec<(u16, u32)>, ConnectionError> {
+    if payload.len() % 6 != 0 {
+        return Err(ConnectionError(ErrorCode::FrameSizeError));
+    }
+    Ok(payload
+        .chunks_exact(6)
+        .map(|setting| {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            (id, value)
+        })
+        .collect())
+}
+
+/// Parses a 31-bit stream id or window increment, ignoring the reserved bit.
+pub fn parse_u31(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff
+}
+
+/// Reads frames, keeping partially received ones across failed reads, so
+/// that reading can continue after a timeout.
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: vec![],
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Reads exactly `len` bytes, like the connection preface.
+    pub fn read_exact(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.fill(len)?;
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Returns `None` if the peer closed the connection between frames.
+    pub fn read_frame(&mut self) -> anyhow::Result<Option<Frame>> {
+        if !self.fill(HEADER_LEN)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]) as usize;
+        if len > self.max_frame_size {
+            Err(ConnectionError(ErrorCode::FrameSizeError))?
+        }
+        self.fill(HEADER_LEN + len)?;
+
+        let frame = Frame {
+            kind: self.buf[3],
+            flags: self.buf[4],
+            stream_id: parse_u31(&self.buf[5..9]),
+            payload: self.buf[HEADER_LEN..HEADER_LEN + len].to_vec(),
+        };
+        self.buf.drain(..HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+
+    /// Buffers at least `len` bytes. Returns `false` if the connection was
+    /// closed before any byte arrived.
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        let mut chunk = [0; 8 * 1024];
+        while self.buf.len() < len {
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(false);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend(&chunk[..n]);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use super::{
+        parse_settings, ConnectionError, ErrorCode, Frame, FrameReader, DATA, HEADERS, PADDED,
+        PRIORITY_FLAG,
+    };
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = vec![];
+        Frame::new(DATA, 1, 3, b"hello".to_vec())
+            .write_to(&mut buf)
+            .unwrap();
+        Frame::settings(&[(4, 1 << 20)]).write_to(&mut buf).unwrap();
+        assert_eq!(&buf[..9], b"\x00\x00\x05\x00\x01\x00\x00\x00\x03");
+
+        let mut reader = FrameReader::new(Cursor::new(buf));
+        let frame = reader.read_frame().unwrap().unwrap();
+        assert_eq!((frame.kind, frame.flags, frame.stream_id), (DATA, 1, 3));
+        assert_eq!(frame.payload, b"hello");
+        let frame = reader.read_frame().unwrap().unwrap();
+        assert_eq!(parse_settings(&frame.payload).unwrap(), [(4, 1 << 20)]);
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    /// Fails every other read, like a read timeout.
+    struct Flaky<R> {
+        reader: R,
+        fail: bool,
+    }
+
+    impl<R: Read> Read for Flaky<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            if self.fail {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.reader.read(&mut buf[..1])
+        }
+    }
+
+    #[test]
+    fn test_frame_reader_resumes() {
+        let mut buf = vec![];
+        Frame::new(DATA, 0, 1, b"abc".to_vec())
+            .write_to(&mut buf)
+            .unwrap();
+        let mut reader = FrameReader::new(Flaky {
+            reader: Cursor::new(buf),
+            fail: false,
+        });
+        let frame = loop {
+            if let Ok(frame) = reader.read_frame() {
+                break frame.unwrap();
+            }
+        };
+        assert_eq!(frame.payload, b"abc");
+    }
+
+    #[test]
+    fn test_frame_reader_errors() {
+        let mut reader = FrameReader::new(Cursor::new(b"\x00\x40\x01\x00\x00\x00\x00\x00\x01"));
+        let err = reader.read_frame().unwrap_err();
+        let err = err.downcast_ref::<ConnectionError>().unwrap();
+        assert_eq!(err.0, ErrorCode::FrameSizeError);
+
+        let mut reader = FrameReader::new(Cursor::new(b"\x00\x00\x05\x00\x00"));
+        let err = reader.read_frame().unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_strip_padding() {
+        let mut frame = Frame::new(
+            HEADERS,
+            PADDED | PRIORITY_FLAG,
+            1,
+            b"\x02\0\0\0\0\x10ab\0\0".to_vec(),
+        );
+        assert_eq!(frame.strip_padding().unwrap(), 8);
+        assert_eq!(frame.payload, b"ab");
+
+        let mut frame = Frame::new(DATA, PADDED, 1, b"\x05ab".to_vec());
+        assert!(frame.strip_padding().is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Header compression for HTTP/2 (RFC 7541).
+
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+use super::huffman;
+
+#[derive(Error, Debug)]
+#[error("compression error")]
+pub struct CompressionError;
+
+/// RFC 7541, appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Per-entry overhead counted towards the table size.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Decodes header blocks, keeping the dynamic table in sync with the peer's
+/// encoder. All blocks of a connection have to be decoded in order, even
+/// those of rejected requests.
+#[derive(Debug)]
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    /// The limit announced with `SETTINGS_HEADER_TABLE_SIZE`.
+    max_size_limit: usize,
+    max_size: usize,
+    /// The limit announced with `SETTINGS_MAX_HEADER_LIST_SIZE`.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub fn new(max_size: usize, max_list_size: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size_limit: max_size,
+            max_size,
+            max_list_size,
+        }
+    }
+
+    /// Returns `None` if the fields are larger than the header list size
+    /// limit. The rest of the block is still decoded, to keep the table in
+    /// sync, but its fields aren't kept.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+    ) -> Result<Option<Vec<(String, String)>>, CompressionError> {
+        let mut headers = vec![];
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // indexed header field
+                let index = decode_int(&mut block, 7)?;
+                let (name, value) = self.get(index)?;
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size <= self.max_list_size {
+                    headers.push((name.to_owned(), value.to_owned()));
+                }
+            } else if first & 0x40 != 0 {
+                // literal header field with incremental indexing
+                let header = self.decode_literal(&mut block, 6)?;
+                list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+                if list_size <= self.max_list_size {
+                    headers.push(header.clone());
+                }
+                self.insert(header);
+            } else if first & 0x20 != 0 {
+                // dynamic table size update, only allowed at the start
+                if list_size != 0 {
+                    return Err(CompressionError);
+                }
+                let max_size = decode_int(&mut block, 5)?;
+                if max_size > self.max_size_limit {
+                    return Err(CompressionError);
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // literal header field without indexing or never indexed
+                let header = self.decode_literal(&mut block, 4)?;
+                list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+                if list_size <= self.max_list_size {
+                    headers.push(header);
+                }
+            }
+        }
+        Ok((list_size <= self.max_list_size).then_some(headers))
+    }
+
+    fn get(&self, index: usize) -> Result<(&str, &str), CompressionError> {
+        match index {
+            0 => Err(CompressionError),
+            1..=61 => Ok(STATIC_TABLE[index - 1]),
+            _ => self
+                .table
+                .get(index - 62)
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .ok_or(CompressionError),
+        }
+    }
+
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix_bits: u8,
+    ) -> Result<(String, String), CompressionError> {
+        let name = match decode_int(block, prefix_bits)? {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0.to_owned(),
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // An entry larger than the table empties it (RFC 7541, section 4.4).
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Evicts entries until `additional` bytes fit.
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+fn decode_int(block: &mut &[u8], prefix_bits: u8) -> Result<usize, CompressionError> {
+    let (&first, rest) = block.split_first().ok_or(CompressionError)?;
+    *block = rest;
+    let max_prefix = (1 << prefix_bits) - 1;
+    let mut value = (first & max_prefix) as usize;
+    if value < max_prefix as usize {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(CompressionError)?;
+        *block = rest;
+        // Larger values aren't needed for any length or index.
+        if shift > 28 {
+            return Err(CompressionError);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, CompressionError> {
+    let is_huffman = block.first().ok_or(CompressionError)? & 0x80 != 0;
+    let len = decode_int(block, 7)?;
+    if len > block.len() {
+        return Err(CompressionError);
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+
+    let bytes = if is_huffman {
+        huffman::decode(bytes).ok_or(CompressionError)?
+    } else {
+        bytes.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Encodes a header block without the dynamic table or Huffman coding,
+/// so the encoder has no state to keep in sync.
+pub fn encode<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|&h| h == (name, value)) {
+            encode_int(&mut block, index + 1, 7, 0x80);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_int(&mut block, index + 1, 4, 0),
+            None => {
+                block.push(0);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+fn encode_int(block: &mut Vec<u8>, mut value: usize, prefix_bits: u8, flags: u8) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn encode_string(block: &mut Vec<u8>, s: &str) {
+    encode_int(block, s.len(), 7, 0);
+    block.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_int, encode, encode_int, Decoder};
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_int() {
+        // RFC 7541, appendix C.1
+        let mut block = vec![];
+        encode_int(&mut block, 10, 5, 0);
+        assert_eq!(block, [0x0a]);
+        let mut block = vec![];
+        encode_int(&mut block, 1337, 5, 0);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_int(&mut &block[..], 5).unwrap(), 1337);
+
+        assert!(decode_int(&mut &[0x1f, 0x9a][..], 5).is_err());
+        assert!(decode_int(&mut &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..], 5).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        // RFC 7541, appendix C.3
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let block =
+            b"\x82\x86\x84\x41\x0f\x77\x77\x77\x2e\x65\x78\x61\x6d\x70\x6c\x65\x2e\x63\x6f\x6d";
+        let want = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        assert_eq!(decoder.decode(block).unwrap().unwrap(), headers(&want));
+        assert_eq!(decoder.size, 57);
+
+        let block = b"\x82\x86\x84\xbe\x58\x08\x6e\x6f\x2d\x63\x61\x63\x68\x65";
+        let want = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ];
+        assert_eq!(decoder.decode(block).unwrap().unwrap(), headers(&want));
+        assert_eq!(decoder.size, 110);
+
+        let block = b"\x82\x87\x85\xbf\x40\x0a\x63\x75\x73\x74\x6f\x6d\x2d\x6b\x65\x79\
+                      \x0c\x63\x75\x73\x74\x6f\x6d\x2d\x76\x61\x6c\x75\x65";
+        let want = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ];
+        assert_eq!(decoder.decode(block).unwrap().unwrap(), headers(&want));
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_decode_huffman() {
+        // RFC 7541, appendix C.4.1
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        let block = b"\x82\x86\x84\x41\x8c\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff";
+        let want = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ];
+        assert_eq!(decoder.decode(block).unwrap().unwrap(), headers(&want));
+    }
+
+    #[test]
+    fn test_decode_eviction() {
+        let mut decoder = Decoder::new(100, usize::MAX);
+        // custom-key: custom-value (54 bytes), then custom-key: other
+        // (47 bytes) with the name taken from the first entry
+        let block = b"\x40\x0a\x63\x75\x73\x74\x6f\x6d\x2d\x6b\x65\x79\
+                      \x0c\x63\x75\x73\x74\x6f\x6d\x2d\x76\x61\x6c\x75\x65\
+                      \x7e\x05other";
+        let want = [("custom-key", "custom-value"), ("custom-key", "other")];
+        assert_eq!(decoder.decode(block).unwrap().unwrap(), headers(&want));
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.size, 47);
+        assert_eq!(
+            decoder.decode(b"\xbe").unwrap().unwrap(),
+            headers(&want[1..])
+        );
+        // the first entry has been evicted
+        assert!(decoder.decode(b"\xbf").is_err());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = Decoder::new(4096, usize::MAX);
+        // index 0
+        assert!(decoder.decode(b"\x80").is_err());
+        // index beyond the tables
+        assert!(decoder.decode(b"\xbe").is_err());
+        // string longer than the block
+        assert!(decoder.decode(b"\x00\x05abc").is_err());
+        // table size update above the limit
+        assert!(decoder.decode(b"\x3f\xe2\x1f").is_err());
+        // table size update after a header field
+        assert!(decoder.decode(b"\x82\x20").is_err());
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let mut decoder = Decoder::new(4096, 16 * 1024);
+        // a large field added to the table, then referenced over and over
+        let mut block = vec![0x40];
+        encode_int(&mut block, 1, 7, 0);
+        block.push(b'x');
+        encode_int(&mut block, 4000, 7, 0);
+        block.extend([b'a'; 4000]);
+        block.extend([0xbe; 1000]);
+        assert_eq!(decoder.decode(&block).unwrap(), None);
+
+        // the table was updated anyway
+        let want = [("x", "a".repeat(4000))];
+        let want: Vec<_> = want
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        assert_eq!(decoder.decode(b"\xbe").unwrap(), Some(want));
+    }
+
+    #[test]
+    fn test_encode() {
+        let want = [
+            (":status", "200"),
+            (":status", "201"),
+            ("content-type", "text/plain"),
+            ("x-custom", "value"),
+        ];
+        let block = encode(want);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(
+            Decoder::new(4096, usize::MAX)
+                .decode(&block)
+                .unwrap()
+                .unwrap(),
+            headers(&want)
+        );
+    }
+}
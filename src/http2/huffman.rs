@@ -0,0 +1,127 @@
+//! Huffman decoding of HPACK string literals (RFC 7541, appendix B).
+
+use std::sync::OnceLock;
+
+/// Code length of each symbol, the last one being EOS. The code is
+/// canonical, so the codes follow from the lengths.
+#[rustfmt::skip]
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,];
+
+const EOS: u16 = 256;
+const MAX_CODE_LENGTH: usize = 30;
+
+struct Table {
+    /// Symbols ordered by code.
+    symbols: Vec<u16>,
+    /// Per code length: the first code, the index of its symbol and the
+    /// number of codes.
+    first_code: [u32; MAX_CODE_LENGTH + 1],
+    first_index: [usize; MAX_CODE_LENGTH + 1],
+    count: [u32; MAX_CODE_LENGTH + 1],
+}
+
+fn table() -> &'static Table {
+    static TABLE: OnceLock<Table> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=EOS).collect();
+        symbols.sort_by_key(|&symbol| CODE_LENGTHS[symbol as usize]);
+
+        let mut count = [0; MAX_CODE_LENGTH + 1];
+        for len in CODE_LENGTHS {
+            count[len as usize] += 1;
+        }
+        let mut first_code = [0; MAX_CODE_LENGTH + 1];
+        let mut first_index = [0; MAX_CODE_LENGTH + 1];
+        let (mut code, mut index) = (0, 0);
+        for len in 1..=MAX_CODE_LENGTH {
+            first_code[len] = code;
+            first_index[len] = index;
+            code = (code + count[len]) << 1;
+            index += count[len] as usize;
+        }
+
+        Table {
+            symbols,
+            first_code,
+            first_index,
+            count,
+        }
+    })
+}
+
+/// Returns `None` if `input` isn't a valid encoding.
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    let table = table();
+    let mut output = Vec::with_capacity(input.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0);
+    for byte in input {
+        for shift in (0..8).rev() {
+            code = (code << 1) | (byte >> shift & 1) as u32;
+            len += 1;
+            let offset = code.wrapping_sub(table.first_code[len]);
+            if offset < table.count[len] {
+                let symbol = table.symbols[table.first_index[len] + offset as usize];
+                if symbol == EOS {
+                    return None;
+                }
+                output.push(symbol as u8);
+                (code, len) = (0, 0);
+            } else if len == MAX_CODE_LENGTH {
+                return None;
+            }
+        }
+    }
+
+    // The padding is a prefix of EOS, which consists of ones.
+    if len > 7 || code != (1 << len) - 1 {
+        return None;
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+
+    #[test]
+    fn test_decode() {
+        // RFC 7541, appendix C.4
+        let input = b"\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff";
+        assert_eq!(decode(input).unwrap(), b"www.example.com");
+        let input = b"\xa8\xeb\x10\x64\x9c\xbf";
+        assert_eq!(decode(input).unwrap(), b"no-cache");
+        let input = b"\x25\xa8\x49\xe9\x5b\xb8\xe8\xb4\xbf";
+        assert_eq!(decode(input).unwrap(), b"custom-value");
+        assert_eq!(decode(b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        // padding longer than 7 bits
+        assert_eq!(
+            decode(b"\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff\xff"),
+            None
+        );
+        // padding not made of ones
+        assert_eq!(decode(b"\x00"), None);
+        // EOS
+        assert_eq!(decode(b"\xff\xff\xff\xff"), None);
+    }
+}
@@ -0,0 +1,1447 @@
+//! HTTP/2 (RFC 9113). Frames are read on the connection's thread, while
+//! every stream is handled on a thread of its own, so that slow handlers
+//! don't hold up the other streams. Stream threads write their frames
+//! while the connection's thread is blocked reading, so the underlying
+//! stream must not hold a lock across socket I/O (see `tls::TlsStream`).
+
+mod frame;
+mod hpack;
+mod huffman;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    sync::{Condvar, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tracing::{debug, info, warn, Span};
+
+use crate::{
+    headers::Headers,
+    request::{Framing, HttpVersion, InvalidRequest, LimitExceeded, Limits, Request, RequestLine},
+    response_writer::{has_framed_body, Body, ResponseWriter},
+    server::{check_expectation, create_req_span, ConnInfo, Handler, Timeouts},
+    status_code_registry::ReasonPhrase,
+    stream::Stream,
+    timed_stream::RequestTimeout,
+};
+
+pub use frame::PREFACE;
+use frame::{
+    parse_settings, parse_u31, ConnectionError, ErrorCode, Frame, FrameReader, ACK, CONTINUATION,
+    DATA, DEFAULT_MAX_FRAME_SIZE, DEFAULT_WINDOW_SIZE, END_HEADERS, END_STREAM, GOAWAY, HEADERS,
+    MAX_MAX_FRAME_SIZE, MAX_WINDOW_SIZE, PING, PRIORITY, PUSH_PROMISE, RST_STREAM, SETTINGS,
+    SETTINGS_ENABLE_PUSH, SETTINGS_INITIAL_WINDOW_SIZE, SETTINGS_MAX_CONCURRENT_STREAMS,
+    SETTINGS_MAX_FRAME_SIZE, SETTINGS_MAX_HEADER_LIST_SIZE, WINDOW_UPDATE,
+};
+
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const HEADER_TABLE_SIZE: usize = 4096;
+
+/// Fields that only apply to HTTP/1 connections (RFC 9113, section 8.2.2).
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// An HTTP/1.1 request asking to switch to h2c. It is answered on stream 1.
+#[derive(Debug)]
+pub struct Upgrade {
+    settings: Vec<u8>,
+    fields: Vec<(String, String)>,
+}
+
+/// Returns the upgrade `r` asks for with `Upgrade: h2c` (RFC 7540,
+/// section 3.2).
+pub fn h2c_upgrade(r: &Request) -> Option<Upgrade> {
+    if r.get_http_version() != HttpVersion::Http11 {
+        return None;
+    }
+    let headers = r.get_headers();
+    let has_option = |name: &str, option: &str| {
+        headers
+            .get_iter(name)
+            .is_some_and(|mut it| it.any(|o| o.eq_ignore_ascii_case(option)))
+    };
+    if !has_option("upgrade", "h2c")
+        || !has_option("connection", "upgrade")
+        || !has_option("connection", "http2-settings")
+    {
+        return None;
+    }
+    let settings = headers.get_scalar("http2-settings").ok()??;
+    let settings = URL_SAFE_NO_PAD
+        .decode(settings.trim_end_matches('='))
+        .ok()?;
+
+    let mut fields = vec![
+        (":method".to_owned(), r.get_http_method().to_owned()),
+        (":scheme".to_owned(), "http".to_owned()),
+        (":path".to_owned(), r.get_request_target().to_owned()),
+    ];
+    for (name, value) in headers.iter() {
+        match name {
+            "host" => fields.insert(3, (":authority".to_owned(), value)),
+            "http2-settings" | "te" => {}
+            _ if CONNECTION_SPECIFIC.contains(&name) => {}
+            _ => fields.push((name.to_owned(), value)),
+        }
+    }
+    Some(Upgrade { settings, fields })
+}
+
+/// Serves an HTTP/2 connection until it is closed. `preface` is the part of
+/// the client connection preface that hasn't been read from `reader` yet.
+#[allow(clippy::too_many_arguments)]
+pub fn serve(
+    reader: impl Read,
+    writer: &Stream,
+    preface: &[u8],
+    upgrade: Option<Upgrade>,
+    conn: &ConnInfo,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    handler: &(impl Handler + Sync + ?Sized),
+) -> anyhow::Result<()> {
+    info!("http2");
+    let shared = Shared::new(writer);
+    let ctx = Context {
+        conn,
+        timeouts,
+        limits,
+        handler,
+    };
+    let span = Span::current();
+    let mut conn = Conn::new(&shared, reader, limits);
+
+    let res = thread::scope(|s| {
+        let res = conn.run(preface, upgrade, |new_stream| {
+            let (span, ctx, shared) = (span.clone(), &ctx, &shared);
+            s.spawn(move || {
+                let _guard = span.enter();
+                serve_stream(ctx, shared, new_stream);
+            });
+        });
+        shared.close();
+        res
+    });
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(err) if is_timeout(&err) => {
+            debug!("idle timeout");
+            Ok(())
+        }
+        Err(err) => {
+            let Some(ConnectionError(error_code)) = err.downcast_ref() else {
+                return Err(err);
+            };
+            debug!(?error_code, "connection error");
+            let goaway = Frame::goaway(conn.last_stream_id, *error_code);
+            shared.write_frames(&[goaway]).map_err(Into::into)
+        }
+    }
+}
+
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| {
+        matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+    })
+}
+
+struct Context<'a, H: ?Sized> {
+    conn: &'a ConnInfo,
+    timeouts: &'a Timeouts,
+    limits: &'a Limits,
+    handler: &'a H,
+}
+
+/// The state shared by the connection's thread and the stream threads.
+struct Shared<'a> {
+    stream: &'a Stream,
+    /// Keeps frames of different streams from interleaving. Never acquired
+    /// while `state` is locked.
+    write_lock: Mutex<()>,
+    state: Mutex<State>,
+    /// Signals changes of `state`.
+    cond: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    streams: HashMap<u32, StreamState>,
+    /// Send window of the connection.
+    send_window: i64,
+    /// The peer's initial send window of new streams.
+    initial_window: i64,
+    /// The largest frame the peer accepts.
+    max_frame_size: usize,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct StreamState {
+    /// Received body data the handler hasn't read yet.
+    body: VecDeque<u8>,
+    body_end: bool,
+    body_error: Option<BodyError>,
+    received: u64,
+    content_length: Option<u64>,
+    recv_window: i64,
+    send_window: i64,
+    reset: bool,
+    timed_out: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum BodyError {
+    TooLarge,
+    /// The body doesn't match the content length.
+    Invalid,
+}
+
+impl From<BodyError> for io::Error {
+    fn from(err: BodyError) -> Self {
+        match err {
+            BodyError::TooLarge => io::Error::new(io::ErrorKind::InvalidData, LimitExceeded::Body),
+            BodyError::Invalid => io::Error::new(io::ErrorKind::InvalidData, InvalidRequest),
+        }
+    }
+}
+
+impl StreamState {
+    fn end_body(&mut self) {
+        self.body_end = true;
+        if self.content_length.is_some_and(|l| l != self.received) {
+            self.body_error.get_or_insert(BodyError::Invalid);
+        }
+    }
+}
+
+impl State {
+    /// Returns the stream, unless it or the connection has been closed.
+    fn stream_mut(&mut self, id: u32) -> io::Result<&mut StreamState> {
+        if self.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection closed",
+            ));
+        }
+        match self.streams.get_mut(&id) {
+            Some(stream) if !stream.reset => Ok(stream),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream reset",
+            )),
+        }
+    }
+}
+
+impl<'a> Shared<'a> {
+    fn new(stream: &'a Stream) -> Self {
+        Self {
+            stream,
+            write_lock: Mutex::new(()),
+            state: Mutex::new(State {
+                streams: HashMap::new(),
+                send_window: DEFAULT_WINDOW_SIZE,
+                initial_window: DEFAULT_WINDOW_SIZE,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn write_frames(&self, frames: &[Frame]) -> io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut writer = self.stream;
+        for frame in frames {
+            if let Err(err) = frame.write_to(&mut writer) {
+                self.close();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wakes up the stream threads waiting for data or window updates.
+    fn close(&self) {
+        self.lock().closed = true;
+        self.cond.notify_all();
+    }
+
+    fn reset_stream(&self, id: u32, error_code: ErrorCode) -> io::Result<()> {
+        if let Some(stream) = self.lock().streams.get_mut(&id) {
+            stream.reset = true;
+        }
+        self.cond.notify_all();
+        self.write_frames(&[Frame::rst_stream(id, error_code)])
+    }
+
+    /// Removes a stream whose response has been sent.
+    fn finish_stream(&self, id: u32) {
+        let Some(stream) = self.lock().streams.remove(&id) else {
+            return;
+        };
+        // The rest of the body isn't needed anymore (RFC 9113, section 8.1).
+        if !stream.reset && !stream.body_end {
+            let _ = self.write_frames(&[Frame::rst_stream(id, ErrorCode::NoError)]);
+        }
+    }
+
+    fn send_headers(
+        &self,
+        id: u32,
+        fields: &[(String, String)],
+        end_stream: bool,
+    ) -> io::Result<()> {
+        let block = hpack::encode(fields.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        let max_frame_size = {
+            let mut state = self.lock();
+            state.stream_mut(id)?;
+            state.max_frame_size
+        };
+
+        let mut frames: Vec<_> = block
+            .chunks(max_frame_size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let kind = if i == 0 { HEADERS } else { CONTINUATION };
+                Frame::new(kind, 0, id, chunk.to_vec())
+            })
+            .collect();
+        frames.last_mut().unwrap().flags |= END_HEADERS;
+        if end_stream {
+            frames[0].flags |= END_STREAM;
+        }
+        self.write_frames(&frames)
+    }
+
+    /// Sends `data` as the flow control windows allow.
+    fn send_data(&self, id: u32, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let n = {
+                let mut state = self.lock();
+                loop {
+                    let conn_window = state.send_window;
+                    let max_frame_size = state.max_frame_size;
+                    let stream = state.stream_mut(id)?;
+                    let window = conn_window.min(stream.send_window);
+                    if data.is_empty() || window > 0 {
+                        let n = data.len().min(window.max(0) as usize).min(max_frame_size);
+                        stream.send_window -= n as i64;
+                        state.send_window -= n as i64;
+                        break n;
+                    }
+                    state = self.cond.wait(state).unwrap();
+                }
+            };
+
+            let (chunk, rest) = data.split_at(n);
+            let flags = if end_stream && rest.is_empty() {
+                END_STREAM
+            } else {
+                0
+            };
+            self.write_frames(&[Frame::new(DATA, flags, id, chunk.to_vec())])?;
+            data = rest;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A stream opened by the client.
+struct NewStream {
+    id: u32,
+    /// Position among the streams of the connection.
+    index: usize,
+    /// `None` if the fields exceeded the header list size limit.
+    fields: Option<Vec<(String, String)>>,
+    end_stream: bool,
+}
+
+/// Reads and processes the frames of the connection.
+struct Conn<'s, 'a, R> {
+    shared: &'s Shared<'a>,
+    frames: FrameReader<R>,
+    decoder: hpack::Decoder,
+    limits: &'s Limits,
+    /// Stream id, flags and the header block so far of a `HEADERS` frame
+    /// that is continued with `CONTINUATION` frames.
+    pending_headers: Option<(u32, u8, Vec<u8>)>,
+    last_stream_id: u32,
+    stream_count: usize,
+    going_away: bool,
+}
+
+impl<'s, 'a, R: Read> Conn<'s, 'a, R> {
+    fn new(shared: &'s Shared<'a>, reader: R, limits: &'s Limits) -> Self {
+        Self {
+            shared,
+            frames: FrameReader::new(reader),
+            decoder: hpack::Decoder::new(
+                HEADER_TABLE_SIZE,
+                limits.header_bytes.try_into().unwrap_or(usize::MAX),
+            ),
+            limits,
+            pending_headers: None,
+            last_stream_id: 0,
+            stream_count: 0,
+            going_away: false,
+        }
+    }
+
+    fn run(
+        &mut self,
+        preface: &[u8],
+        upgrade: Option<Upgrade>,
+        mut spawn: impl FnMut(NewStream),
+    ) -> anyhow::Result<()> {
+        let max_header_list_size = self.limits.header_bytes.try_into().unwrap_or(u32::MAX);
+        let settings = Frame::settings(&[
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, max_header_list_size),
+        ]);
+        self.shared.write_frames(&[settings])?;
+
+        if let Some(upgrade) = upgrade {
+            self.apply_settings(&upgrade.settings)?;
+            spawn(self.open_stream(1, Some(upgrade.fields), true));
+        }
+        if self.frames.read_exact(preface.len())? != preface {
+            Err(ConnectionError(ErrorCode::ProtocolError))?
+        }
+
+        loop {
+            if self.going_away && self.shared.lock().streams.is_empty() {
+                return Ok(());
+            }
+            let frame = match self.frames.read_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(err) if is_timeout(&err) => {
+                    if !self.shared.lock().streams.is_empty() {
+                        continue;
+                    }
+                    debug!("idle timeout");
+                    let goaway = Frame::goaway(self.last_stream_id, ErrorCode::NoError);
+                    return self.shared.write_frames(&[goaway]).map_err(Into::into);
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(new_stream) = self.process(frame)? {
+                spawn(new_stream);
+            }
+        }
+    }
+
+    fn process(&mut self, mut frame: Frame) -> anyhow::Result<Option<NewStream>> {
+        let protocol_error = ConnectionError(ErrorCode::ProtocolError);
+        let frame_size_error = ConnectionError(ErrorCode::FrameSizeError);
+        if let Some((id, _, _)) = self.pending_headers {
+            if frame.kind != CONTINUATION || frame.stream_id != id {
+                Err(protocol_error)?
+            }
+        }
+        let id = frame.stream_id;
+
+        match frame.kind {
+            DATA => self.on_data(frame)?,
+            HEADERS => {
+                if id == 0 {
+                    Err(protocol_error)?
+                }
+                frame.strip_padding()?;
+                if frame.has_flag(END_HEADERS) {
+                    return self.on_header_block(id, frame.flags, frame.payload);
+                }
+                self.pending_headers = Some((id, frame.flags, frame.payload));
+            }
+            CONTINUATION => {
+                let Some((id, flags, mut block)) = self.pending_headers.take() else {
+                    return Err(protocol_error.into());
+                };
+                block.extend(&frame.payload);
+                if block.len() as u64 > self.limits.header_bytes + DEFAULT_MAX_FRAME_SIZE as u64 {
+                    Err(ConnectionError(ErrorCode::EnhanceYourCalm))?
+                }
+                if frame.has_flag(END_HEADERS) {
+                    return self.on_header_block(id, flags, block);
+                }
+                self.pending_headers = Some((id, flags, block));
+            }
+            PRIORITY => {
+                if id == 0 {
+                    Err(protocol_error)?
+                }
+                if frame.payload.len() != 5 {
+                    self.shared.reset_stream(id, ErrorCode::FrameSizeError)?;
+                }
+            }
+            RST_STREAM => {
+                if id == 0 || id > self.last_stream_id {
+                    Err(protocol_error)?
+                }
+                if frame.payload.len() != 4 {
+                    Err(frame_size_error)?
+                }
+                if let Some(stream) = self.shared.lock().streams.get_mut(&id) {
+                    stream.reset = true;
+                }
+                self.shared.cond.notify_all();
+            }
+            SETTINGS => {
+                if id != 0 {
+                    Err(protocol_error)?
+                }
+                if frame.has_flag(ACK) {
+                    if !frame.payload.is_empty() {
+                        Err(frame_size_error)?
+                    }
+                    return Ok(None);
+                }
+                self.apply_settings(&frame.payload)?;
+                self.shared
+                    .write_frames(&[Frame::new(SETTINGS, ACK, 0, vec![])])?;
+            }
+            PUSH_PROMISE => Err(protocol_error)?,
+            PING => {
+                if id != 0 {
+                    Err(protocol_error)?
+                }
+                if frame.payload.len() != 8 {
+                    Err(frame_size_error)?
+                }
+                if !frame.has_flag(ACK) {
+                    self.shared
+                        .write_frames(&[Frame::new(PING, ACK, 0, frame.payload)])?;
+                }
+            }
+            GOAWAY => {
+                if id != 0 {
+                    Err(protocol_error)?
+                }
+                debug!("goaway received");
+                self.going_away = true;
+            }
+            WINDOW_UPDATE => self.on_window_update(frame)?,
+            // Unknown frame types are ignored (RFC 9113, section 4.1).
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn on_header_block(
+        &mut self,
+        id: u32,
+        flags: u8,
+        block: Vec<u8>,
+    ) -> anyhow::Result<Option<NewStream>> {
+        // The block has to be decoded even if the stream is refused, to keep
+        // the dynamic table in sync.
+        let fields = self
+            .decoder
+            .decode(&block)
+            .map_err(|_| ConnectionError(ErrorCode::CompressionError))?;
+        let end_stream = flags & END_STREAM != 0;
+
+        if id <= self.last_stream_id {
+            // trailers, which are ignored
+            let mut state = self.shared.lock();
+            let Some(stream) = state.streams.get_mut(&id) else {
+                return Ok(None);
+            };
+            let error_code = match (stream.body_end, end_stream) {
+                (true, _) => ErrorCode::StreamClosed,
+                (false, false) => ErrorCode::ProtocolError,
+                (false, true) => {
+                    stream.end_body();
+                    self.shared.cond.notify_all();
+                    return Ok(None);
+                }
+            };
+            drop(state);
+            self.shared.reset_stream(id, error_code)?;
+            return Ok(None);
+        }
+        if id % 2 == 0 {
+            Err(ConnectionError(ErrorCode::ProtocolError))?
+        }
+        self.last_stream_id = id;
+
+        if self.going_away || self.shared.lock().streams.len() >= MAX_CONCURRENT_STREAMS {
+            self.shared
+                .write_frames(&[Frame::rst_stream(id, ErrorCode::RefusedStream)])?;
+            return Ok(None);
+        }
+        let new_stream = self.open_stream(id, fields, end_stream);
+        if self.stream_count >= self.limits.max_requests {
+            debug!("max requests reached");
+            self.going_away = true;
+            self.shared
+                .write_frames(&[Frame::goaway(id, ErrorCode::NoError)])?;
+        }
+        Ok(Some(new_stream))
+    }
+
+    fn open_stream(
+        &mut self,
+        id: u32,
+        fields: Option<Vec<(String, String)>>,
+        end_stream: bool,
+    ) -> NewStream {
+        self.last_stream_id = id;
+        let index = self.stream_count;
+        self.stream_count += 1;
+        let content_length = fields
+            .iter()
+            .flatten()
+            .find(|(k, _)| k == "content-length")
+            .and_then(|(_, v)| v.parse().ok());
+        let mut state = self.shared.lock();
+        let send_window = state.initial_window;
+        state.streams.insert(
+            id,
+            StreamState {
+                body: VecDeque::new(),
+                body_end: end_stream,
+                body_error: None,
+                received: 0,
+                content_length,
+                recv_window: DEFAULT_WINDOW_SIZE,
+                send_window,
+                reset: false,
+                timed_out: false,
+            },
+        );
+        NewStream {
+            id,
+            index,
+            fields,
+            end_stream,
+        }
+    }
+
+    fn on_data(&mut self, mut frame: Frame) -> anyhow::Result<()> {
+        let id = frame.stream_id;
+        if id == 0 {
+            Err(ConnectionError(ErrorCode::ProtocolError))?
+        }
+        let len = frame.payload.len();
+        let padding = frame.strip_padding()?;
+
+        // The connection window is replenished right away, the window of the
+        // stream as the handler reads the body.
+        let mut window_updates = vec![];
+        if len > 0 {
+            window_updates.push(Frame::window_update(0, len as u32));
+        }
+        let mut error_code = None;
+        let mut state = self.shared.lock();
+        match state.streams.get_mut(&id) {
+            None if id > self.last_stream_id => Err(ConnectionError(ErrorCode::ProtocolError))?,
+            // closed by now
+            None => {}
+            Some(stream) if stream.reset => {}
+            Some(stream) if stream.body_end => error_code = Some(ErrorCode::StreamClosed),
+            Some(stream) if stream.recv_window < len as i64 => {
+                error_code = Some(ErrorCode::FlowControlError)
+            }
+            Some(stream) => {
+                stream.recv_window -= (len - padding) as i64;
+                if padding > 0 {
+                    window_updates.push(Frame::window_update(id, padding as u32));
+                }
+                stream.received += frame.payload.len() as u64;
+                if stream.received > self.limits.body_size {
+                    stream.body_error.get_or_insert(BodyError::TooLarge);
+                } else if stream.content_length.is_some_and(|l| stream.received > l) {
+                    stream.body_error.get_or_insert(BodyError::Invalid);
+                }
+                if stream.body_error.is_none() {
+                    stream.body.extend(&frame.payload);
+                }
+                if frame.has_flag(END_STREAM) {
+                    stream.end_body();
+                }
+                self.shared.cond.notify_all();
+            }
+        }
+        drop(state);
+
+        self.shared.write_frames(&window_updates)?;
+        if let Some(error_code) = error_code {
+            self.shared.reset_stream(id, error_code)?;
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> anyhow::Result<()> {
+        if frame.payload.len() != 4 {
+            Err(ConnectionError(ErrorCode::FrameSizeError))?
+        }
+        let increment = parse_u31(&frame.payload) as i64;
+        let id = frame.stream_id;
+
+        let mut state = self.shared.lock();
+        if id == 0 {
+            state.send_window += increment;
+            if increment == 0 {
+                Err(ConnectionError(ErrorCode::ProtocolError))?
+            }
+            if state.send_window > MAX_WINDOW_SIZE {
+                Err(ConnectionError(ErrorCode::FlowControlError))?
+            }
+            self.shared.cond.notify_all();
+            return Ok(());
+        }
+
+        let Some(stream) = state.streams.get_mut(&id) else {
+            if id > self.last_stream_id {
+                Err(ConnectionError(ErrorCode::ProtocolError))?
+            }
+            return Ok(());
+        };
+        stream.send_window += increment;
+        let error_code = if increment == 0 {
+            ErrorCode::ProtocolError
+        } else if stream.send_window > MAX_WINDOW_SIZE {
+            ErrorCode::FlowControlError
+        } else {
+            self.shared.cond.notify_all();
+            return Ok(());
+        };
+        drop(state);
+        self.shared.reset_stream(id, error_code)?;
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), ConnectionError> {
+        let settings = parse_settings(payload)?;
+        let mut state = self.shared.lock();
+        for (id, value) in settings {
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(ConnectionError(ErrorCode::ProtocolError))
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(ConnectionError(ErrorCode::FlowControlError));
+                    }
+                    let delta = value - state.initial_window;
+                    state.initial_window = value;
+                    for stream in state.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(ConnectionError(ErrorCode::FlowControlError));
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) {
+                        return Err(ConnectionError(ErrorCode::ProtocolError));
+                    }
+                    state.max_frame_size = value;
+                }
+                _ => {}
+            }
+        }
+        self.shared.cond.notify_all();
+        Ok(())
+    }
+}
+
+/// Reads the body of a stream as the client sends it.
+struct StreamBody<'s, 'a> {
+    shared: &'s Shared<'a>,
+    id: u32,
+    timeout: Duration,
+}
+
+impl Read for StreamBody<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.shared.lock();
+        let (n, body_end) = loop {
+            let stream = state.stream_mut(self.id)?;
+            if let Some(err) = stream.body_error {
+                return Err(err.into());
+            }
+            if !stream.body.is_empty() {
+                let n = buf.len().min(stream.body.len());
+                for (dst, src) in buf.iter_mut().zip(stream.body.drain(..n)) {
+                    *dst = src;
+                }
+                stream.recv_window += n as i64;
+                break (n, stream.body_end);
+            }
+            if stream.body_end {
+                return Ok(0);
+            }
+
+            let (guard, res) = self.shared.cond.wait_timeout(state, self.timeout).unwrap();
+            state = guard;
+            if res.timed_out() {
+                if let Some(stream) = state.streams.get_mut(&self.id) {
+                    stream.timed_out = true;
+                }
+                return Err(io::Error::new(io::ErrorKind::TimedOut, RequestTimeout));
+            }
+        };
+        drop(state);
+
+        if !body_end && n > 0 {
+            self.shared
+                .write_frames(&[Frame::window_update(self.id, n as u32)])?;
+        }
+        Ok(n)
+    }
+}
+
+/// The request line and headers of a stream in HTTP/1 form.
+struct Head {
+    method: String,
+    path: String,
+    header_lines: String,
+}
+
+enum InvalidHead {
+    /// Reset with `PROTOCOL_ERROR` (RFC 9113, section 8.1.1).
+    Malformed,
+    /// Answered with a response.
+    Rejected(ReasonPhrase),
+}
+
+fn parse_fields(fields: Vec<(String, String)>, limits: &Limits) -> Result<Head, InvalidHead> {
+    let size: usize = fields.iter().map(|(k, v)| k.len() + v.len() + 32).sum();
+    if size as u64 > limits.header_bytes || fields.len() > limits.header_count {
+        return Err(InvalidHead::Rejected(
+            ReasonPhrase::RequestHeaderFieldsTooLarge,
+        ));
+    }
+
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut header_lines = String::new();
+    let mut cookies = vec![];
+    let mut has_host = false;
+    let mut regular_seen = false;
+    for (name, value) in fields {
+        if value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+            return Err(InvalidHead::Malformed);
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let field = match pseudo {
+                _ if regular_seen => return Err(InvalidHead::Malformed),
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(InvalidHead::Malformed),
+            };
+            if field.replace(value).is_some() {
+                return Err(InvalidHead::Malformed);
+            }
+            continue;
+        }
+
+        regular_seen = true;
+        let is_name_byte = |b: u8| b.is_ascii_graphic() && !b.is_ascii_uppercase() && b != b':';
+        if name.is_empty()
+            || !name.bytes().all(is_name_byte)
+            || CONNECTION_SPECIFIC.contains(&name.as_str())
+            || (name == "te" && value != "trailers")
+        {
+            return Err(InvalidHead::Malformed);
+        }
+        match name.as_str() {
+            // Split cookies are joined again (RFC 9113, section 8.2.3).
+            "cookie" => cookies.push(value),
+            _ => {
+                has_host |= name == "host";
+                header_lines += &format!("{}: {}\r\n", name, value);
+            }
+        }
+    }
+
+    let (Some(method), Some(_), Some(path)) = (method, scheme, path) else {
+        return Err(InvalidHead::Malformed);
+    };
+    if path.is_empty() {
+        return Err(InvalidHead::Malformed);
+    }
+    if path.len() as u64 > limits.request_line {
+        return Err(InvalidHead::Rejected(ReasonPhrase::URITooLong));
+    }
+    if !cookies.is_empty() {
+        header_lines += &format!("cookie: {}\r\n", cookies.join("; "));
+    }
+    if let Some(authority) = authority.filter(|_| !has_host) {
+        header_lines += &format!("host: {}\r\n", authority);
+    }
+    Ok(Head {
+        method,
+        path,
+        header_lines,
+    })
+}
+
+fn serve_stream(ctx: &Context<impl Handler + ?Sized>, shared: &Shared, new_stream: NewStream) {
+    let id = new_stream.id;
+    if let Err(err) = respond(ctx, shared, new_stream) {
+        debug!(?err, id, "stream aborted");
+    }
+    shared.finish_stream(id);
+}
+
+fn respond(
+    ctx: &Context<impl Handler + ?Sized>,
+    shared: &Shared,
+    new_stream: NewStream,
+) -> io::Result<()> {
+    let NewStream {
+        id,
+        index,
+        fields,
+        end_stream,
+    } = new_stream;
+    let fields = fields.ok_or(InvalidHead::Rejected(
+        ReasonPhrase::RequestHeaderFieldsTooLarge,
+    ));
+    let head = match fields.and_then(|fields| parse_fields(fields, ctx.limits)) {
+        Ok(head) => head,
+        Err(InvalidHead::Malformed) => {
+            debug!(id, "malformed request");
+            return shared.reset_stream(id, ErrorCode::ProtocolError);
+        }
+        Err(InvalidHead::Rejected(reason_phrase)) => return send_status(shared, id, reason_phrase),
+    };
+    let Ok(headers) = Headers::parse(&head.header_lines) else {
+        return send_status(shared, id, ReasonPhrase::BadRequest);
+    };
+    let request_line = RequestLine::new(&head.method, &head.path, HttpVersion::Http2);
+    let mut r = Request::new(request_line, None, headers, None);
+    ctx.conn.apply(&mut r, index);
+
+    let span = create_req_span(&r);
+    let _guard = span.enter();
+
+    let framing = match Framing::parse(r.get_headers()) {
+        Ok(Framing::Length(length)) if length > ctx.limits.body_size => {
+            return send_status(shared, id, ReasonPhrase::ContentTooLarge)
+        }
+        Ok(Framing::None) if !end_stream => Framing::Chunked,
+        Ok(framing) => framing,
+        Err(_) => return send_status(shared, id, ReasonPhrase::BadRequest),
+    };
+    match check_expectation(&r, framing, ctx.limits, |r| ctx.handler.check_request(r)) {
+        Ok(false) => {}
+        Ok(true) => {
+            let fields = [(":status".to_owned(), "100".to_owned())];
+            shared.send_headers(id, &fields, false)?;
+        }
+        Err(reason_phrase) => {
+            info!(?r, ?reason_phrase, "expectation rejected");
+            return send_status(shared, id, reason_phrase);
+        }
+    }
+
+    if !end_stream {
+        r.set_body_reader(Box::new(StreamBody {
+            shared,
+            id,
+            timeout: ctx.timeouts.body,
+        }));
+    }
+    info!(?r);
+
+    let mut w = ResponseWriter::new_empty();
+    ctx.handler.handle(&mut w, &mut r);
+    drop(r);
+
+    if shared.lock().streams.get(&id).is_some_and(|s| s.timed_out) {
+        warn!("request timeout while reading the body");
+        w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::RequestTimeout);
+    }
+    // HTTP/2 has no `101 Switching Protocols` (RFC 9113, section 8.6).
+    if w.take_upgrade().is_some() {
+        warn!("protocol upgrade not possible on this connection");
+        w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotImplemented);
+    }
+    send_response(shared, id, w)
+}
+
+fn send_status(shared: &Shared, id: u32, reason_phrase: ReasonPhrase) -> io::Result<()> {
+    let mut w = ResponseWriter::new_empty();
+    w.set_reason_phrase(reason_phrase);
+    send_response(shared, id, w)
+}
+
+fn send_response(shared: &Shared, id: u32, mut w: ResponseWriter) -> io::Result<()> {
+    let status_code = w.get_status_code().unwrap_or(500);
+    let mut fields = vec![(":status".to_owned(), status_code.to_string())];
+    fields.extend(
+        w.get_headers()
+            .iter()
+            .map(|(k, v)| (k.to_lowercase(), v.clone()))
+            .filter(|(k, _)| !CONNECTION_SPECIFIC.contains(&k.as_str())),
+    );
+
+    match w.take_body() {
+        Body::Bytes(bytes) => {
+            let has_content_length = fields.iter().any(|(k, _)| k == "content-length");
+            if has_framed_body(status_code) && !has_content_length {
+                fields.push(("content-length".to_owned(), bytes.len().to_string()));
+            }
+            shared.send_headers(id, &fields, bytes.is_empty())?;
+            if !bytes.is_empty() {
+                shared.send_data(id, &bytes, true)?;
+            }
+            Ok(())
+        }
+        Body::Reader(mut reader) => {
+            shared.send_headers(id, &fields, false)?;
+            let mut buf = vec![0; DEFAULT_MAX_FRAME_SIZE];
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        shared.reset_stream(id, ErrorCode::InternalError)?;
+                        return Err(err);
+                    }
+                };
+                shared.send_data(id, &buf[..n], n == 0)?;
+                if n == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    use crate::{
+        request::{Limits, Request},
+        response_writer::ResponseWriter,
+        server::{Server, Timeouts},
+        status_code_registry::ReasonPhrase,
+    };
+
+    use super::{
+        frame::{
+            parse_settings, Frame, FrameReader, DATA, END_HEADERS, END_STREAM, GOAWAY, HEADERS,
+            RST_STREAM, SETTINGS, SETTINGS_INITIAL_WINDOW_SIZE, WINDOW_UPDATE,
+        },
+        hpack, PREFACE,
+    };
+
+    fn start_server(limits: Limits) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_limits(limits);
+        server.set_timeouts(Timeouts {
+            idle: Duration::from_millis(500),
+            ..Default::default()
+        });
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                let host = r
+                    .get_headers()
+                    .get_scalar("host")
+                    .unwrap()
+                    .unwrap_or_default();
+                let mut body = format!(
+                    "{} {} {}",
+                    r.get_http_method(),
+                    host,
+                    r.get_request_target()
+                );
+                if let Some(cookie) = r.get_headers().get_scalar("cookie").unwrap() {
+                    body += &format!(" {}", cookie);
+                }
+                if let Some(req_body) = r.read_body().unwrap() {
+                    body += &format!(" {}", String::from_utf8_lossy(req_body));
+                }
+                if r.get_request_target() == "/slow" {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                w.set_body_str(&body);
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+        addr
+    }
+
+    /// A response read from a raw HTTP/2 connection.
+    #[derive(Debug, Default)]
+    pub struct Response {
+        pub fields: Vec<(String, String)>,
+        pub body: Vec<u8>,
+        pub reset: bool,
+    }
+
+    /// A minimal client speaking HTTP/2 over `stream`.
+    pub struct Client<S> {
+        frames: FrameReader<S>,
+        decoder: hpack::Decoder,
+        next_id: u32,
+        /// Frames skipped while reading responses.
+        pub skipped: Vec<Frame>,
+    }
+
+    impl<S: Read + Write> Client<S> {
+        /// Sends the connection preface, unless the connection was upgraded.
+        pub fn new(mut stream: S, send_preface: bool) -> Self {
+            if send_preface {
+                stream.write_all(PREFACE).unwrap();
+                Frame::settings(&[]).write_to(&mut stream).unwrap();
+            }
+            Self {
+                frames: FrameReader::new(stream),
+                decoder: hpack::Decoder::new(4096, usize::MAX),
+                next_id: 1,
+                skipped: vec![],
+            }
+        }
+
+        pub fn send(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+            Frame::new(kind, flags, id, payload.to_vec())
+                .write_to(self.frames.get_mut())
+                .unwrap();
+        }
+
+        /// Opens a stream, returning its id.
+        pub fn send_request(&mut self, fields: &[(&str, &str)], body: Option<&[u8]>) -> u32 {
+            let id = self.next_id;
+            self.next_id += 2;
+            let flags = if body.is_none() { END_STREAM } else { 0 };
+            self.send(
+                HEADERS,
+                END_HEADERS | flags,
+                id,
+                &hpack::encode(fields.iter().copied()),
+            );
+            if let Some(body) = body {
+                self.send(DATA, END_STREAM, id, body);
+            }
+            id
+        }
+
+        pub fn get(&mut self, authority: &str, path: &str) -> u32 {
+            let fields = [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", authority),
+                (":path", path),
+            ];
+            self.send_request(&fields, None)
+        }
+
+        pub fn read_frame(&mut self) -> Option<Frame> {
+            self.frames.read_frame().unwrap()
+        }
+
+        /// Reads frames until the streams `ids` are complete, returning the
+        /// responses in the order they completed.
+        pub fn read_responses(&mut self, ids: &[u32]) -> Vec<(u32, Response)> {
+            let mut responses: Vec<(u32, Response)> =
+                ids.iter().map(|&id| (id, Response::default())).collect();
+            let mut done = vec![];
+            while done.len() < ids.len() {
+                let frame = self.read_frame().expect("connection closed");
+                let i = match ids.iter().position(|&id| id == frame.stream_id) {
+                    Some(i) if !done.contains(&i) => i,
+                    _ => {
+                        self.skipped.push(frame);
+                        continue;
+                    }
+                };
+                let response = &mut responses[i].1;
+                match frame.kind {
+                    HEADERS => {
+                        response.fields = self.decoder.decode(&frame.payload).unwrap().unwrap()
+                    }
+                    DATA => response.body.extend(&frame.payload),
+                    RST_STREAM => response.reset = true,
+                    _ => {}
+                }
+                if frame.has_flag(END_STREAM) || frame.kind == RST_STREAM {
+                    done.push(i);
+                }
+            }
+            done.into_iter()
+                .map(|i| std::mem::take(&mut responses[i]))
+                .collect()
+        }
+    }
+
+    impl Response {
+        pub fn field(&self, name: &str) -> Option<&str> {
+            self.fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    // - - -
+
+    #[test]
+    fn test_prior_knowledge() {
+        let addr = start_server(Limits::default());
+        let client = reqwest::blocking::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+
+        let url = format!("http://{}/echo", addr);
+        let resp = client.get(&url).send().unwrap();
+        assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], "text/plain");
+        assert_eq!(resp.text().unwrap(), format!("GET {} /echo", addr));
+
+        let body = "x".repeat(200 * 1024);
+        let resp = client.post(&url).body(body.clone()).send().unwrap();
+        assert_eq!(
+            resp.text().unwrap(),
+            format!("POST {} /echo {}", addr, body)
+        );
+    }
+
+    #[test]
+    fn test_multiplexing() {
+        let addr = start_server(Limits::default());
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), true);
+        let slow = client.get("localhost", "/slow");
+        let fast = client.get("localhost", "/fast");
+        let responses = client.read_responses(&[slow, fast]);
+
+        let ids: Vec<_> = responses.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [fast, slow]);
+        let (_, response) = &responses[0];
+        assert_eq!(response.field(":status"), Some("200"));
+        assert_eq!(response.body, b"GET localhost /fast");
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let addr = start_server(Limits::default());
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), false);
+        client.frames.get_mut().write_all(PREFACE).unwrap();
+        Frame::settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 4)])
+            .write_to(client.frames.get_mut())
+            .unwrap();
+        let id = client.get("localhost", "/");
+
+        let mut body: Vec<u8> = vec![];
+        loop {
+            let frame = client.read_frame().unwrap();
+            if frame.kind != DATA {
+                continue;
+            }
+            assert!(frame.payload.len() <= 4);
+            body.extend(&frame.payload);
+            if frame.has_flag(END_STREAM) {
+                break;
+            }
+            client.send(WINDOW_UPDATE, 0, id, &3u32.to_be_bytes());
+        }
+        assert_eq!(body, b"GET localhost /");
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let addr = start_server(Limits::default());
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), true);
+
+        let missing_path = client.send_request(&[(":method", "GET"), (":scheme", "http")], None);
+        let uppercase = client.send_request(
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                ("X-Upper", "1"),
+            ],
+            None,
+        );
+        let too_large = client.send_request(
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/"),
+                ("content-length", "100000000"),
+            ],
+            Some(b""),
+        );
+        let cookies = client.send_request(
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2"),
+            ],
+            None,
+        );
+        let responses = client.read_responses(&[missing_path, uppercase, too_large, cookies]);
+        let response = |id| &responses.iter().find(|(i, _)| *i == id).unwrap().1;
+
+        assert!(response(missing_path).reset);
+        assert!(response(uppercase).reset);
+        assert_eq!(response(too_large).field(":status"), Some("413"));
+        assert_eq!(response(cookies).body, b"GET  / a=1; b=2");
+    }
+
+    #[test]
+    fn test_header_list_too_large() {
+        let addr = start_server(Limits::default());
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), true);
+        let fields = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+
+        // A field of 4000 bytes added to the table and then referenced a
+        // thousand times, which would expand to 4 MB.
+        let mut bomb = hpack::encode(fields.iter().copied());
+        bomb.extend(b"\x40\x05x-big\x7f\xa1\x1e");
+        bomb.extend([b'a'; 4000]);
+        bomb.extend([0xbe; 1000]);
+        client.send(HEADERS, END_HEADERS | END_STREAM, 1, &bomb);
+
+        // The table is still in sync.
+        let mut block = hpack::encode(fields.iter().copied());
+        block.push(0xbe);
+        client.send(HEADERS, END_HEADERS | END_STREAM, 3, &block);
+
+        let responses = client.read_responses(&[1, 3]);
+        let response = |id| &responses.iter().find(|(i, _)| *i == id).unwrap().1;
+        assert_eq!(response(1).field(":status"), Some("431"));
+        assert_eq!(response(3).field(":status"), Some("200"));
+    }
+
+    #[test]
+    fn test_max_requests() {
+        let addr = start_server(Limits {
+            max_requests: 2,
+            ..Default::default()
+        });
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), true);
+        let fst = client.get("localhost", "/fst");
+        let snd = client.get("localhost", "/snd");
+        let responses = client.read_responses(&[fst, snd]);
+        assert_eq!(responses.len(), 2);
+
+        while let Some(frame) = client.read_frame() {
+            client.skipped.push(frame);
+        }
+        let goaway = client.skipped.iter().find(|f| f.kind == GOAWAY).unwrap();
+        assert_eq!(goaway.payload, [0, 0, 0, 3, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_connection_error() {
+        let addr = start_server(Limits::default());
+        let mut client = Client::new(TcpStream::connect(addr).unwrap(), true);
+        // even stream ids are reserved for the server
+        client.send(
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            2,
+            &hpack::encode([(":method", "GET")]),
+        );
+
+        let mut goaway = None;
+        while let Some(frame) = client.read_frame() {
+            if frame.kind == GOAWAY {
+                goaway = Some(frame.payload);
+            }
+        }
+        assert_eq!(goaway.unwrap(), [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_h2c_upgrade() {
+        let addr = start_server(Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let settings = URL_SAFE_NO_PAD
+            .encode(Frame::settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 1 << 20)]).payload);
+        let req = format!(
+            "GET /upgraded HTTP/1.1\r\nHost: localhost\r\nCookie: a=1\r\n\
+             Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+             HTTP2-Settings: {}\r\n\r\n",
+            settings
+        );
+        stream.write_all(req.as_bytes()).unwrap();
+
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n"
+        );
+
+        let mut client = Client::new(stream, true);
+        let frame = client.read_frame().unwrap();
+        assert_eq!(frame.kind, SETTINGS);
+        assert!(parse_settings(&frame.payload).unwrap().contains(&(3, 100)));
+
+        let responses = client.read_responses(&[1]);
+        assert_eq!(responses[0].1.body, b"GET localhost /upgraded a=1");
+
+        client.next_id = 3;
+        let id = client.get("localhost", "/next");
+        let responses = client.read_responses(&[id]);
+        assert_eq!(responses[0].1.body, b"GET localhost /next");
+    }
+
+    #[test]
+    fn test_no_upgrade_with_body() {
+        let addr = start_server(Limits::default());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings, close\r\n\
+                  Upgrade: h2c\r\nHTTP2-Settings: \r\nContent-Length: 2\r\n\r\nhi",
+            )
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.ends_with("\r\n\r\nPOST localhost / hi"), "{}", resp);
+    }
+}
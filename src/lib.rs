@@ -1,46 +1,161 @@
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::{path::PathBuf, process, thread, time::Duration};
+
+use anyhow::Context;
 use clap::Parser;
 
-use middleware::gzip_compressor;
+use config::{Config, LogFormat, LogLevel, LoggingConfig, Mount};
+use content_coding::CompressionLevel;
+use listener::Listener;
+use middleware::{access_log, body_decoder, error_pages, gzip_compressor};
 use request::Request;
 use response_writer::ResponseWriter;
 use router::Router;
-use server::{HttpMethod, Server};
+use server::{Backend, Handler, HttpMethod, Server, Site, SiteHandle};
+use sse::Event;
 use status_code_registry::ReasonPhrase;
+use websocket::{Message, WebSocket, WebSocketRoute};
 
+#[cfg(feature = "tokio")]
+mod async_server;
+mod config;
+mod content_coding;
+mod event_loop;
 mod file_server;
 mod headers;
+mod http2;
+mod listener;
 mod middleware;
 mod multi_map;
+mod proxy;
 mod request;
 mod response_writer;
 mod router;
 mod server;
 mod slice_ext;
+mod sse;
 mod status_code_registry;
+mod stream;
 mod stream_reader;
 #[cfg(test)]
 mod test_utils;
+mod timed_stream;
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
 
+#[cfg(test)]
 #[ctor::ctor]
-fn init_tracing() {
+fn init_test_tracing() {
     use tracing_subscriber::fmt::format;
 
-    let builder = tracing_subscriber::fmt()
+    tracing_subscriber::fmt()
         .event_format(format().pretty())
         .with_target(false)
         .with_file(true)
-        .with_line_number(true);
+        .with_line_number(true)
+        .with_test_writer()
+        .init();
+}
 
-    #[cfg(not(test))]
-    builder.init();
-    #[cfg(test)]
-    builder.with_test_writer().init();
+fn init_tracing(logging: &LoggingConfig) {
+    use tracing_subscriber::fmt::format;
+
+    let builder = tracing_subscriber::fmt().with_max_level(tracing::Level::from(logging.level));
+    match logging.format {
+        LogFormat::Pretty => builder
+            .event_format(format().pretty())
+            .with_target(false)
+            .with_file(true)
+            .with_line_number(true)
+            .init(),
+        LogFormat::Compact => builder.compact().with_target(false).init(),
+    }
 }
 
-#[derive(Parser, Debug)]
+const DEFAULT_LISTEN: &str = "127.0.0.1:4221";
+/// Where `--directory` is mounted.
+const FILES_PREFIX: &str = "/files/";
+
+/// Flags override the settings of the configuration file.
+#[derive(Parser, Debug, Clone)]
 struct Args {
+    /// TOML configuration file, read again on SIGHUP by the threaded backend
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Directory served and written to under /files/
     #[arg(short, long)]
-    directory: Option<String>,
+    directory: Option<PathBuf>,
+    /// Directory with error page templates named `<status code>.html`
+    #[arg(long)]
+    error_pages: Option<PathBuf>,
+    /// Responses smaller than this many bytes are sent uncompressed [default: 1024]
+    #[arg(long)]
+    compression_min_size: Option<usize>,
+    /// [default: fast]
+    #[arg(long, value_enum)]
+    compression_level: Option<CompressionLevel>,
+    /// Maximum size of an uploaded file after decoding its Content-Encoding
+    /// [default: 67108864]
+    #[arg(long)]
+    max_decoded_body_size: Option<usize>,
+    /// How connections are served [default: threaded]
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+    /// Address to accept TCP connections on, may be given several times.
+    /// Without any listeners, 127.0.0.1:4221 is used.
+    #[arg(long)]
+    listen: Vec<String>,
+    /// Unix socket to accept connections on as well
+    #[cfg(unix)]
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket file in octal, like 660
+    #[cfg(unix)]
+    #[arg(long, value_parser = parse_mode)]
+    unix_socket_mode: Option<u32>,
+    /// Inherited file descriptor of a listening socket, may be given several
+    /// times. Sockets passed with systemd socket activation are used as well.
+    #[cfg(unix)]
+    #[arg(long)]
+    listen_fd: Vec<i32>,
+    /// Expect a PROXY protocol header at the start of every connection
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// Proxy whose X-Forwarded-For, Forwarded and X-Forwarded-Proto headers
+    /// are trusted, as an IP address, a CIDR range or `unix`. May be given
+    /// several times.
+    #[arg(long)]
+    trusted_proxy: Vec<String>,
+    /// [default: info]
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+    /// PEM certificate chain to serve HTTPS with
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Certificate for a server name requested with SNI, as `<NAME>=<CERT>,<KEY>`
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_sni_cert: Vec<tls::SniCert>,
+    /// PEM bundle of the CAs to verify client certificates with
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+    /// [default: required]
+    #[cfg(feature = "tls")]
+    #[arg(long, value_enum)]
+    tls_client_auth: Option<tls::ClientAuthMode>,
+}
+
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
 }
 
 fn home(w: &mut ResponseWriter, _: &mut Request) {
@@ -62,47 +177,336 @@ fn user_agent(w: &mut ResponseWriter, r: &mut Request) {
     w.set_reason_phrase(ReasonPhrase::OK);
 }
 
-pub fn run() {
-    let args = Args::parse();
+/// Counts up every second, continuing after the `Last-Event-ID` of a
+/// reconnecting client.
+fn ticks(w: &mut ResponseWriter, r: &mut Request) {
+    let start = r
+        .get_headers()
+        .get_last_event_id()
+        .and_then(|id| id.parse::<u64>().ok())
+        .map_or(0, |id| id + 1);
+    let (sender, events) = sse::channel(Duration::from_secs(15));
+    thread::spawn(move || {
+        for tick in start.. {
+            let mut event = Event::new(&tick.to_string());
+            event.set_id(&tick.to_string());
+            event.set_retry(Duration::from_secs(1));
+            if sender.send(&event).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+    w.set_event_stream(events);
+    w.set_reason_phrase(ReasonPhrase::OK);
+}
+
+fn echo_ws(ws: &mut WebSocket, _: &Request) {
+    while let Ok(Some(message)) = ws.recv() {
+        let res = match message {
+            Message::Text(text) => ws.send_text(&text),
+            Message::Binary(data) => ws.send_binary(&data),
+            _ => Ok(()),
+        };
+        if res.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the configuration file, if any, and applies the flags on top.
+fn load_config(args: &Args) -> anyhow::Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let server = &mut config.server;
+    if !args.listen.is_empty() {
+        server.listen = args.listen.clone();
+    }
+    #[cfg(unix)]
+    {
+        if let Some(path) = &args.unix_socket {
+            server.unix_socket = Some(path.clone());
+        }
+        if let Some(mode) = args.unix_socket_mode {
+            server.unix_socket_mode = Some(mode);
+        }
+    }
+    if let Some(backend) = args.backend {
+        server.backend = backend;
+    }
+    if args.proxy_protocol {
+        server.proxy_protocol = true;
+    }
+    if !args.trusted_proxy.is_empty() {
+        server.trusted_proxies = args.trusted_proxy.clone();
+    }
+    if let Some(directory) = &args.error_pages {
+        server.error_pages = Some(directory.clone());
+    }
+
+    if let Some(directory) = &args.directory {
+        config.mounts.retain(|mount| mount.prefix != FILES_PREFIX);
+        config.mounts.push(Mount {
+            prefix: FILES_PREFIX.to_owned(),
+            directory: directory.clone(),
+            writable: true,
+            max_decoded_body_size: config::default_max_decoded_body_size(),
+        });
+    }
+    if let Some(size) = args.max_decoded_body_size {
+        for mount in &mut config.mounts {
+            mount.max_decoded_body_size = size;
+        }
+    }
+
+    if let Some(min_size) = args.compression_min_size {
+        config.compression.min_size = min_size;
+    }
+    if let Some(level) = args.compression_level {
+        config.compression.level = level;
+    }
+    if let Some(level) = args.log_level {
+        config.logging.level = level;
+    }
 
+    #[cfg(feature = "tls")]
+    {
+        let tls = &mut config.tls;
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            tls.cert = Some(cert.clone());
+            tls.key = Some(key.clone());
+        }
+        if !args.tls_sni_cert.is_empty() {
+            tls.sni = args
+                .tls_sni_cert
+                .iter()
+                .map(|sni_cert| config::SniConfig {
+                    name: sni_cert.server_name.clone(),
+                    cert: sni_cert.paths.cert.clone(),
+                    key: sni_cert.paths.key.clone(),
+                })
+                .collect();
+        }
+        if let Some(ca) = &args.tls_client_ca {
+            tls.client_ca = Some(ca.clone());
+        }
+        if let Some(mode) = args.tls_client_auth {
+            tls.client_auth = Some(mode);
+        }
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Builds the routes, including the directories mounted by `config`.
+fn build_router(config: &Config) -> Router<'static> {
     let mut router = Router::new();
     router.add_route(HttpMethod::Get, "/", &home);
-    let echo_handler = gzip_compressor::new(echo);
-    router.add_route(HttpMethod::Get, "/echo/:str", &echo_handler);
+    let echo_handler = gzip_compressor::with_config(echo, config.compression.gzip_config());
+    router.add_owned_route(HttpMethod::Get, "/echo/:str", echo_handler);
     router.add_route(HttpMethod::Get, "/user-agent", &user_agent);
+    router.add_route(HttpMethod::Get, "/ticks", &ticks);
+    router.add_owned_route(HttpMethod::Get, "/ws/echo", WebSocketRoute::new(echo_ws));
 
-    let file_retriever = args
-        .directory
-        .as_deref()
-        .map(|directory| file_server::new_file_retriever(directory));
-    if let Some(file_retriever) = &file_retriever {
-        router.add_route(HttpMethod::Get, "/files/", file_retriever);
-    };
-    let file_writer = args
-        .directory
-        .as_deref()
-        .map(|directory| file_server::new_file_writer(directory));
-    if let Some(file_retriever) = &file_writer {
-        router.add_route(HttpMethod::Post, "/files/", file_retriever);
+    for mount in &config.mounts {
+        let file_retriever = file_server::new_file_retriever(mount.directory.clone());
+        router.add_owned_route(HttpMethod::Get, &mount.prefix, file_retriever);
+        if mount.writable {
+            let file_writer = body_decoder::new(
+                file_server::new_file_writer(mount.directory.clone()),
+                mount.max_decoded_body_size,
+            );
+            router.add_owned_route(HttpMethod::Post, &mount.prefix, file_writer);
+        }
+    }
+    router
+}
+
+#[cfg(feature = "tls")]
+fn tls_config(config: &config::TlsConfig) -> anyhow::Result<Option<Arc<rustls::ServerConfig>>> {
+    let mut resolver = tls::CertResolver::default();
+    if let (Some(cert), Some(key)) = (&config.cert, &config.key) {
+        resolver.set_default(&tls::CertPaths {
+            cert: cert.clone(),
+            key: key.clone(),
+        })?;
+    }
+    for sni in &config.sni {
+        resolver.add_sni_cert(&tls::SniCert {
+            server_name: sni.name.clone(),
+            paths: tls::CertPaths {
+                cert: sni.cert.clone(),
+                key: sni.key.clone(),
+            },
+        })?;
+    }
+    if resolver.is_empty() {
+        return Ok(None);
+    }
+    let client_auth = config.client_ca.as_ref().map(|ca| tls::ClientAuth {
+        ca: ca.clone(),
+        mode: config.client_auth.unwrap_or_default(),
+    });
+    tls::server_config(resolver, client_auth.as_ref()).map(Some)
+}
+
+fn bind_listeners(args: &Args, config: &Config) -> anyhow::Result<Vec<Listener>> {
+    let mut listeners = vec![];
+    for addr in &config.server.listen {
+        let listener =
+            Listener::bind_tcp(addr).with_context(|| format!("cannot listen on {}", addr))?;
+        listeners.push(listener);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.server.unix_socket {
+        let options = listener::UnixSocketOptions {
+            mode: config.server.unix_socket_mode,
+            ..Default::default()
+        };
+        let listener = Listener::bind_unix(path, &options)
+            .with_context(|| format!("cannot listen on {}", path.display()))?;
+        listeners.push(listener);
+    }
+    #[cfg(unix)]
+    {
+        listeners.extend(listener::listen_fds().context("socket activation failed")?);
+        for &fd in &args.listen_fd {
+            // SAFETY: The supervisor passed the descriptor for serving.
+            let listener = unsafe { Listener::from_raw_fd(fd) }
+                .with_context(|| format!("cannot listen on file descriptor {}", fd))?;
+            listeners.push(listener);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = args;
+    if listeners.is_empty() {
+        listeners.push(Listener::bind_tcp(DEFAULT_LISTEN).context("cannot listen")?);
+    }
+    Ok(listeners)
+}
+
+/// Sets up the server described by `config` without serving yet.
+fn build_server(args: &Args, config: &Config) -> anyhow::Result<Server> {
+    let mut listeners = bind_listeners(args, config)?.into_iter();
+    // There is at least the default listener.
+    let mut server = Server::from_listener(listeners.next().unwrap());
+    for listener in listeners {
+        server.add_listener(listener);
+    }
+    server.set_backend(config.server.backend);
+    server.set_proxy_protocol(config.server.proxy_protocol);
+    server.set_trusted_proxies(config.trusted_proxies()?);
+    #[cfg(feature = "tls")]
+    if let Some(tls_config) = tls_config(&config.tls)? {
+        server.set_tls_config(tls_config);
+    }
+    Ok(server)
+}
+
+/// What `config` serves, the part of it that can be reloaded.
+fn build_site(config: &Config) -> Site {
+    let router = build_router(config);
+    let handler: Box<dyn Handler + Send + Sync> = match &config.server.error_pages {
+        Some(directory) => Box::new(access_log::new(error_pages::new(router, directory.clone()))),
+        None => Box::new(access_log::new(router)),
     };
+    Site {
+        handler,
+        limits: config.limits,
+        timeouts: config.timeouts,
+    }
+}
+
+/// Replaces the site with one for the current configuration. A broken
+/// configuration leaves the site as it is. Changes to sections the `running`
+/// server was started with are reported, as they need a restart.
+fn reload(args: &Args, site: &SiteHandle, running: &Config) {
+    match load_config(args) {
+        Ok(config) => {
+            for section in config.restart_sections(running) {
+                tracing::warn!("changes to [{}] need a restart", section);
+            }
+            site.store(build_site(&config));
+            tracing::info!("config reloaded");
+        }
+        Err(err) => tracing::error!("keeping the previous config: {:#}", err),
+    }
+}
+
+/// Reloads on every SIGHUP. Listeners, the backend, TLS, the proxy settings
+/// and logging keep their configuration until the server restarts.
+#[cfg(unix)]
+fn reload_on_sighup(args: Args, site: SiteHandle, running: Config) -> anyhow::Result<()> {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+    let mut signals = Signals::new([SIGHUP]).context("cannot handle SIGHUP")?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            reload(&args, &site, &running);
+        }
+    });
+    Ok(())
+}
+
+pub fn run() {
+    let args = Args::parse();
+    let config = load_config(&args);
+    let default_logging = LoggingConfig::default();
+    init_tracing(
+        config
+            .as_ref()
+            .map_or(&default_logging, |config| &config.logging),
+    );
 
-    let server = Server::new("127.0.0.1:4221");
-    server.run(router);
+    let res = config.and_then(|config| {
+        let server = build_server(&args, &config)?;
+        let site = SiteHandle::new(build_site(&config));
+        // The other backends keep serving the site they started with.
+        #[cfg(unix)]
+        if args.config.is_some() {
+            if config.server.backend == Backend::Threaded {
+                reload_on_sighup(args.clone(), site.clone(), config)?;
+            } else {
+                tracing::warn!("reloading the config is only supported by the threaded backend");
+            }
+        }
+        server.run_site(&site);
+        Ok(())
+    });
+    if let Err(err) = res {
+        tracing::error!("{:#}", err);
+        process::exit(1);
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::thread;
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpStream,
+        sync::Arc,
+        thread,
+    };
 
+    use clap::Parser;
     use reqwest::{blocking::Client, header};
+    use tempdir::TempDir;
 
-    use crate::router::Router;
+    use crate::{config::LogLevel, content_coding::CompressionLevel, router::Router};
 
-    use super::{echo, home, user_agent, HttpMethod, Server};
+    use super::{
+        build_site, echo, home, load_config, reload, user_agent, Args, Config, HttpMethod, Server,
+        SiteHandle,
+    };
 
     #[test]
     fn test_home() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         thread::spawn(move || {
@@ -118,7 +522,7 @@ pub mod tests {
 
     #[test]
     fn test_echo() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         thread::spawn(move || {
@@ -135,7 +539,7 @@ pub mod tests {
 
     #[test]
     fn test_user_agent() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         thread::spawn(move || {
@@ -154,4 +558,106 @@ pub mod tests {
         let body = resp.text().unwrap();
         assert_eq!(body, "test");
     }
+
+    #[test]
+    fn test_site_rejects_expectation_early() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+        let site = SiteHandle::new(build_site(&Config::default()));
+        thread::spawn(move || server.run_site(&site));
+
+        for (target, status) in [("/missing", "404"), ("/", "405")] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let request = format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+                 Expect: 100-continue\r\nConnection: close\r\n\r\n",
+                target
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+            assert!(
+                resp.starts_with(&format!("HTTP/1.1 {} ", status)),
+                "{}",
+                resp
+            );
+            assert!(!resp.contains("100 Continue"), "{}", resp);
+        }
+    }
+
+    #[test]
+    fn test_load_config() {
+        let dir = TempDir::new("lib").unwrap();
+        let config_path = dir.path().join("server.toml");
+        let toml = format!(
+            r#"
+            [server]
+            listen = ["127.0.0.1:8080"]
+
+            [[mount]]
+            prefix = "/files/"
+            directory = "{0}"
+
+            [[mount]]
+            prefix = "/static/"
+            directory = "{0}"
+
+            [compression]
+            level = "best"
+            min_size = 100
+            "#,
+            dir.path().display()
+        );
+        fs::write(&config_path, toml).unwrap();
+
+        let args = Args::parse_from([
+            "server".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+            "--directory".as_ref(),
+            dir.path().as_os_str(),
+            "--compression-level".as_ref(),
+            "fast".as_ref(),
+            "--log-level".as_ref(),
+            "debug".as_ref(),
+        ]);
+        let config = load_config(&args).unwrap();
+        assert_eq!(config.server.listen, ["127.0.0.1:8080"]);
+        assert_eq!(config.compression.level, CompressionLevel::Fast);
+        assert_eq!(config.compression.min_size, 100);
+        assert_eq!(config.logging.level, LogLevel::Debug);
+        assert_eq!(config.mounts.len(), 2);
+        assert_eq!(config.mounts[0].prefix, "/static/");
+        assert!(!config.mounts[0].writable);
+        assert_eq!(config.mounts[1].prefix, "/files/");
+        assert!(config.mounts[1].writable);
+
+        let args = Args::parse_from(["server", "--trusted-proxy", "10.0.0.0/40"]);
+        let err = format!("{:#}", load_config(&args).unwrap_err());
+        assert!(err.contains("`10.0.0.0/40`"), "{}", err);
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("lib").unwrap();
+        let config_path = dir.path().join("server.toml");
+        fs::write(&config_path, "[limits]\nbody_size = 100\n").unwrap();
+        let args = Args::parse_from([
+            "server".as_ref(),
+            "--config".as_ref(),
+            config_path.as_os_str(),
+        ]);
+        let running = load_config(&args).unwrap();
+        let site = SiteHandle::new(build_site(&running));
+
+        let old_site = site.load();
+        fs::write(&config_path, "[limits]\nbody_size = \"big\"\n").unwrap();
+        reload(&args, &site, &running);
+        assert!(Arc::ptr_eq(&old_site, &site.load()));
+
+        fs::write(&config_path, "[limits]\nbody_size = 200\n").unwrap();
+        reload(&args, &site, &running);
+        assert_eq!(site.load().limits.body_size, 200);
+        assert_eq!(old_site.limits.body_size, 100);
+    }
 }
@@ -0,0 +1,434 @@
+#[cfg(unix)]
+use std::{
+    env, fs,
+    ops::Range,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+};
+use std::{
+    fmt, io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+use crate::stream::{PeerAddr, Stream};
+
+/// How the file of a Unix socket is set up and cleaned up.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixSocketOptions {
+    /// Permissions of the socket file, like `0o660`, instead of the ones
+    /// given by the umask. The socket is created with them, so there's no
+    /// window in which it's more accessible.
+    pub mode: Option<u32>,
+    /// Removes a socket file left behind by a previous run before binding.
+    /// Sockets something still listens on and other kinds of files are
+    /// never removed.
+    pub remove_stale: bool,
+    /// Removes the socket file when the listener is dropped.
+    pub remove_on_drop: bool,
+}
+
+#[cfg(unix)]
+impl Default for UnixSocketOptions {
+    fn default() -> Self {
+        Self {
+            mode: None,
+            remove_stale: true,
+            remove_on_drop: true,
+        }
+    }
+}
+
+/// The first file descriptor passed with systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    /// `None` for unnamed inherited sockets.
+    path: Option<PathBuf>,
+    remove_on_drop: bool,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let (true, Some(path)) = (self.remove_on_drop, &self.path) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A socket connections are accepted on.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, options: &UnixSocketOptions) -> io::Result<Self> {
+        let path = path.as_ref();
+        if options.remove_stale {
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => remove_stale(path)?,
+                _ => {}
+            }
+        }
+        let listener = match options.mode {
+            Some(mode) => bind_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        Ok(Listener::Unix(UnixSocket {
+            listener,
+            path: Some(path.to_owned()),
+            remove_on_drop: options.remove_on_drop,
+        }))
+    }
+
+    /// Takes over a listening socket inherited from the parent process, like
+    /// a supervisor handing it to a new server before the old one drains.
+    /// Inherited socket files are left to their creator to remove.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        set_cloexec(fd.as_raw_fd())?;
+        let listener = std::net::TcpListener::from(fd);
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(false)?;
+            return Ok(Listener::Tcp(listener));
+        }
+
+        let listener = UnixListener::from(OwnedFd::from(listener));
+        let addr = listener
+            .local_addr()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a TCP or Unix socket"))?;
+        listener.set_nonblocking(false)?;
+        Ok(Listener::Unix(UnixSocket {
+            listener,
+            path: addr.as_pathname().map(Path::to_owned),
+            remove_on_drop: false,
+        }))
+    }
+
+    /// Like [`Listener::from_fd`], for a file descriptor given by number.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else in the process may own `fd`.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        // Dropping an `OwnedFd` of a closed descriptor is undefined.
+        if libc::fcntl(fd, libc::F_GETFD) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Self::from_fd(OwnedFd::from_raw_fd(fd))
+    }
+
+    pub fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((stream.into(), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let (stream, _) = socket.listener.accept()?;
+                Ok((stream.into(), PeerAddr::Unix))
+            }
+        }
+    }
+
+    /// The address of a TCP listener.
+    #[allow(dead_code)]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+/// Takes the listeners passed with systemd socket activation, see
+/// `sd_listen_fds(3)`. The environment variables are removed, so that they
+/// are taken only once and not passed on.
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    activation_fds(listen_pid.as_deref(), listen_fds.as_deref(), process::id())?
+        // SAFETY: The descriptors were passed to this process for serving,
+        // and the environment variables naming them are gone.
+        .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// The descriptors passed to the process `pid`.
+#[cfg(unix)]
+fn activation_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> io::Result<Range<RawFd>> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0..0);
+    };
+    // Meant for another process, like the parent of a forking server.
+    if listen_pid.parse() != Ok(pid) {
+        return Ok(0..0);
+    }
+    let n: RawFd = listen_fds
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid LISTEN_FDS"))?;
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + n)
+}
+
+/// Removes the socket file at `path` unless a server still listens on it.
+#[cfg(unix)]
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "address in use: another server is listening on the socket",
+        )),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Binds with the umask set so that the socket file is created with `mode`.
+/// The umask is process-wide, so files other threads create meanwhile get
+/// it as well, which is why this is only done while binding.
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let mask = !mode as libc::mode_t & 0o777;
+    // SAFETY: umask can't fail; the previous one is restored below.
+    let previous = unsafe { libc::umask(mask) };
+    let result = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    let listener = result?;
+    // for bits the umask doesn't apply to
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Keeps the descriptor from being inherited by child processes, which
+/// Rust does for the descriptors it opens itself.
+#[cfg(unix)]
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: Only changes descriptor flags.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.fmt(f),
+                Err(_) => f.write_str("tcp"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(socket) => match &socket.path {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => f.write_str("unix"),
+            },
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::{Read, Write},
+        net::TcpListener,
+        os::{
+            fd::OwnedFd,
+            unix::{
+                fs::PermissionsExt,
+                net::{UnixListener, UnixStream},
+            },
+        },
+        thread,
+    };
+
+    use tempdir::TempDir;
+
+    use crate::{
+        request::Request, response_writer::ResponseWriter, server::Server,
+        status_code_registry::ReasonPhrase,
+    };
+
+    use super::{activation_fds, Listener, UnixSocketOptions};
+
+    fn get(stream: &mut (impl Read + Write), target: &str) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", target);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("server.sock");
+        // a stale socket of a previous run
+        drop(UnixListener::bind(&path).unwrap());
+
+        let options = UnixSocketOptions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let listener = Listener::bind_unix(&path, &options).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let server = Server::from_listener(listener);
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, _: &mut Request| {
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let resp = get(&mut stream, "/");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_remove_on_drop() {
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("server.sock");
+        let listener = Listener::bind_unix(&path, &UnixSocketOptions::default()).unwrap();
+        assert!(path.exists());
+        drop(listener);
+        assert!(!path.exists());
+
+        // other files aren't replaced
+        fs::write(&path, "data").unwrap();
+        assert!(Listener::bind_unix(&path, &UnixSocketOptions::default()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+    }
+
+    #[test]
+    fn test_live_socket_is_kept() {
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("server.sock");
+        let _live = Listener::bind_unix(&path, &UnixSocketOptions::default()).unwrap();
+
+        let err = Listener::bind_unix(&path, &UnixSocketOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(UnixStream::connect(&path).is_ok());
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("admin.sock");
+
+        let mut server = Server::from_listener(Listener::bind_tcp("localhost:0").unwrap());
+        let tcp_addr = server.local_addr();
+        let second = Listener::bind_tcp("localhost:0").unwrap();
+        let second_addr = second.local_addr().unwrap();
+        let second = server.add_listener(second);
+        let admin =
+            server.add_listener(Listener::bind_unix(&path, &UnixSocketOptions::default()).unwrap());
+        assert_eq!((second, admin), (1, 2));
+
+        thread::spawn(move || {
+            let public = |w: &mut ResponseWriter, _: &mut Request| {
+                w.set_body_str("public");
+                w.set_reason_phrase(ReasonPhrase::OK);
+            };
+            let admin = |w: &mut ResponseWriter, _: &mut Request| {
+                w.set_body_str("admin");
+                w.set_reason_phrase(ReasonPhrase::OK);
+            };
+            server.run_each(&[&public, &public, &admin]);
+        });
+
+        for addr in [tcp_addr, second_addr] {
+            let body = reqwest::blocking::get(format!("http://{}", addr))
+                .unwrap()
+                .text()
+                .unwrap();
+            assert_eq!(body, "public");
+        }
+        let mut stream = UnixStream::connect(&path).unwrap();
+        assert!(get(&mut stream, "/").ends_with("\r\n\r\nadmin"));
+    }
+
+    #[test]
+    fn test_from_fd() {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(listener)).unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("inherited.sock");
+        let unix_listener = UnixListener::bind(&path).unwrap();
+        let unix_listener = Listener::from_fd(OwnedFd::from(unix_listener)).unwrap();
+        assert_eq!(
+            unix_listener.to_string(),
+            format!("unix:{}", path.display())
+        );
+
+        let mut server = Server::from_listener(listener);
+        server.add_listener(unix_listener);
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, _: &mut Request| {
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+        let resp = reqwest::blocking::get(format!("http://{}", addr)).unwrap();
+        assert_eq!(resp.status(), 200);
+        let mut stream = UnixStream::connect(&path).unwrap();
+        assert!(get(&mut stream, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let file = File::open("Cargo.toml").unwrap();
+        assert!(Listener::from_fd(OwnedFd::from(file)).is_err());
+    }
+
+    #[test]
+    fn test_inherited_socket_file_is_kept() {
+        let dir = TempDir::new("listener").unwrap();
+        let path = dir.path().join("inherited.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        drop(Listener::from_fd(OwnedFd::from(listener)).unwrap());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_activation_fds() {
+        assert_eq!(activation_fds(None, None, 7).unwrap(), 0..0);
+        assert_eq!(activation_fds(Some("7"), Some("2"), 7).unwrap(), 3..5);
+        // passed to another process
+        assert_eq!(activation_fds(Some("8"), Some("2"), 7).unwrap(), 0..0);
+        assert!(activation_fds(Some("7"), Some("two"), 7).is_err());
+    }
+}
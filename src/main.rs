@@ -1,5 +1,3 @@
-use codecrafters_http_server;
-
 fn main() {
     codecrafters_http_server::run();
 }
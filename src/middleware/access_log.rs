@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::{
+    request::Request, response_writer::ResponseWriter, server::Handler,
+    status_code_registry::ReasonPhrase,
+};
+
+/// Logs a line for every request the wrapped handler handles.
+pub struct AccessLog<H>(H);
+
+/// Wraps `handler` and logs a line for every request it handles.
+pub fn new<H: Handler>(handler: H) -> AccessLog<H> {
+    AccessLog(handler)
+}
+
+impl<H: Handler> Handler for AccessLog<H> {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        let start = Instant::now();
+        self.0.handle(w, r);
+        info!(target: "access", "{}", entry(w, r, start.elapsed()));
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.0.check_request(r)
+    }
+}
+
+/// Like the Common Log Format: the client, its certificate's subject, the
+/// request line, the status and the body size, followed by the connection
+/// and the time taken. Unknown values are written as `-`.
+fn entry(w: &ResponseWriter, r: &Request, elapsed: Duration) -> String {
+    let connection = r.get_connection();
+    let client = r
+        .get_client_addr()
+        .or_else(|| connection.peer_addr.and_then(|addr| addr.ip()))
+        .map_or("-".to_owned(), |addr| addr.to_string());
+    let user = r
+        .get_client_identity()
+        .map_or("-", |identity| identity.subject.as_str());
+    let status = w
+        .get_status_code()
+        .map_or("-".to_owned(), |status| status.to_string());
+    let size = w
+        .get_body_len()
+        .map_or("-".to_owned(), |len| len.to_string());
+    let peer = connection
+        .peer_addr
+        .map_or("-".to_owned(), |addr| addr.to_string());
+    format!(
+        "{} \"{}\" \"{} {} {}\" {} {} {} conn={}/{} peer={} {}ms",
+        client,
+        user,
+        r.get_http_method(),
+        r.get_request_target(),
+        r.get_http_version().as_str(),
+        status,
+        size,
+        r.get_scheme(),
+        connection.id,
+        connection.request_index,
+        peer,
+        elapsed.as_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        headers::Headers,
+        request::{Connection, HttpVersion, Request, RequestLine, Scheme},
+        response_writer::ResponseWriter,
+        status_code_registry::ReasonPhrase,
+        stream::PeerAddr,
+    };
+
+    use super::entry;
+
+    #[test]
+    fn test_entry() {
+        let request_line = RequestLine::new("GET", "/index.html", HttpVersion::Http11);
+        let mut r = Request::new(request_line, None, Headers::new_empty(), None);
+        let mut w = ResponseWriter::new_empty();
+        assert_eq!(
+            entry(&w, &r, Duration::from_millis(3)),
+            "- \"-\" \"GET /index.html HTTP/1.1\" - 0 http conn=0/0 peer=- 3ms"
+        );
+
+        r.set_connection(Connection {
+            id: 7,
+            peer_addr: Some(PeerAddr::Tcp("10.0.0.1:50000".parse().unwrap())),
+            local_addr: None,
+            request_index: 2,
+        });
+        w.set_reason_phrase(ReasonPhrase::OK);
+        w.set_body_str("hello");
+        assert_eq!(
+            entry(&w, &r, Duration::ZERO),
+            "10.0.0.1 \"-\" \"GET /index.html HTTP/1.1\" 200 5 http conn=7/2 peer=10.0.0.1:50000 0ms"
+        );
+
+        // behind a proxy
+        r.set_client_addr("192.0.2.1".parse().unwrap());
+        r.set_scheme(Scheme::Https);
+        assert!(entry(&w, &r, Duration::ZERO).starts_with("192.0.2.1 \"-\""));
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::Read;
+
+use tracing::{debug, error};
+
+use crate::{
+    content_coding::ContentCoding, request::Request, response_writer::ResponseWriter,
+    server::Handler, status_code_registry::ReasonPhrase,
+};
+
+/// Decodes request bodies before the wrapped handler sees them.
+pub struct BodyDecoder<H> {
+    handler: H,
+    max_size: usize,
+}
+
+/// Wraps `handler` and decodes the request body according to the request's
+/// `Content-Encoding`. Bodies that decode to more than `max_size` bytes are
+/// rejected with 413.
+pub fn new<H: Handler>(handler: H, max_size: usize) -> BodyDecoder<H> {
+    BodyDecoder { handler, max_size }
+}
+
+impl<H: Handler> Handler for BodyDecoder<H> {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        if let Err(reason_phrase) = decode_body(r, self.max_size) {
+            w.set_reason_phrase(reason_phrase);
+            if reason_phrase == ReasonPhrase::UnsupportedMediaType {
+                w.add_accept_encoding_header("br, zstd, gzip, deflate");
+            }
+            return;
+        }
+        self.handler.handle(w, r);
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.handler.check_request(r)
+    }
+}
+
+fn decode_body(r: &mut Request, max_size: usize) -> Result<(), ReasonPhrase> {
+    let Some(codings) = r.get_headers().get_content_encoding() else {
+        return Ok(());
+    };
+    let codings = codings
+        .filter(|coding| !coding.is_empty())
+        .map(ContentCoding::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| ReasonPhrase::UnsupportedMediaType)?;
+    let Some(mut decoder) = r.take_body() else {
+        return Ok(());
+    };
+
+    // Codings are listed in the order in which they were applied.
+    for coding in codings.iter().rev() {
+        decoder = coding.decoder(decoder).map_err(|err| {
+            error!(?err);
+            ReasonPhrase::InternalServerError
+        })?;
+    }
+
+    let mut decoded = vec![];
+    let n = decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|err| {
+            debug!(?err);
+            ReasonPhrase::BadRequest
+        })?;
+    if n > max_size {
+        return Err(ReasonPhrase::ContentTooLarge);
+    }
+
+    r.set_body(decoded);
+    // Both described the encoded body.
+    r.remove_header("content-encoding");
+    r.remove_header("content-length");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use reqwest::{blocking::Client, header};
+
+    use crate::{
+        content_coding::{CompressionLevel, ContentCoding},
+        request::Request,
+        response_writer::ResponseWriter,
+        server::Server,
+        status_code_registry::ReasonPhrase,
+    };
+
+    fn start_server(max_size: usize) -> String {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            let echo_body = |w: &mut ResponseWriter, r: &mut Request| {
+                assert!(r.get_headers().get_content_encoding().is_none());
+                assert!(r.get_headers().get_content_length().unwrap().is_none());
+                let body = r.read_body().unwrap().unwrap().to_vec();
+                w.set_body(body, "text/plain");
+                w.set_reason_phrase(ReasonPhrase::OK);
+            };
+            server.run(super::new(echo_body, max_size));
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn post(url: &str, content_encoding: &str, body: Vec<u8>) -> reqwest::blocking::Response {
+        Client::new()
+            .post(url)
+            .header(header::CONTENT_ENCODING, content_encoding)
+            .body(body)
+            .send()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let url = start_server(1024);
+        let level = CompressionLevel::default();
+
+        let body = ContentCoding::Gzip.encode(b"Hello World!", level).unwrap();
+        let resp = post(&url, "gzip", body);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().unwrap(), "Hello World!");
+
+        let body = ContentCoding::Deflate
+            .encode(b"Hello World!", level)
+            .unwrap();
+        let body = ContentCoding::Gzip.encode(&body, level).unwrap();
+        let resp = post(&url, "deflate, gzip", body);
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().unwrap(), "Hello World!");
+    }
+
+    #[test]
+    fn test_decoded_too_large() {
+        let url = start_server(1024);
+        let body = ContentCoding::Gzip
+            .encode(&[0; 1025], CompressionLevel::default())
+            .unwrap();
+        let resp = post(&url, "gzip", body);
+        assert_eq!(resp.status(), 413);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let url = start_server(1024);
+        let resp = post(&url, "gzip", b"Hello World!".to_vec());
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let url = start_server(1024);
+        let resp = post(&url, "compress", b"Hello World!".to_vec());
+        assert_eq!(resp.status(), 415);
+        assert_eq!(
+            resp.headers().get("accept-encoding").unwrap(),
+            "br, zstd, gzip, deflate"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use tracing::error;
+
+use crate::{
+    request::Request, response_writer::ResponseWriter, server::Handler,
+    status_code_registry::ReasonPhrase,
+};
+
+/// Fills the body of the wrapped handler's error responses.
+pub struct ErrorPages<H> {
+    handler: H,
+    directory: PathBuf,
+}
+
+/// Wraps `handler` and fills the body of error responses (4xx/5xx) that have
+/// none with the template `<directory>/<status code>.html`, if it exists.
+pub fn new<H: Handler>(handler: H, directory: impl Into<PathBuf>) -> ErrorPages<H> {
+    ErrorPages {
+        handler,
+        directory: directory.into(),
+    }
+}
+
+impl<H: Handler> Handler for ErrorPages<H> {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        self.handler.handle(w, r);
+
+        let Some(status_code) = w.get_status_code() else {
+            return;
+        };
+        if status_code < 400 || w.has_body() {
+            return;
+        }
+
+        let path = self.directory.join(format!("{}.html", status_code));
+        match fs::read(&path) {
+            Ok(contents) => w.set_body(contents, "text/html"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => error!("{:?}: {}", path, err),
+        }
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.handler.check_request(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use tempdir::TempDir;
+
+    use crate::{
+        request::Request, response_writer::ResponseWriter, server::Server,
+        status_code_registry::ReasonPhrase,
+    };
+
+    #[test]
+    fn test_error_pages() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        let tmp_dir = TempDir::new("").unwrap();
+        fs::write(tmp_dir.path().join("404.html"), "<h1>Not Found</h1>").unwrap();
+        let directory = tmp_dir.path().to_owned();
+
+        thread::spawn(move || {
+            let handler = |w: &mut ResponseWriter, r: &mut Request| match r.get_request_target() {
+                "/missing" => w.set_reason_phrase(ReasonPhrase::NotFound),
+                _ => w.set_reason_phrase(ReasonPhrase::InternalServerError),
+            };
+            server.run(super::new(handler, directory));
+        });
+
+        let url = format!("http://{}/missing", addr);
+        let resp = reqwest::blocking::get(url).unwrap();
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.text().unwrap(), "<h1>Not Found</h1>");
+
+        // No template for 500, the response is sent without a body.
+        let url = format!("http://{}/broken", addr);
+        let resp = reqwest::blocking::get(url).unwrap();
+        assert_eq!(resp.status(), 500);
+        assert_eq!(resp.headers().get("content-length").unwrap(), "0");
+        assert_eq!(resp.text().unwrap(), "");
+    }
+}
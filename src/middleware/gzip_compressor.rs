@@ -1,37 +1,274 @@
-use std::io::Read;
-
-use flate2::{bufread::GzEncoder, Compression};
 use tracing::error;
 
-use crate::{request::Request, response_writer::ResponseWriter, server::Handler};
+use crate::{
+    content_coding::{self, CompressionLevel, ContentCoding},
+    request::Request,
+    response_writer::{Body, ResponseWriter},
+    server::Handler,
+    status_code_registry::ReasonPhrase,
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Buffered bodies smaller than this are sent uncompressed.
+    /// Streamed bodies are always compressed.
+    pub min_size: usize,
+    pub level: CompressionLevel,
+    /// Media types worth compressing. An entry like `text/*` matches
+    /// all subtypes.
+    pub mime_types: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            min_size: 1024,
+            level: CompressionLevel::default(),
+            mime_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+impl Config {
+    fn is_compressible(&self, content_type: &str) -> bool {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        self.mime_types
+            .iter()
+            .any(|mime_type| match mime_type.strip_suffix("*") {
+                Some(prefix) => essence.starts_with(prefix),
+                None => essence == *mime_type,
+            })
+    }
+}
+
+/// Compresses the responses of the wrapped handler.
+pub struct GzipCompressor<H> {
+    handler: H,
+    config: Config,
+}
+
+#[allow(dead_code)]
+pub fn new<H: Handler>(handler: H) -> GzipCompressor<H> {
+    with_config(handler, Config::default())
+}
+
+pub fn with_config<H: Handler>(handler: H, config: Config) -> GzipCompressor<H> {
+    GzipCompressor { handler, config }
+}
 
-pub fn new(handler: impl Handler) -> impl Handler {
-    move |w: &mut ResponseWriter, r: &mut Request| {
-        handler.handle(w, r);
+impl<H: Handler> Handler for GzipCompressor<H> {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        self.handler.handle(w, r);
+        w.add_vary_header("Accept-Encoding");
 
-        let body = w.get_body();
-        if body.len() == 0 {
+        // Compressors buffer their output, which would hold events back.
+        if !w.has_body() || w.has_header("content-encoding") || w.is_event_stream() {
             return;
         }
 
+        if w.get_body_len()
+            .is_some_and(|len| len < self.config.min_size)
+        {
+            return;
+        }
         let Some(content_type) = w.get_content_type_header() else {
-            error!("Content-Type is supposed to be present");
             return;
         };
+        if !self.config.is_compressible(content_type) {
+            return;
+        }
         let content_type = String::from(content_type);
 
-        if let Some(mut encodings) = r.get_headers().get_accept_encoding() {
-            if encodings.any(|encoding| encoding == "gzip") {
-                let mut gz = GzEncoder::new(body, Compression::fast());
-                let mut buffer = vec![];
-                if let Err(err) = gz.read_to_end(&mut buffer) {
-                    eprintln!("{}", err);
+        let coding = match content_coding::negotiate(r.get_headers().get_accept_encoding()) {
+            Ok(ContentCoding::Identity) => return,
+            Ok(coding) => coding,
+            Err(_) => {
+                *w = ResponseWriter::new_empty();
+                w.set_reason_phrase(ReasonPhrase::NotAcceptable);
+                w.add_vary_header("Accept-Encoding");
+                return;
+            }
+        };
+
+        match w.take_body() {
+            Body::Bytes(bytes) => match coding.encode(&bytes, self.config.level) {
+                Ok(encoded) => w.set_body(encoded, &content_type),
+                Err(err) => {
+                    error!("{}", err);
+                    w.set_body(bytes, &content_type);
                     return;
                 }
+            },
+            Body::Reader(reader) => match coding.encoder(reader, self.config.level) {
+                Ok(encoder) => w.set_body_reader(encoder, &content_type),
+                Err(err) => {
+                    error!("{}", err);
+                    *w = ResponseWriter::new_empty();
+                    w.set_reason_phrase(ReasonPhrase::InternalServerError);
+                    return;
+                }
+            },
+        }
+        w.add_content_encoding_header(coding.as_str());
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.handler.check_request(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read},
+        thread,
+    };
 
-                w.set_body(buffer, &content_type);
-                w.add_content_encoding_header("gzip");
+    use flate2::read::GzDecoder;
+    use reqwest::{
+        blocking::{Client, Response},
+        header,
+    };
+
+    use crate::{
+        request::Request, response_writer::ResponseWriter, server::Server,
+        status_code_registry::ReasonPhrase,
+    };
+
+    use super::Config;
+
+    const LARGE: usize = 4 * 1024;
+
+    fn handler(w: &mut ResponseWriter, r: &mut Request) {
+        w.set_reason_phrase(ReasonPhrase::OK);
+        match r.get_request_target() {
+            "/small" => w.set_body_str("abc"),
+            "/large" => w.set_body_str(&"a".repeat(LARGE)),
+            "/binary" => w.set_body(vec![0; LARGE], "application/octet-stream"),
+            "/encoded" => {
+                w.set_body(vec![0; LARGE], "text/plain");
+                w.add_content_encoding_header("br");
             }
+            "/stream" => w.set_body_reader(Cursor::new("a".repeat(LARGE)), "text/plain"),
+            _ => w.set_reason_phrase(ReasonPhrase::NotFound),
+        }
+    }
+
+    fn start_server(config: Config) -> String {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(super::with_config(handler, config));
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn get(url: &str, accept_encoding: &str) -> Response {
+        Client::new()
+            .get(url)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .send()
+            .unwrap()
+    }
+
+    fn gunzip(resp: Response) -> String {
+        let mut body = String::new();
+        GzDecoder::new(resp).read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_gzip() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/large", url), "deflate;q=0.5, gzip");
+
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+        assert_eq!(gunzip(resp), "a".repeat(LARGE));
+    }
+
+    #[test]
+    fn test_identity() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/large", url), "gzip;q=0");
+
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+        assert_eq!(resp.text().unwrap(), "a".repeat(LARGE));
+    }
+
+    #[test]
+    fn test_not_acceptable() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/large", url), "identity;q=0, unknown");
+
+        assert_eq!(resp.status(), 406);
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+
+        // bodies that are never compressed are sent as they are
+        for path in ["/small", "/binary"] {
+            let resp = get(&format!("{}{}", url, path), "identity;q=0, unknown");
+            assert_eq!(resp.status(), 200, "{}", path);
+            assert!(resp.headers().get("content-encoding").is_none());
         }
     }
+
+    #[test]
+    fn test_min_size() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/small", url), "gzip");
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.text().unwrap(), "abc");
+
+        let url = start_server(Config {
+            min_size: 0,
+            ..Default::default()
+        });
+        let resp = get(&format!("{}/small", url), "gzip");
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        assert_eq!(gunzip(resp), "abc");
+    }
+
+    #[test]
+    fn test_not_compressible() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/binary", url), "gzip");
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.bytes().unwrap().len(), LARGE);
+    }
+
+    #[test]
+    fn test_already_encoded() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/encoded", url), "gzip");
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "br");
+        assert_eq!(resp.bytes().unwrap().len(), LARGE);
+    }
+
+    #[test]
+    fn test_stream() {
+        let url = start_server(Config::default());
+        let resp = get(&format!("{}/stream", url), "gzip");
+        assert_eq!(resp.headers().get("transfer-encoding").unwrap(), "chunked");
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        assert_eq!(gunzip(resp), "a".repeat(LARGE));
+    }
 }
@@ -1 +1,4 @@
+pub mod access_log;
+pub mod body_decoder;
+pub mod error_pages;
 pub mod gzip_compressor;
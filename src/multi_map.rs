@@ -117,12 +117,20 @@ where
         self.insert(key, Value::from(vector));
     }
 
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<Value<T>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.0.remove(key)
+    }
+
     pub fn get<Q>(&self, key: &Q) -> Option<&Value<T>>
     where
         K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        self.0.get(&key)
+        self.0.get(key)
     }
 
     pub fn get_scalar<Q>(&self, key: &Q) -> anyhow::Result<Option<&T>>
@@ -176,6 +184,6 @@ impl<'a, K, V> IntoIterator for &'a MultiMap<K, V> {
     type Item = <&'a HashMap<K, Value<V>> as IntoIterator>::Item;
     type IntoIter = <&'a HashMap<K, Value<V>> as IntoIterator>::IntoIter;
     fn into_iter(self) -> Self::IntoIter {
-        (&self.0).into_iter()
+        self.0.iter()
     }
 }
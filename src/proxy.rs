@@ -0,0 +1,473 @@
+//! Client addresses of connections relayed by proxies, from the PROXY
+//! protocol (versions 1 and 2) or the `Forwarded`, `X-Forwarded-For` and
+//! `X-Forwarded-Proto` headers of trusted proxies.
+
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+use crate::{headers::Headers, request::Scheme};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the line break.
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Error, Debug)]
+#[error("invalid PROXY protocol header")]
+pub struct InvalidProxyHeader;
+
+/// Reads the PROXY protocol header at the start of a connection, and nothing
+/// beyond it. Returns the address of the client, or `None` if the proxy
+/// didn't relay one, like for its own health checks.
+pub fn read_header(mut reader: impl Read) -> anyhow::Result<Option<SocketAddr>> {
+    let mut start = [0; 12];
+    reader.read_exact(&mut start)?;
+    if &start == V2_SIGNATURE {
+        return read_v2(reader);
+    }
+    if !start.starts_with(b"PROXY ") {
+        Err(InvalidProxyHeader)?
+    }
+
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            Err(InvalidProxyHeader)?
+        }
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| InvalidProxyHeader)?;
+    parse_v1(line).ok_or_else(|| InvalidProxyHeader.into())
+}
+
+/// Parses `PROXY <TCP4|TCP6|UNKNOWN> <src> <dst> <src port> <dst port>`.
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let mut fields = line.split(' ').skip(1);
+    let is_v4 = match fields.next()? {
+        "TCP4" => true,
+        "TCP6" => false,
+        // The rest of the line is to be ignored.
+        "UNKNOWN" => return Some(None),
+        _ => return None,
+    };
+    let ip: IpAddr = fields.next()?.parse().ok()?;
+    let _destination: IpAddr = fields.next()?.parse().ok()?;
+    let port = parse_port(fields.next()?)?;
+    parse_port(fields.next()?)?;
+    if fields.next().is_some() || ip.is_ipv4() != is_v4 {
+        return None;
+    }
+    Some(Some(SocketAddr::new(ip, port)))
+}
+
+/// Ports are decimal without leading zeros.
+fn parse_port(port: &str) -> Option<u16> {
+    if (port.starts_with('0') && port != "0") || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    port.parse().ok()
+}
+
+fn read_v2(mut reader: impl Read) -> anyhow::Result<Option<SocketAddr>> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let [version_command, family, len @ ..] = header;
+    let mut addresses = vec![0; u16::from_be_bytes(len).into()];
+    reader.read_exact(&mut addresses)?;
+
+    if version_command >> 4 != 2 {
+        Err(InvalidProxyHeader)?
+    }
+    match version_command & 0xf {
+        // LOCAL: the proxy's own connection
+        0 => return Ok(None),
+        1 => {}
+        _ => Err(InvalidProxyHeader)?,
+    }
+
+    // Type-length-value fields after the addresses are ignored.
+    let addr = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        1 | 2 => return Err(InvalidProxyHeader.into()),
+        // unspecified or Unix sockets
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+#[derive(Error, Debug)]
+#[error("invalid trusted proxy, expected an IP address, a CIDR range or `unix`")]
+pub struct InvalidTrustedProxy;
+
+/// Proxies whose forwarding headers are believed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrustedProxy {
+    /// Addresses in a CIDR range like `10.0.0.0/8`.
+    Range { addr: IpAddr, prefix_len: u8 },
+    /// Peers of Unix sockets.
+    Unix,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let TrustedProxy::Range { addr, prefix_len } = *self else {
+            return false;
+        };
+        let mask = |bits: u32| match u32::from(prefix_len) {
+            0 => 0,
+            len => u128::MAX << (bits - len),
+        };
+        match (addr, ip.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                (u32::from(addr) ^ u32::from(ip)) as u128 & mask(32) == 0
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                (u128::from(addr) ^ u128::from(ip)) & mask(128) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = InvalidTrustedProxy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| InvalidTrustedProxy)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| InvalidTrustedProxy)?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidTrustedProxy);
+        }
+        Ok(TrustedProxy::Range { addr, prefix_len })
+    }
+}
+
+/// What trusted proxies reported about the client.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Forwarded {
+    pub client_addr: Option<IpAddr>,
+    pub scheme: Option<Scheme>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<TrustedProxy>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<TrustedProxy>) -> Self {
+        Self(proxies)
+    }
+
+    /// `None` stands for the peer of a Unix socket.
+    fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        self.0.iter().any(|proxy| match peer {
+            Some(ip) => proxy.contains(ip),
+            None => *proxy == TrustedProxy::Unix,
+        })
+    }
+
+    /// Follows the proxies in front of `peer` back to the first address that
+    /// isn't trusted, which is the client. `Forwarded` takes precedence over
+    /// `X-Forwarded-For`. The scheme is the one reported along with the
+    /// client, or else the last `X-Forwarded-Proto` value.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &Headers) -> Forwarded {
+        let mut forwarded = Forwarded::default();
+        if !self.trusts(peer) {
+            return forwarded;
+        }
+
+        let hops: Vec<(Option<IpAddr>, Option<Scheme>)> = match headers.get_forwarded() {
+            Some(elements) => elements.map(parse_forwarded_element).collect(),
+            None => {
+                let scheme = headers
+                    .get_x_forwarded_proto()
+                    .and_then(|it| it.last())
+                    .and_then(parse_scheme);
+                forwarded.scheme = scheme;
+                let Some(hops) = headers.get_x_forwarded_for() else {
+                    return forwarded;
+                };
+                hops.map(|node| (parse_node(node), None)).collect()
+            }
+        };
+        for (ip, scheme) in hops.into_iter().rev() {
+            // Obfuscated and unknown clients end the chain.
+            let Some(ip) = ip else {
+                break;
+            };
+            forwarded.client_addr = Some(ip);
+            forwarded.scheme = scheme.or(forwarded.scheme);
+            if !self.trusts(Some(ip)) {
+                break;
+            }
+        }
+        forwarded
+    }
+}
+
+/// Returns the `for` and `proto` parameters of an element of `Forwarded`
+/// (RFC 7239, section 4).
+fn parse_forwarded_element(element: &str) -> (Option<IpAddr>, Option<Scheme>) {
+    let (mut ip, mut scheme) = (None, None);
+    for pair in element.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => ip = parse_node(value),
+            "proto" => scheme = parse_scheme(value),
+            _ => {}
+        }
+    }
+    (ip, scheme)
+}
+
+/// Parses an address that may come with a port, IPv6 addresses in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+fn parse_scheme(scheme: &str) -> Option<Scheme> {
+    match scheme.trim().to_ascii_lowercase().as_str() {
+        "http" => Some(Scheme::Http),
+        "https" => Some(Scheme::Https),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+    };
+
+    use crate::{
+        headers::Headers, request::Request, request::Scheme, response_writer::ResponseWriter,
+        server::Server, status_code_registry::ReasonPhrase,
+    };
+
+    use super::{read_header, Forwarded, TrustedProxies, TrustedProxy};
+
+    #[test]
+    fn test_read_v1() {
+        let mut input = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        let addr = read_header(&mut input).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(input, b"GET /");
+
+        let mut input = &b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 80\r\n"[..];
+        let addr = read_header(&mut input).unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        let addr = read_header(&b"PROXY UNKNOWN ignored\r\n"[..]).unwrap();
+        assert_eq!(addr, None);
+
+        for invalid in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 01 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            &[b"PROXY UNKNOWN ".as_slice(), &[b'x'; 100], b"\r\n"].concat(),
+        ] {
+            read_header(invalid).unwrap_err();
+        }
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn test_read_v2() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        // a type-length-value field
+        addresses.extend([0x04, 0, 1, 0]);
+        let mut input = v2_header(1, 0x11, &addresses);
+        input.extend(b"GET /");
+        let mut input = &input[..];
+        let addr = read_header(&mut input).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(input, b"GET /");
+
+        let mut addresses = [0; 36];
+        addresses[..16].copy_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        addresses[32..34].copy_from_slice(&4711u16.to_be_bytes());
+        let addr = read_header(&v2_header(1, 0x21, &addresses)[..]).unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        // LOCAL, e.g. health checks of the proxy
+        assert_eq!(read_header(&v2_header(0, 0, &[])[..]).unwrap(), None);
+
+        read_header(&v2_header(1, 0x11, &[192, 0, 2, 1])[..]).unwrap_err();
+        read_header(&v2_header(2, 0x11, &addresses[..12])[..]).unwrap_err();
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let range: TrustedProxy = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        let range: TrustedProxy = "2001:db8::/32".parse().unwrap();
+        assert!(range.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!range.contains("2001:db9::1".parse().unwrap()));
+        let single: TrustedProxy = "192.0.2.1".parse().unwrap();
+        assert!(single.contains("192.0.2.1".parse().unwrap()));
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+        let all: TrustedProxy = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.9".parse().unwrap()));
+        assert_eq!("unix".parse::<TrustedProxy>().unwrap(), TrustedProxy::Unix);
+
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "localhost",
+            "10.0.0.0/",
+        ] {
+            invalid.parse::<TrustedProxy>().unwrap_err();
+        }
+    }
+
+    fn resolve(peer: &str, raw_headers: &str) -> Forwarded {
+        let trusted =
+            TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap(), "unix".parse().unwrap()]);
+        let headers = Headers::parse(raw_headers).unwrap();
+        trusted.resolve(peer.parse().ok(), &headers)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let client = |ip: &str| Some(ip.parse().unwrap());
+
+        let forwarded = resolve(
+            "10.0.0.1",
+            "x-forwarded-for: 198.51.100.7, 203.0.113.5, 10.0.0.2\r\nx-forwarded-proto: https\r\n",
+        );
+        assert_eq!(forwarded.client_addr, client("203.0.113.5"));
+        assert_eq!(forwarded.scheme, Some(Scheme::Https));
+
+        // not from a trusted proxy
+        let forwarded = resolve("192.0.2.1", "x-forwarded-for: 203.0.113.5\r\n");
+        assert_eq!(forwarded, Forwarded::default());
+
+        let forwarded = resolve(
+            "unix",
+            "forwarded: for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.3;proto=http\r\n\
+             x-forwarded-for: 198.51.100.7\r\n",
+        );
+        assert_eq!(forwarded.client_addr, client("2001:db8::17"));
+        assert_eq!(forwarded.scheme, Some(Scheme::Https));
+
+        // obfuscated identifiers end the chain
+        let forwarded = resolve(
+            "10.0.0.1",
+            "forwarded: for=_hidden, For=\"10.0.0.4:80\"\r\n",
+        );
+        assert_eq!(forwarded.client_addr, client("10.0.0.4"));
+        assert_eq!(forwarded.scheme, None);
+    }
+
+    fn client(w: &mut ResponseWriter, r: &mut Request) {
+        let addr = r.get_client_addr().map(|addr| addr.to_string());
+        w.set_body_str(&format!("{} {}", addr.unwrap_or_default(), r.get_scheme()));
+        w.set_reason_phrase(ReasonPhrase::OK);
+    }
+
+    fn start_server(configure: impl FnOnce(&mut Server)) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        configure(&mut server);
+        let addr = server.local_addr();
+        thread::spawn(move || server.run(client));
+        addr
+    }
+
+    fn send(addr: SocketAddr, req: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(req).unwrap();
+        let mut resp = String::new();
+        let _ = stream.read_to_string(&mut resp);
+        resp
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let addr = start_server(|server| server.set_proxy_protocol(true));
+
+        let resp = send(
+            addr,
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.0\r\n\r\n",
+        );
+        assert!(resp.ends_with("\r\n\r\n192.0.2.1 http"), "{}", resp);
+
+        let resp = send(addr, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(resp, "");
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let addr = start_server(|server| {
+            let proxies = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+            server.set_trusted_proxies(TrustedProxies::new(proxies));
+        });
+        let resp = send(
+            addr,
+            b"GET / HTTP/1.0\r\nX-Forwarded-For: 203.0.113.5\r\nX-Forwarded-Proto: https\r\n\r\n",
+        );
+        assert!(resp.ends_with("\r\n\r\n203.0.113.5 https"), "{}", resp);
+
+        let addr = start_server(|_| {});
+        let resp = send(
+            addr,
+            b"GET / HTTP/1.0\r\nX-Forwarded-For: 203.0.113.5\r\n\r\n",
+        );
+        assert!(
+            resp.ends_with(&format!("\r\n\r\n{} http", addr.ip())),
+            "{}",
+            resp
+        );
+    }
+}
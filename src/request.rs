@@ -1,19 +1,67 @@
-use std::io::{ErrorKind, Read};
+use std::{
+    fmt::{self, Debug},
+    io::{self, Cursor, ErrorKind, Read},
+    net::{IpAddr, SocketAddr},
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use strum_macros::Display;
 use thiserror::Error;
 
-use crate::{headers::Headers, slice_ext, stream_reader::StreamReader};
+use crate::{
+    headers::Headers,
+    slice_ext,
+    status_code_registry::ReasonPhrase,
+    stream::PeerAddr,
+    stream_reader::{EndOfFile, StreamReader},
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+            HttpVersion::Http2 => "HTTP/2",
+        }
+    }
+
+    /// Parses `HTTP-version` (RFC 9112, section 2.3). Higher minor versions
+    /// of HTTP/1 are treated as HTTP/1.1.
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let (major, minor) = raw
+            .strip_prefix("HTTP/")
+            .and_then(|version| version.split_once('.'))
+            .ok_or(InvalidRequest)?;
+        let is_digit = |s: &str| s.len() == 1 && s.as_bytes()[0].is_ascii_digit();
+        if !is_digit(major) || !is_digit(minor) {
+            Err(InvalidRequest)?
+        }
+
+        match (major, minor) {
+            ("1", "0") => Ok(HttpVersion::Http10),
+            ("1", _) => Ok(HttpVersion::Http11),
+            _ => Err(UnsupportedVersion)?,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct RequestLine<'a> {
     http_method: &'a str,
     request_target: &'a str,
-    http_version: &'a str,
+    http_version: HttpVersion,
 }
 
 impl<'a> RequestLine<'a> {
-    fn new(http_method: &'a str, request_target: &'a str, http_version: &'a str) -> Self {
+    pub fn new(http_method: &'a str, request_target: &'a str, http_version: HttpVersion) -> Self {
         Self {
             http_method,
             request_target,
@@ -23,10 +71,14 @@ impl<'a> RequestLine<'a> {
 
     pub fn parse(raw: &'a str) -> anyhow::Result<Self> {
         let raw = raw.strip_suffix("\r\n").ok_or(anyhow!("missing crlf"))?;
-        let mut it = raw.split(|c| c == ' ');
+        let mut it = raw.split(' ');
         let http_method = it.next().ok_or(anyhow!("empty http method"))?;
         let request_target = it.next().ok_or(anyhow!("empty request target"))?;
         let http_version = it.next().ok_or(anyhow!("empty http version"))?;
+        if it.next().is_some() {
+            bail!("too many request line elements");
+        }
+        let http_version = HttpVersion::parse(http_version)?;
         Ok(Self::new(http_method, request_target, http_version))
     }
 
@@ -39,18 +91,75 @@ impl<'a> RequestLine<'a> {
         self.request_target
     }
 
-    #[allow(unused)]
-    pub fn http_version(&self) -> &'a str {
+    pub fn http_version(&self) -> HttpVersion {
         self.http_version
     }
 }
 
+pub enum Body<'a> {
+    /// Not read yet, bound to the connection.
+    Stream(Box<dyn Read + Send + 'a>),
+    Buffered(Vec<u8>),
+}
+
+impl Debug for Body<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Stream(_) => f.debug_tuple("Stream").finish(),
+            Body::Buffered(bytes) => f.debug_tuple("Buffered").field(&bytes.len()).finish(),
+        }
+    }
+}
+
+/// A subject alternative name of a client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+/// The verified certificate a client authenticated with (mutual TLS).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// The subject as a distinguished name, e.g. `CN=client, O=example`.
+    pub subject: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+}
+
+/// The scheme a client sent a request with.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+/// The connection a request arrived on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Connection {
+    /// Unique among the connections of the process.
+    pub id: u64,
+    /// The other end of the socket, which may be a proxy.
+    pub peer_addr: Option<PeerAddr>,
+    /// `None` for Unix sockets.
+    pub local_addr: Option<SocketAddr>,
+    /// Position of the request on the connection, starting at 0.
+    pub request_index: usize,
+}
+
 #[derive(Debug)]
 pub struct Request<'a> {
     request_line: RequestLine<'a>,
     param: Option<&'a str>,
     headers: Headers<'a>,
-    body: Option<Vec<u8>>,
+    body: Option<Body<'a>>,
+    client_identity: Option<ClientIdentity>,
+    client_addr: Option<IpAddr>,
+    scheme: Scheme,
+    connection: Connection,
 }
 
 impl<'a> Request<'a> {
@@ -58,13 +167,17 @@ impl<'a> Request<'a> {
         request_line: RequestLine<'a>,
         param: Option<&'a str>,
         headers: Headers<'a>,
-        body: Option<Vec<u8>>,
+        body: Option<Box<dyn Read + Send + 'a>>,
     ) -> Self {
         Self {
             request_line,
             param,
             headers,
-            body,
+            body: body.map(Body::Stream),
+            client_identity: None,
+            client_addr: None,
+            scheme: Scheme::default(),
+            connection: Connection::default(),
         }
     }
 
@@ -76,25 +189,98 @@ impl<'a> Request<'a> {
         self.request_line.request_target()
     }
 
-    #[allow(unused)]
-    pub fn get_http_version(&self) -> &'a str {
+    pub fn get_http_version(&self) -> HttpVersion {
         self.request_line.http_version()
     }
 
     pub fn get_param(&self) -> Option<&str> {
-        self.param.as_deref()
+        self.param
     }
 
     pub fn set_param(&mut self, param: &'a str) {
         self.param = Some(param);
     }
 
-    pub fn get_headers(&self) -> &Headers {
+    pub fn get_headers(&self) -> &Headers<'_> {
         &self.headers
     }
 
-    pub fn get_body(&self) -> Option<&[u8]> {
-        self.body.as_deref()
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.remove(key);
+    }
+
+    /// The client certificate, if the client authenticated with one.
+    pub fn get_client_identity(&self) -> Option<&ClientIdentity> {
+        self.client_identity.as_ref()
+    }
+
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub fn set_client_identity(&mut self, client_identity: ClientIdentity) {
+        self.client_identity = Some(client_identity);
+    }
+
+    /// The address of the client, which may be behind proxies. `None` for
+    /// peers of Unix sockets that didn't tell.
+    pub fn get_client_addr(&self) -> Option<IpAddr> {
+        self.client_addr
+    }
+
+    pub fn set_client_addr(&mut self, client_addr: IpAddr) {
+        self.client_addr = Some(client_addr);
+    }
+
+    /// The scheme the client used, which may differ from this server's
+    /// behind a proxy terminating TLS.
+    pub fn get_scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    pub fn set_scheme(&mut self, scheme: Scheme) {
+        self.scheme = scheme;
+    }
+
+    pub fn get_connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn set_connection(&mut self, connection: Connection) {
+        self.connection = connection;
+    }
+
+    #[allow(dead_code)]
+    pub fn has_body(&self) -> bool {
+        self.body.is_some()
+    }
+
+    /// Takes the body as a reader. Unless it has been buffered, reading
+    /// consumes it directly from the connection.
+    pub fn take_body(&mut self) -> Option<Box<dyn Read + Send + 'a>> {
+        match self.body.take()? {
+            Body::Stream(reader) => Some(reader),
+            Body::Buffered(bytes) => Some(Box::new(Cursor::new(bytes))),
+        }
+    }
+
+    /// Reads the whole body into memory, if not done already, and returns it.
+    #[allow(dead_code)]
+    pub fn read_body(&mut self) -> io::Result<Option<&[u8]>> {
+        if let Some(Body::Stream(reader)) = &mut self.body {
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes)?;
+            self.body = Some(Body::Buffered(bytes));
+        }
+        match &self.body {
+            Some(Body::Buffered(bytes)) => Ok(Some(bytes)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Some(Body::Buffered(body));
+    }
+
+    pub fn set_body_reader(&mut self, body: Box<dyn Read + Send + 'a>) {
+        self.body = Some(Body::Stream(body));
     }
 }
 
@@ -113,78 +299,679 @@ pub fn make_keys_lowercase(bytes: &mut [u8]) {
 #[error("invalid request")]
 pub struct InvalidRequest;
 
+#[derive(Error, Debug)]
+#[error("unsupported http version")]
+pub struct UnsupportedVersion;
+
+#[derive(Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitExceeded {
+    #[error("request line too long")]
+    RequestLine,
+    #[error("header fields too large")]
+    Headers,
+    #[error("content too large")]
+    Body,
+}
+
+impl LimitExceeded {
+    pub fn reason_phrase(&self) -> ReasonPhrase {
+        match self {
+            LimitExceeded::RequestLine => ReasonPhrase::URITooLong,
+            LimitExceeded::Headers => ReasonPhrase::RequestHeaderFieldsTooLarge,
+            LimitExceeded::Body => ReasonPhrase::ContentTooLarge,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum length of the request line including CRLF.
+    pub request_line: u64,
+    /// Maximum length of all header lines including the final empty line.
+    pub header_bytes: u64,
+    pub header_count: usize,
+    pub body_size: u64,
+    /// Maximum number of requests served on one connection.
+    pub max_requests: usize,
+    /// Maximum number of pipelined requests answered before the connection
+    /// is closed.
+    pub pipeline_depth: usize,
+    /// Maximum number of concurrent connections from one IP address.
+    pub conns_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            request_line: 8 * 1024,
+            header_bytes: 8 * 1024,
+            header_count: 100,
+            body_size: 8 * 1024 * 1024,
+            max_requests: 100,
+            pipeline_depth: 16,
+            conns_per_ip: 32,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unsupported transfer coding")]
+pub struct UnsupportedTransferCoding;
+
+/// How the length of a request body is determined (RFC 9112, section 6.3).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Framing {
+    None,
+    Length(u64),
+    Chunked,
+}
+
+impl Framing {
+    /// Rejects messages whose framing is ambiguous, like ones with both
+    /// `Transfer-Encoding` and `Content-Length` or with several
+    /// `Content-Length` values, since they enable request smuggling.
+    pub fn parse(headers: &Headers) -> anyhow::Result<Self> {
+        let content_length = headers.get_content_length().map_err(|_| InvalidRequest)?;
+
+        let Some(codings) = headers.get_transfer_encoding() else {
+            return Ok(content_length.map_or(Framing::None, Framing::Length));
+        };
+        if content_length.is_some() {
+            Err(InvalidRequest)?
+        }
+
+        let codings = codings.collect::<Vec<_>>();
+        let is_chunked = |coding: &&str| coding.eq_ignore_ascii_case("chunked");
+        match codings.split_last() {
+            Some((last, rest)) if is_chunked(last) => {
+                if rest.iter().any(is_chunked) {
+                    Err(InvalidRequest)?
+                }
+                if !rest.is_empty() {
+                    Err(UnsupportedTransferCoding)?
+                }
+                Ok(Framing::Chunked)
+            }
+            // Without chunked as final coding the length cannot be determined.
+            _ => Err(InvalidRequest)?,
+        }
+    }
+}
+
+const MAX_DISCARD: u64 = 256 * 1024;
+const MAX_CHUNK_LINE: u64 = 1024;
+
+#[derive(Debug, Copy, Clone)]
+enum BodyState {
+    Done,
+    /// Reading the body failed, the position in the stream is unknown.
+    Failed,
+    Length(u64),
+    Chunked {
+        chunk_remaining: u64,
+        total: u64,
+    },
+}
+
+fn invalid_body(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "connection closed before end of body",
+    )
+}
+
 pub struct RequestReader<R> {
     stream_reader: StreamReader<R>,
+    limits: Limits,
+    body_state: BodyState,
+    request_count: usize,
+    pipeline_depth: usize,
 }
 
 impl<R: Read> RequestReader<R> {
+    #[allow(dead_code)]
     pub fn new(r: R) -> Self {
+        Self::with_limits(r, Limits::default())
+    }
+
+    pub fn with_limits(r: R, limits: Limits) -> Self {
         Self {
             stream_reader: StreamReader::new(r),
+            limits,
+            body_state: BodyState::Done,
+            request_count: 0,
+            pipeline_depth: 0,
         }
     }
 
-    pub fn read_metadata<'a>(&mut self, buf: &'a mut String) -> anyhow::Result<usize> {
-        self.stream_reader.set_limit(1024);
-        self.stream_reader.read_line(buf)?;
-        if !buf.ends_with("\r\n") {
+    pub fn get_ref(&self) -> &R {
+        self.stream_reader.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.stream_reader.get_mut()
+    }
+
+    /// Returns the number of requests started on this connection, including
+    /// the current one.
+    pub fn request_count(&self) -> usize {
+        self.request_count
+    }
+
+    /// Returns the number of consecutive requests, including the current
+    /// one, that were received before the previous response was sent.
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    /// Hands the connection over to another protocol, including what has
+    /// been received but not read yet.
+    pub fn stream_reader(&mut self) -> &mut StreamReader<R> {
+        self.stream_reader.set_limit(u64::MAX);
+        &mut self.stream_reader
+    }
+
+    /// Reads a CRLF terminated line within the current limit.
+    /// Returns `exceeded` if the limit is reached before the line ends.
+    fn read_line(&mut self, buf: &mut String, exceeded: LimitExceeded) -> anyhow::Result<()> {
+        let start = buf.len();
+        match self.stream_reader.read_line(buf) {
+            Err(err) if err.is::<EndOfFile>() && self.stream_reader.limit_reached() => {
+                Err(exceeded)?
+            }
+            res => res?,
+        }
+        if !buf[start..].ends_with("\r\n") {
+            if self.stream_reader.limit_reached() {
+                Err(exceeded)?
+            }
             Err(InvalidRequest)?
         }
+        Ok(())
+    }
+
+    /// Waits until the next request starts to arrive.
+    /// Returns `false` if the peer closed the connection instead.
+    pub fn wait_for_request(&mut self) -> io::Result<bool> {
+        self.pipeline_depth = if self.stream_reader.has_buffered_data() {
+            self.pipeline_depth + 1
+        } else {
+            1
+        };
+
+        self.stream_reader.set_limit(u64::MAX);
+        if self.stream_reader.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+        self.request_count += 1;
+        Ok(true)
+    }
+
+    pub fn read_metadata(&mut self, buf: &mut String) -> anyhow::Result<usize> {
+        self.stream_reader.set_limit(self.limits.request_line);
+        self.read_line(buf, LimitExceeded::RequestLine)?;
         let request_line_end = buf.len();
 
-        self.stream_reader.set_limit(8 * 1024);
+        self.stream_reader.set_limit(self.limits.header_bytes);
         let mut start = request_line_end;
-        loop {
-            self.stream_reader.read_line(buf)?;
-            let mut line = &buf[start..];
-            line = line.strip_suffix("\r\n").ok_or(InvalidRequest)?;
-            if line.is_empty() {
+        for _ in 0..=self.limits.header_count {
+            self.read_line(buf, LimitExceeded::Headers)?;
+            if buf[start..] == *"\r\n" {
                 return Ok(request_line_end);
             }
             start = buf.len();
         }
+        Err(LimitExceeded::Headers)?
+    }
+
+    /// Prepares reading the body of the request whose metadata was read last.
+    pub fn read_body(&mut self, framing: Framing) -> anyhow::Result<Option<BodyReader<'_, R>>> {
+        self.body_state = match framing {
+            Framing::None => return Ok(None),
+            Framing::Length(length) if length > self.limits.body_size => Err(LimitExceeded::Body)?,
+            Framing::Length(length) => BodyState::Length(length),
+            Framing::Chunked => BodyState::Chunked {
+                chunk_remaining: 0,
+                total: 0,
+            },
+        };
+        self.stream_reader.set_limit(u64::MAX);
+        Ok(Some(BodyReader {
+            request_reader: self,
+        }))
     }
 
-    pub fn read_body<'a>(
+    /// Skips what the handler left unread of the current body, so that the
+    /// next request can be read. Returns `false` if more than `MAX_DISCARD`
+    /// bytes are left; the connection cannot be reused then.
+    pub fn discard_body(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 8 * 1024];
+        let mut discarded = 0;
+        loop {
+            match self.body_state {
+                BodyState::Done => return Ok(true),
+                BodyState::Failed => return Ok(false),
+                BodyState::Length(remaining) if remaining > MAX_DISCARD => return Ok(false),
+                _ if discarded > MAX_DISCARD => return Ok(false),
+                _ => discarded += self.read_body_bytes(&mut buf)? as u64,
+            }
+        }
+    }
+
+    fn read_body_bytes(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = match self.body_state {
+            BodyState::Done => return Ok(0),
+            BodyState::Failed => return Err(invalid_body("body unreadable after error")),
+            BodyState::Length(remaining) => self.read_length(buf, remaining),
+            BodyState::Chunked {
+                chunk_remaining,
+                total,
+            } => self.read_chunked(buf, chunk_remaining, total),
+        };
+        if res.is_err() {
+            self.body_state = BodyState::Failed;
+        }
+        res
+    }
+
+    fn read_length(&mut self, buf: &mut [u8], remaining: u64) -> io::Result<usize> {
+        if remaining == 0 {
+            self.body_state = BodyState::Done;
+            return Ok(0);
+        }
+        let max = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.stream_reader.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            return Err(unexpected_eof());
+        }
+        self.body_state = match remaining - n as u64 {
+            0 => BodyState::Done,
+            remaining => BodyState::Length(remaining),
+        };
+        Ok(n)
+    }
+
+    fn read_chunked(
         &mut self,
-        request_line: &RequestLine,
-        headers: &Headers,
-    ) -> anyhow::Result<Option<Vec<u8>>> {
-        self.stream_reader.set_limit(8 * 1024);
-        let mut body = None;
-        if request_line.http_method().to_lowercase() == "post" {
-            let content_length = headers
-                .get_content_length()
-                .map_err(|_| InvalidRequest)?
-                .ok_or(InvalidRequest)?;
-            let mut buf = vec![0; content_length];
-            if let Err(err) = self.stream_reader.read_exact(&mut buf) {
-                if err.kind() == ErrorKind::UnexpectedEof {
-                    Err(InvalidRequest)?
-                } else {
-                    Err(err)?
+        buf: &mut [u8],
+        mut chunk_remaining: u64,
+        mut total: u64,
+    ) -> io::Result<usize> {
+        if chunk_remaining == 0 {
+            let mut line = String::new();
+            self.read_chunk_line(&mut line)?;
+            // Every chunk but the first is preceded by the CRLF ending the
+            // previous chunk's data.
+            if total > 0 {
+                if line != "\r\n" {
+                    return Err(invalid_body(InvalidRequest));
                 }
+                line.clear();
+                self.read_chunk_line(&mut line)?;
+            }
+
+            chunk_remaining = parse_chunk_size(&line).ok_or(invalid_body(InvalidRequest))?;
+
+            if chunk_remaining == 0 {
+                self.read_trailers()?;
+                self.body_state = BodyState::Done;
+                return Ok(0);
+            }
+            total = total.saturating_add(chunk_remaining);
+            if total > self.limits.body_size {
+                return Err(invalid_body(LimitExceeded::Body));
             }
-            body = Some(buf)
+        }
+
+        let max = buf
+            .len()
+            .min(chunk_remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.stream_reader.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            return Err(unexpected_eof());
+        }
+        self.body_state = BodyState::Chunked {
+            chunk_remaining: chunk_remaining - n as u64,
+            total,
         };
-        return Ok(body);
+        Ok(n)
+    }
+
+    fn read_chunk_line(&mut self, line: &mut String) -> io::Result<()> {
+        self.stream_reader.set_limit(MAX_CHUNK_LINE);
+        let res = self.stream_reader.read_line(line);
+        let limit_reached = self.stream_reader.limit_reached();
+        self.stream_reader.set_limit(u64::MAX);
+
+        match res {
+            Err(err) if err.is::<EndOfFile>() && !limit_reached => return Err(unexpected_eof()),
+            Err(err) => return Err(err.downcast::<io::Error>().unwrap_or_else(invalid_body)),
+            Ok(()) => {}
+        }
+        if !line.ends_with("\r\n") {
+            if limit_reached || line.ends_with('\n') {
+                return Err(invalid_body(InvalidRequest));
+            }
+            return Err(unexpected_eof());
+        }
+        Ok(())
+    }
+
+    /// Reads and ignores the trailer section of a chunked body.
+    fn read_trailers(&mut self) -> io::Result<()> {
+        self.stream_reader.set_limit(self.limits.header_bytes);
+        let res = self.read_trailer_lines();
+        self.stream_reader.set_limit(u64::MAX);
+
+        res.map_err(|err| match err.downcast::<io::Error>() {
+            Ok(err) => err,
+            Err(err) if err.is::<EndOfFile>() => unexpected_eof(),
+            Err(err) => invalid_body(err),
+        })
+    }
+
+    fn read_trailer_lines(&mut self) -> anyhow::Result<()> {
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            self.read_line(&mut buf, LimitExceeded::Headers)?;
+            if buf == "\r\n" {
+                return Ok(());
+            }
+        }
     }
 }
 
+/// Parses the size of a chunk line, ignoring chunk extensions.
+fn parse_chunk_size(line: &str) -> Option<u64> {
+    let size = line.trim_end().split(';').next()?.trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+/// Reads the body of the current request from the connection.
+pub struct BodyReader<'a, R> {
+    request_reader: &'a mut RequestReader<R>,
+}
+
+impl<R: Read> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.request_reader.read_body_bytes(buf)
+    }
+}
+
+/// A request whose head and body have been received completely.
+#[derive(Debug)]
+pub struct ParsedRequest {
+    /// Request line and header lines, with lowercase field names.
+    pub head: String,
+    pub request_line_end: usize,
+    pub body: Option<Vec<u8>>,
+    /// Number of input bytes the request occupied.
+    pub len: usize,
+}
+
+#[derive(Debug)]
+struct PendingHead {
+    head: String,
+    request_line_end: usize,
+    framing: Framing,
+}
+
+#[derive(Debug, Default)]
+struct ChunkedProgress {
+    /// Offset of the first byte not parsed yet, relative to the body.
+    pos: usize,
+    decoded: Vec<u8>,
+    trailers: bool,
+}
+
+/// Parses requests from the bytes received so far, for connections that
+/// must not block until more data arrives. Progress is kept between calls,
+/// so the input is scanned only once.
+#[derive(Debug)]
+pub struct RequestParser {
+    limits: Limits,
+    /// Input bytes already searched for the end of the head.
+    scanned: usize,
+    pending: Option<PendingHead>,
+    chunked: ChunkedProgress,
+}
+
+impl RequestParser {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            scanned: 0,
+            pending: None,
+            chunked: ChunkedProgress::default(),
+        }
+    }
+
+    /// Returns the head and framing of the current request once the head
+    /// has been received.
+    pub fn pending_head(&self) -> Option<(&str, usize, Framing)> {
+        self.pending.as_ref().map(|pending| {
+            (
+                pending.head.as_str(),
+                pending.request_line_end,
+                pending.framing,
+            )
+        })
+    }
+
+    /// Parses the request at the start of `input`, which has to start with
+    /// the same bytes as in the previous call. Returns `None` until the
+    /// request has been received completely.
+    pub fn parse(&mut self, input: &[u8]) -> anyhow::Result<Option<ParsedRequest>> {
+        if self.pending.is_none() {
+            let Some(head_len) = self.find_head_end(input)? else {
+                return Ok(None);
+            };
+            self.pending = Some(self.parse_head(&input[..head_len])?);
+        }
+
+        let pending = self.pending.as_ref().unwrap();
+        let head_len = pending.head.len();
+        let body_input = &input[head_len..];
+        let (body, body_len) = match pending.framing {
+            Framing::None => (None, 0),
+            Framing::Length(length) => {
+                let length = length as usize;
+                if body_input.len() < length {
+                    return Ok(None);
+                }
+                (Some(body_input[..length].to_vec()), length)
+            }
+            Framing::Chunked => {
+                if !self.parse_chunked(body_input)? {
+                    return Ok(None);
+                }
+                let chunked = std::mem::take(&mut self.chunked);
+                (Some(chunked.decoded), chunked.pos)
+            }
+        };
+
+        let pending = self.pending.take().unwrap();
+        self.scanned = 0;
+        Ok(Some(ParsedRequest {
+            head: pending.head,
+            request_line_end: pending.request_line_end,
+            body,
+            len: head_len + body_len,
+        }))
+    }
+
+    /// Returns the length of the head once its final empty line has been
+    /// received.
+    fn find_head_end(&mut self, input: &[u8]) -> anyhow::Result<Option<usize>> {
+        let start = self.scanned.saturating_sub(3);
+        if let Some(pos) = find_crlf_crlf(&input[start..]) {
+            return Ok(Some(start + pos + 4));
+        }
+        self.scanned = input.len();
+
+        let request_line = self.limits.request_line as usize;
+        let Some(request_line_len) = find_crlf(&input[..input.len().min(request_line)]) else {
+            if input.len() >= request_line {
+                Err(LimitExceeded::RequestLine)?
+            }
+            return Ok(None);
+        };
+        if input.len() - request_line_len >= self.limits.header_bytes as usize {
+            Err(LimitExceeded::Headers)?
+        }
+        Ok(None)
+    }
+
+    fn parse_head(&self, input: &[u8]) -> anyhow::Result<PendingHead> {
+        let mut request_reader = RequestReader::with_limits(Cursor::new(input), self.limits);
+        let mut head = String::with_capacity(input.len());
+        let request_line_end = request_reader.read_metadata(&mut head).map_err(|err| {
+            if err.is::<LimitExceeded>() {
+                err
+            } else {
+                InvalidRequest.into()
+            }
+        })?;
+
+        let (_, headers_raw) = head.split_at_mut(request_line_end);
+        make_keys_lowercase(unsafe { headers_raw.as_bytes_mut() });
+        let headers = Headers::parse(&head[request_line_end..]).map_err(|_| InvalidRequest)?;
+        let framing = Framing::parse(&headers)?;
+        if let Framing::Length(length) = framing {
+            if length > self.limits.body_size {
+                Err(LimitExceeded::Body)?
+            }
+        }
+
+        Ok(PendingHead {
+            head,
+            request_line_end,
+            framing,
+        })
+    }
+
+    /// Decodes the complete chunks in `input`. Returns whether the last
+    /// chunk and the trailer section have been received.
+    fn parse_chunked(&mut self, input: &[u8]) -> anyhow::Result<bool> {
+        let progress = &mut self.chunked;
+        loop {
+            let rest = &input[progress.pos..];
+            let max_line = if progress.trailers {
+                self.limits.header_bytes
+            } else {
+                MAX_CHUNK_LINE
+            } as usize;
+            let Some(line_len) = find_crlf(&rest[..rest.len().min(max_line)]) else {
+                if rest.len() >= max_line {
+                    Err(InvalidRequest)?
+                }
+                return Ok(false);
+            };
+
+            let line = &rest[..line_len];
+            if progress.trailers {
+                progress.pos += line_len + 2;
+                if line.is_empty() {
+                    return Ok(true);
+                }
+                continue;
+            }
+
+            let size = std::str::from_utf8(line)
+                .ok()
+                .and_then(parse_chunk_size)
+                .ok_or(InvalidRequest)?;
+            if size == 0 {
+                progress.pos += line_len + 2;
+                progress.trailers = true;
+                continue;
+            }
+            if progress.decoded.len() as u64 + size > self.limits.body_size {
+                Err(LimitExceeded::Body)?
+            }
+
+            let data_start = line_len + 2;
+            let data_end = data_start + size as usize;
+            if rest.len() < data_end + 2 {
+                return Ok(false);
+            }
+            if rest[data_end..data_end + 2] != *b"\r\n" {
+                Err(InvalidRequest)?
+            }
+            progress
+                .decoded
+                .extend_from_slice(&rest[data_start..data_end]);
+            progress.pos += data_end + 2;
+        }
+    }
+}
+
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|w| w == b"\r\n")
+}
+
+fn find_crlf_crlf(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::{self, Cursor};
+    use std::io::{self, Cursor, Read};
 
-    use crate::{request::RequestLine, stream_reader::EndOfFile, test_utils::ErrReader};
+    use crate::{
+        headers::Headers, request::RequestLine, stream_reader::EndOfFile, test_utils::ErrReader,
+    };
 
-    use super::RequestReader;
+    use super::{
+        Framing, HttpVersion, InvalidRequest, LimitExceeded, Limits, ParsedRequest, RequestParser,
+        RequestReader, UnsupportedTransferCoding, UnsupportedVersion,
+    };
 
     #[test]
     fn test_request_line_parse() {
         let request_line = RequestLine::parse("GET / HTTP/1.1\r\n").unwrap();
         assert_eq!(request_line.http_method(), "GET");
         assert_eq!(request_line.request_target(), "/");
-        assert_eq!(request_line.http_version(), "HTTP/1.1");
+        assert_eq!(request_line.http_version(), HttpVersion::Http11);
+    }
+
+    #[test]
+    fn test_http_version_parse() {
+        let tests = [
+            ("HTTP/1.0", Some(HttpVersion::Http10)),
+            ("HTTP/1.1", Some(HttpVersion::Http11)),
+            ("HTTP/1.2", Some(HttpVersion::Http11)),
+            ("HTTP/2.0", None),
+            ("HTTP/3.0", None),
+        ];
+        for (raw, want) in tests {
+            let got = HttpVersion::parse(raw);
+            match want {
+                Some(want) => assert_eq!(got.unwrap(), want, "{}", raw),
+                None => assert!(got.unwrap_err().is::<UnsupportedVersion>(), "{}", raw),
+            }
+        }
+
+        for raw in [
+            "",
+            "HTTP/1",
+            "HTTP/11.1",
+            "HTTP/1.x",
+            "http/1.1",
+            "HTTP/1.1 ",
+        ] {
+            let err = HttpVersion::parse(raw).unwrap_err();
+            assert!(err.is::<InvalidRequest>(), "{:?}", raw);
+        }
     }
 
     // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
@@ -200,7 +987,7 @@ mod tests {
         let request_line = RequestLine::parse(&buf[..request_line_end]).unwrap();
         assert_eq!(request_line.http_method(), "GET");
         assert_eq!(request_line.request_target(), "/");
-        assert_eq!(request_line.http_version(), "HTTP/1.1");
+        assert_eq!(request_line.http_version(), HttpVersion::Http11);
     }
 
     #[test]
@@ -258,6 +1045,238 @@ mod tests {
         }
     }
 
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // limits
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    fn check_limit_exceeded(data: &str, limits: Limits, want: LimitExceeded) {
+        let cursor = Cursor::new(data);
+        let mut request_reader = RequestReader::with_limits(cursor, limits);
+        let mut buf = String::new();
+        let err = request_reader.read_metadata(&mut buf).unwrap_err();
+        assert_eq!(*err.downcast_ref::<LimitExceeded>().unwrap(), want);
+    }
+
+    #[test]
+    fn test_request_reader_limits_ok() {
+        let data = "GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        let limits = Limits {
+            request_line: 16,
+            header_bytes: 15,
+            header_count: 1,
+            ..Default::default()
+        };
+        let cursor = Cursor::new(data);
+        let mut request_reader = RequestReader::with_limits(cursor, limits);
+        let mut buf = String::new();
+        request_reader.read_metadata(&mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_request_reader_request_line_too_long() {
+        let limits = Limits {
+            request_line: 15,
+            ..Default::default()
+        };
+        check_limit_exceeded("GET / HTTP/1.1\r\n\r\n", limits, LimitExceeded::RequestLine);
+    }
+
+    #[test]
+    fn test_request_reader_headers_too_large() {
+        let data = "GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        let limits = Limits {
+            header_bytes: 14,
+            ..Default::default()
+        };
+        check_limit_exceeded(data, limits, LimitExceeded::Headers);
+
+        let data = "GET / HTTP/1.1\r\nAccept: */*\r\nHost: localhost\r\n\r\n";
+        let limits = Limits {
+            header_count: 1,
+            ..Default::default()
+        };
+        check_limit_exceeded(data, limits, LimitExceeded::Headers);
+    }
+
+    #[test]
+    fn test_request_reader_body_too_large() {
+        let cursor = Cursor::new("hello");
+        let limits = Limits {
+            body_size: 4,
+            ..Default::default()
+        };
+        let mut request_reader = RequestReader::with_limits(cursor, limits);
+        let headers = Headers::parse("content-length: 5\r\n\r\n").unwrap();
+        let framing = Framing::parse(&headers).unwrap();
+        let Err(err) = request_reader.read_body(framing) else {
+            panic!("body limit not enforced");
+        };
+        assert_eq!(
+            *err.downcast_ref::<LimitExceeded>().unwrap(),
+            LimitExceeded::Body
+        );
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // body
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    #[test]
+    fn test_request_reader_body() {
+        let data = "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        let mut request_reader = RequestReader::new(Cursor::new(data));
+        let mut buf = String::new();
+        let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
+        let headers = Headers::parse(&buf[request_line_end..]).unwrap();
+
+        let mut body = String::new();
+        let mut body_reader = request_reader
+            .read_body(Framing::parse(&headers).unwrap())
+            .unwrap()
+            .unwrap();
+        body_reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn test_request_reader_body_unexpected_eof() {
+        let data = "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhel";
+        let mut request_reader = RequestReader::new(Cursor::new(data));
+        let mut buf = String::new();
+        let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
+        let headers = Headers::parse(&buf[request_line_end..]).unwrap();
+
+        let mut body = vec![];
+        let mut body_reader = request_reader
+            .read_body(Framing::parse(&headers).unwrap())
+            .unwrap()
+            .unwrap();
+        let err = body_reader.read_to_end(&mut body).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_request_reader_discard_body() {
+        let fst = "POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello";
+        let snd = "GET /about HTTP/1.1\r\n\r\n";
+        let cursor = Cursor::new(format!("{}{}", fst, snd));
+        let mut request_reader = RequestReader::new(cursor);
+
+        {
+            assert!(request_reader.wait_for_request().unwrap());
+            let mut buf = String::new();
+            let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
+            let headers = Headers::parse(&buf[request_line_end..]).unwrap();
+            let mut body_reader = request_reader
+                .read_body(Framing::parse(&headers).unwrap())
+                .unwrap()
+                .unwrap();
+            body_reader.read_exact(&mut [0; 2]).unwrap();
+            assert!(request_reader.discard_body().unwrap());
+        }
+
+        let mut buf = String::new();
+        let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
+        let request_line = RequestLine::parse(&buf[..request_line_end]).unwrap();
+        assert_eq!(request_line.request_target(), "/about");
+    }
+
+    #[test]
+    fn test_framing() {
+        let tests = [
+            ("", Some(Framing::None)),
+            ("content-length: 5\r\n", Some(Framing::Length(5))),
+            ("content-length: 0\r\n", Some(Framing::Length(0))),
+            ("transfer-encoding: chunked\r\n", Some(Framing::Chunked)),
+            ("transfer-encoding: Chunked\r\n", Some(Framing::Chunked)),
+            // duplicate or invalid content length
+            ("content-length: 5\r\ncontent-length: 5\r\n", None),
+            ("content-length: 5, 6\r\n", None),
+            ("content-length: +5\r\n", None),
+            ("content-length: \r\n", None),
+            // ambiguous framing
+            ("transfer-encoding: chunked\r\ncontent-length: 5\r\n", None),
+            ("transfer-encoding: chunked, chunked\r\n", None),
+            ("transfer-encoding: chunked, gzip\r\n", None),
+            ("transfer-encoding: gzip\r\n", None),
+        ];
+
+        for (raw, want) in tests {
+            let raw = format!("{}\r\n", raw);
+            let headers = Headers::parse(&raw).unwrap();
+            assert_eq!(Framing::parse(&headers).ok(), want, "{:?}", raw);
+        }
+
+        let headers = Headers::parse("transfer-encoding: gzip, chunked\r\n\r\n").unwrap();
+        let err = Framing::parse(&headers).unwrap_err();
+        err.downcast_ref::<UnsupportedTransferCoding>().unwrap();
+    }
+
+    fn read_chunked(data: &str, limits: Limits) -> (io::Result<String>, String) {
+        let data = format!(
+            "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n{}GET /next HTTP/1.1\r\n\r\n",
+            data
+        );
+        let mut request_reader = RequestReader::with_limits(Cursor::new(data), limits);
+        let mut buf = String::new();
+        request_reader.read_metadata(&mut buf).unwrap();
+
+        let mut body = String::new();
+        let res = request_reader
+            .read_body(Framing::Chunked)
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut body)
+            .map(|_| body);
+        if res.is_err() {
+            return (res, String::new());
+        }
+
+        buf.clear();
+        let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
+        (res, buf[..request_line_end].to_owned())
+    }
+
+    #[test]
+    fn test_request_reader_chunked() {
+        let data = "5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nexpires: never\r\n\r\n";
+        let (body, next) = read_chunked(data, Limits::default());
+        assert_eq!(body.unwrap(), "hello, world");
+        assert_eq!(next, "GET /next HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_request_reader_chunked_invalid() {
+        let tests = [
+            "5\r\nhelloXX7\r\n, world\r\n0\r\n\r\n",
+            "x\r\nhello\r\n0\r\n\r\n",
+            "-5\r\nhello\r\n0\r\n\r\n",
+            "5\nhello\r\n0\r\n\r\n",
+        ];
+        for data in tests {
+            let (body, _) = read_chunked(data, Limits::default());
+            assert_eq!(
+                body.unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+                "{:?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_reader_chunked_too_large() {
+        let limits = Limits {
+            body_size: 8,
+            ..Default::default()
+        };
+        let data = "5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n";
+        let (body, _) = read_chunked(data, limits);
+        let err = body.unwrap_err();
+        let inner = err.get_ref().unwrap().downcast_ref::<LimitExceeded>();
+        assert_eq!(inner, Some(&LimitExceeded::Body));
+    }
+
     // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
     // multiple requests
     // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
@@ -270,25 +1289,108 @@ mod tests {
         let mut request_reader = RequestReader::new(cursor);
 
         {
+            assert!(request_reader.wait_for_request().unwrap());
             let mut buf = String::new();
             let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
             let request_line = RequestLine::parse(&buf[..request_line_end]).unwrap();
             assert_eq!(request_line.http_method(), "GET");
             assert_eq!(request_line.request_target(), "/");
-            assert_eq!(request_line.http_version(), "HTTP/1.1");
+            assert_eq!(request_line.http_version(), HttpVersion::Http11);
+            assert_eq!(request_reader.pipeline_depth(), 1);
         }
 
         {
+            assert!(request_reader.wait_for_request().unwrap());
             let mut buf = String::new();
             let request_line_end = request_reader.read_metadata(&mut buf).unwrap();
             let request_line = RequestLine::parse(&buf[..request_line_end]).unwrap();
             assert_eq!(request_line.http_method(), "GET");
             assert_eq!(request_line.request_target(), "/about");
-            assert_eq!(request_line.http_version(), "HTTP/1.1");
+            assert_eq!(request_line.http_version(), HttpVersion::Http11);
+            assert_eq!(request_reader.pipeline_depth(), 2);
         }
 
-        let mut buf = String::new();
-        let res = request_reader.read_metadata(&mut buf);
-        res.unwrap_err().downcast_ref::<EndOfFile>().unwrap();
+        assert!(!request_reader.wait_for_request().unwrap());
+        assert_eq!(request_reader.request_count(), 2);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // request parser
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    /// Feeds `input` one byte at a time and returns the parsed requests.
+    fn parse_bytewise(input: &[u8], limits: Limits) -> anyhow::Result<Vec<ParsedRequest>> {
+        let mut parser = RequestParser::new(limits);
+        let mut received = vec![];
+        let mut parsed = vec![];
+        for b in input {
+            received.push(*b);
+            while let Some(request) = parser.parse(&received)? {
+                received.drain(..request.len);
+                parsed.push(request);
+            }
+        }
+        assert!(received.is_empty());
+        Ok(parsed)
+    }
+
+    #[test]
+    fn test_request_parser() {
+        let input = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                      POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                      PUT /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nexpires: never\r\n\r\n";
+        let parsed = parse_bytewise(input, Limits::default()).unwrap();
+        assert_eq!(parsed.len(), 3);
+
+        assert_eq!(parsed[0].head, "GET / HTTP/1.1\r\nhost: localhost\r\n\r\n");
+        assert_eq!(parsed[0].request_line_end, "GET / HTTP/1.1\r\n".len());
+        assert_eq!(parsed[0].body, None);
+        assert_eq!(parsed[1].body.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(parsed[2].body.as_deref(), Some(&b"hello, world"[..]));
+    }
+
+    #[test]
+    fn test_request_parser_pending_head() {
+        let mut parser = RequestParser::new(Limits::default());
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(parser.parse(input).unwrap().is_none());
+        let (head, _, framing) = parser.pending_head().unwrap();
+        assert!(head.starts_with("POST / HTTP/1.1\r\n"));
+        assert_eq!(framing, Framing::Length(5));
+    }
+
+    #[test]
+    fn test_request_parser_errors() {
+        let limits = Limits {
+            request_line: 32,
+            header_bytes: 32,
+            body_size: 8,
+            ..Default::default()
+        };
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+        let err = parse_bytewise(long_target.as_bytes(), limits).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitExceeded::RequestLine));
+
+        let long_header = format!("GET / HTTP/1.1\r\nx: {}\r\n\r\n", "a".repeat(32));
+        let err = parse_bytewise(long_header.as_bytes(), limits).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitExceeded::Headers));
+
+        let input = b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n";
+        let err = parse_bytewise(input, limits).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitExceeded::Body));
+
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\n";
+        let err = parse_bytewise(input, limits).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&LimitExceeded::Body));
+
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX";
+        let err = parse_bytewise(input, limits).unwrap_err();
+        assert!(err.is::<InvalidRequest>());
+
+        let input = b"GET / HTTP/1.1\r\nno colon\r\n\r\n";
+        let err = parse_bytewise(input, limits).unwrap_err();
+        assert!(err.is::<InvalidRequest>());
     }
 }
@@ -1,23 +1,68 @@
+use std::{
+    fmt::{self, Debug},
+    io::{self, BufWriter, Read, Write},
+    time::Duration,
+};
+
 use crate::{
-    server::HttpMethod,
+    request::{HttpVersion, Request},
+    server::{HttpMethod, Upgraded},
+    sse::EventStream,
     status_code_registry::{self, ReasonPhrase},
 };
 
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Streamed with chunked transfer coding, or delimited by closing the
+    /// connection for HTTP/1.0.
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader(_) => f.debug_tuple("Reader").finish(),
+        }
+    }
+}
+
+type UpgradeFn = dyn FnOnce(&Request, Upgraded) + Send;
+
+/// Takes over the connection after a `101 Switching Protocols` response.
+pub struct OnUpgrade(Box<UpgradeFn>);
+
+impl OnUpgrade {
+    pub fn call(self, r: &Request, conn: Upgraded) {
+        (self.0)(r, conn)
+    }
+}
+
+impl Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnUpgrade").finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct ResponseWriter {
+    http_version: HttpVersion,
     status_code: Option<u16>,
     reason_phrase: Option<String>,
     headers: Vec<(String, String)>,
-    body: Vec<u8>,
+    body: Body,
+    upgrade: Option<OnUpgrade>,
 }
 
 impl ResponseWriter {
     fn new(status_code: Option<u16>, reason_phrase: Option<String>) -> Self {
         Self {
+            http_version: HttpVersion::Http11,
             status_code,
             reason_phrase,
             headers: vec![],
-            body: vec![],
+            body: Body::Bytes(vec![]),
+            upgrade: None,
         }
     }
 
@@ -25,7 +70,10 @@ impl ResponseWriter {
         Self::new(None, None)
     }
 
-    #[allow(unused)]
+    pub fn set_http_version(&mut self, http_version: HttpVersion) {
+        self.http_version = http_version;
+    }
+
     pub fn get_status_code(&self) -> Option<u16> {
         self.status_code
     }
@@ -48,6 +96,10 @@ impl ResponseWriter {
         self.reason_phrase = Some(reason_phrase);
     }
 
+    pub fn get_headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
     fn add_header(&mut self, k: String, v: String) {
         if let Some(entry) = self.headers.iter_mut().find(|entry| entry.0 == k) {
             entry.1 = v;
@@ -66,6 +118,10 @@ impl ResponseWriter {
         self.add_header("Allow".to_owned(), http_methods);
     }
 
+    pub fn has_header(&self, k: &str) -> bool {
+        self.headers.iter().any(|entry| entry.0.to_lowercase() == k)
+    }
+
     pub fn get_content_type_header(&self) -> Option<&str> {
         self.headers
             .iter()
@@ -77,53 +133,279 @@ impl ResponseWriter {
         self.add_header("Content-Encoding".to_owned(), content_encoding.to_string());
     }
 
+    pub fn add_accept_encoding_header(&mut self, codings: &str) {
+        self.add_header("Accept-Encoding".to_owned(), codings.to_owned());
+    }
+
+    pub fn add_vary_header(&mut self, field: &str) {
+        let vary = match self.headers.iter().find(|entry| entry.0 == "Vary") {
+            Some((_, v)) if v.split(',').any(|f| f.trim().eq_ignore_ascii_case(field)) => return,
+            Some((_, v)) => format!("{}, {}", v, field),
+            None => field.to_owned(),
+        };
+        self.add_header("Vary".to_owned(), vary);
+    }
+
+    pub fn add_connection_header(&mut self, option: &str) {
+        self.add_header("Connection".to_owned(), option.to_owned());
+    }
+
+    pub fn add_upgrade_header(&mut self, protocol: &str) {
+        self.add_header("Upgrade".to_owned(), protocol.to_owned());
+    }
+
+    pub fn add_sec_websocket_accept_header(&mut self, accept: &str) {
+        self.add_header("Sec-WebSocket-Accept".to_owned(), accept.to_owned());
+    }
+
+    pub fn add_sec_websocket_version_header(&mut self, version: &str) {
+        self.add_header("Sec-WebSocket-Version".to_owned(), version.to_owned());
+    }
+
+    pub fn add_cache_control_header(&mut self, directives: &str) {
+        self.add_header("Cache-Control".to_owned(), directives.to_owned());
+    }
+
+    pub fn get_connection_header(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|entry| entry.0.to_lowercase() == "connection")
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// `max` is the number of requests the connection still accepts.
+    pub fn add_keep_alive_header(&mut self, timeout: Duration, max: usize) {
+        let keep_alive = format!("timeout={}, max={}", timeout.as_secs(), max);
+        self.add_header("Keep-Alive".to_owned(), keep_alive);
+    }
+
     fn add_content_type_header(&mut self, content_type: &str) {
         self.add_header("Content-Type".to_owned(), content_type.to_owned());
     }
 
-    fn add_content_length_header(&mut self) {
-        self.add_header("Content-Length".to_owned(), self.body.len().to_string());
+    pub fn has_body(&self) -> bool {
+        match &self.body {
+            Body::Bytes(bytes) => !bytes.is_empty(),
+            Body::Reader(_) => true,
+        }
+    }
+
+    /// Returns the length of the body, or `None` if it is streamed.
+    pub fn get_body_len(&self) -> Option<usize> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes.len()),
+            Body::Reader(_) => None,
+        }
     }
 
-    pub fn get_body(&self) -> &[u8] {
-        &self.body
+    pub fn take_body(&mut self) -> Body {
+        std::mem::replace(&mut self.body, Body::Bytes(vec![]))
     }
 
     pub fn set_body(&mut self, body: Vec<u8>, content_type: &str) {
-        self.body = body;
+        self.body = Body::Bytes(body);
+        self.add_content_type_header(content_type);
+    }
+
+    pub fn set_body_reader(&mut self, body: impl Read + Send + 'static, content_type: &str) {
+        self.body = Body::Reader(Box::new(body));
         self.add_content_type_header(content_type);
-        self.add_content_length_header();
     }
 
     pub fn set_body_str(&mut self, body: &str) {
         self.set_body(body.bytes().collect(), "text/plain");
     }
 
-    pub fn write(self) -> Vec<u8> {
+    /// Streams `events` as a `text/event-stream` body until all senders of
+    /// the stream are dropped.
+    pub fn set_event_stream(&mut self, events: EventStream) {
+        self.set_body_reader(events, "text/event-stream");
+        self.add_cache_control_header("no-cache");
+    }
+
+    /// Whether the body is an event stream, which must be neither buffered
+    /// nor compressed.
+    pub fn is_event_stream(&self) -> bool {
+        self.get_body_len().is_none()
+            && self
+                .get_content_type_header()
+                .is_some_and(|v| v.split(';').next().unwrap().trim() == "text/event-stream")
+    }
+
+    /// Switches the connection to `protocol`. `on_upgrade` is called with
+    /// the request and the connection once the `101 Switching Protocols`
+    /// response is sent.
+    pub fn set_upgrade(
+        &mut self,
+        protocol: &str,
+        on_upgrade: impl FnOnce(&Request, Upgraded) + Send + 'static,
+    ) {
+        self.set_reason_phrase(ReasonPhrase::SwitchingProtocols);
+        self.add_connection_header("Upgrade");
+        self.add_upgrade_header(protocol);
+        self.upgrade = Some(OnUpgrade(Box::new(on_upgrade)));
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade.take()
+    }
+
+    pub fn write_to(mut self, writer: &mut impl Write) -> io::Result<()> {
         let status_code = self.status_code.unwrap();
-        let mut status_line = format!("HTTP/1.1 {}", status_code);
+        let mut head = format!("{} {}", self.http_version.as_str(), status_code);
         if let Some(reason_phrase) = &self.reason_phrase {
-            status_line = format!("{} {}", status_line, reason_phrase);
+            head = format!("{} {}", head, reason_phrase);
         }
-        status_line.push_str("\r\n");
+        head.push_str("\r\n");
 
-        if status_code == 404 {
-            status_line.push_str("\r\n");
-            return status_line.bytes().collect();
+        if has_framed_body(status_code) {
+            match &self.body {
+                Body::Bytes(bytes) => {
+                    self.add_header("Content-Length".to_owned(), bytes.len().to_string())
+                }
+                Body::Reader(_) if self.http_version == HttpVersion::Http10 => {}
+                Body::Reader(_) => {
+                    self.add_header("Transfer-Encoding".to_owned(), "chunked".to_owned())
+                }
+            }
         }
 
-        let mut headers = self
-            .headers
-            .into_iter()
-            .map(|(k, v)| format!("{}: {}\r\n", k, v))
-            .collect::<Vec<_>>()
-            .join("");
-        headers.push_str("\r\n");
-
-        let mut resp = vec![];
-        resp.extend(status_line.bytes());
-        resp.extend(headers.bytes());
-        resp.extend(self.body);
-        resp
+        for (k, v) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+
+        match self.body {
+            Body::Bytes(bytes) => {
+                let mut resp = head.into_bytes();
+                resp.extend(bytes);
+                writer.write_all(&resp)?;
+            }
+            Body::Reader(mut reader) => {
+                let mut writer = BufWriter::new(writer);
+                writer.write_all(head.as_bytes())?;
+                let chunked = self.http_version != HttpVersion::Http10;
+                write_streamed(&mut reader, &mut writer, chunked)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether responses with `status_code` say how their body is framed.
+/// 1xx, 204 and 304 responses carry no body and no `Content-Length`.
+pub fn has_framed_body(status_code: u16) -> bool {
+    !(100..200).contains(&status_code) && status_code != 204 && status_code != 304
+}
+
+/// Flushes whatever was read, so that bodies produced over time, like event
+/// streams, reach the client right away.
+fn write_streamed(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    chunked: bool,
+) -> io::Result<()> {
+    let mut buf = vec![0; 8 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if chunked {
+            write!(writer, "{:x}\r\n", n)?;
+            writer.write_all(&buf[..n])?;
+            writer.write_all(b"\r\n")?;
+        } else {
+            writer.write_all(&buf[..n])?;
+        }
+        writer.flush()?;
+    }
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{request::HttpVersion, status_code_registry::ReasonPhrase};
+
+    use super::ResponseWriter;
+
+    fn write(w: ResponseWriter) -> String {
+        let mut buf = vec![];
+        w.write_to(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_write_not_found() {
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotFound);
+        w.add_header("X-Request-Id".to_owned(), "42".to_owned());
+        w.set_body_str("nothing here");
+        let resp = write(w);
+        assert_eq!(
+            resp,
+            "HTTP/1.1 404 Not Found\r\n\
+             X-Request-Id: 42\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: 12\r\n\
+             \r\n\
+             nothing here"
+        );
+    }
+
+    #[test]
+    fn test_write_empty_body() {
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotFound);
+        let resp = write(w);
+        assert_eq!(resp, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_not_modified() {
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotModified);
+        let resp = write(w);
+        assert_eq!(resp, "HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_chunked() {
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::OK);
+        w.set_body_reader(Cursor::new("Hello World!"), "text/plain");
+        let resp = write(w);
+        assert_eq!(
+            resp,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             Transfer-Encoding: chunked\r\n\
+             \r\n\
+             c\r\nHello World!\r\n\
+             0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_write_http10() {
+        let mut w = ResponseWriter::new_empty();
+        w.set_http_version(HttpVersion::Http10);
+        w.set_reason_phrase(ReasonPhrase::OK);
+        w.set_body_reader(Cursor::new("Hello World!"), "text/plain");
+        let resp = write(w);
+        assert_eq!(
+            resp,
+            "HTTP/1.0 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             Hello World!"
+        );
     }
 }
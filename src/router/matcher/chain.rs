@@ -1,6 +1,4 @@
-use crate::server::Handler;
-
-use super::{Dynamic, Exact, Match, Subtree};
+use super::{Dynamic, Exact, Match, RouteHandler, Subtree};
 
 pub struct Chain<'a> {
     exact: Exact<'a>,
@@ -17,8 +15,9 @@ impl<'a> Chain<'a> {
         }
     }
 
-    pub fn add_route(&mut self, pattern: impl Into<String>, handler: &'a (impl Handler + Sync)) {
+    pub fn add_route(&mut self, pattern: impl Into<String>, handler: impl Into<RouteHandler<'a>>) {
         let pattern = pattern.into();
+        let handler = handler.into();
         assert!(pattern.starts_with('/'));
 
         if pattern == "/" {
@@ -40,7 +39,7 @@ impl<'a> Chain<'a> {
     pub fn pattern_match<'req_line>(
         &self,
         request_target: &'req_line str,
-    ) -> Option<(Match, Option<&'req_line str>)> {
+    ) -> Option<(Match<'_, '_>, Option<&'req_line str>)> {
         if let Some(m) = self.exact.pattern_match(request_target) {
             return Some((m, None));
         }
@@ -91,7 +90,7 @@ mod tests {
         for (request_target, pattern, param_want) in tests {
             let (m, param_got) = chain.pattern_match(request_target).unwrap();
             assert_eq!(m.pattern, pattern);
-            assert_eq!(param_got.as_deref(), param_want);
+            assert_eq!(param_got, param_want);
         }
     }
 
@@ -1,10 +1,8 @@
 use std::collections::HashMap;
 
-use crate::server::Handler;
+use super::{Match, RouteHandler};
 
-use super::Match;
-
-pub struct Dynamic<'a>(HashMap<String, (String, &'a (dyn Handler + Sync))>);
+pub struct Dynamic<'a>(HashMap<String, (String, RouteHandler<'a>)>);
 
 impl<'a> Dynamic<'a> {
     pub fn new() -> Self {
@@ -15,18 +13,18 @@ impl<'a> Dynamic<'a> {
         &mut self,
         prefix: impl Into<String>,
         pattern: impl Into<String>,
-        handler: &'a (impl Handler + Sync),
+        handler: impl Into<RouteHandler<'a>>,
     ) {
         let prefix = prefix.into();
         let pattern = pattern.into();
         assert!(!prefix.ends_with("/"));
-        self.0.insert(prefix, (pattern, handler));
+        self.0.insert(prefix, (pattern, handler.into()));
     }
 
     pub fn pattern_match<'req_line>(
         &self,
         request_target: &'req_line str,
-    ) -> Option<(Match, &'req_line str)> {
+    ) -> Option<(Match<'_, '_>, &'req_line str)> {
         let (prefix, param) = request_target.rsplit_once("/")?;
         if param.is_empty() {
             return None;
@@ -34,7 +32,7 @@ impl<'a> Dynamic<'a> {
 
         self.0
             .get(prefix)
-            .map(|(pattern, handler)| (Match::new(pattern, *handler), param))
+            .map(|(pattern, handler)| (Match::new(pattern, handler.get()), param))
     }
 }
 
@@ -1,26 +1,24 @@
 use std::collections::HashMap;
 
-use crate::server::Handler;
+use super::{Match, RouteHandler};
 
-use super::Match;
-
-pub struct Exact<'a>(HashMap<String, &'a (dyn Handler + Sync)>);
+pub struct Exact<'a>(HashMap<String, RouteHandler<'a>>);
 
 impl<'a> Exact<'a> {
     pub fn new() -> Self {
         Self(HashMap::new())
     }
 
-    pub fn add_route(&mut self, pattern: impl Into<String>, handler: &'a (impl Handler + Sync)) {
+    pub fn add_route(&mut self, pattern: impl Into<String>, handler: impl Into<RouteHandler<'a>>) {
         let pattern = pattern.into();
         assert!(pattern == "/" || !pattern.ends_with("/"));
-        self.0.insert(pattern, handler);
+        self.0.insert(pattern, handler.into());
     }
 
-    pub fn pattern_match(&self, request_target: &str) -> Option<Match> {
+    pub fn pattern_match(&self, request_target: &str) -> Option<Match<'_, '_>> {
         self.0
             .get_key_value(request_target)
-            .map(|(pattern, handler)| Match::new(pattern, *handler))
+            .map(|(pattern, handler)| Match::new(pattern, handler.get()))
     }
 }
 
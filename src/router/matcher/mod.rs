@@ -10,6 +10,27 @@ mod dynamic;
 mod exact;
 mod subtree;
 
+/// The handler of a route, borrowed or owned by the router.
+pub enum RouteHandler<'a> {
+    Borrowed(&'a (dyn Handler + Sync)),
+    Owned(Box<dyn Handler + Send + Sync>),
+}
+
+impl RouteHandler<'_> {
+    fn get(&self) -> &(dyn Handler + Sync) {
+        match self {
+            RouteHandler::Borrowed(handler) => *handler,
+            RouteHandler::Owned(handler) => &**handler,
+        }
+    }
+}
+
+impl<'a, H: Handler + Sync> From<&'a H> for RouteHandler<'a> {
+    fn from(handler: &'a H) -> Self {
+        RouteHandler::Borrowed(handler)
+    }
+}
+
 pub struct Match<'p, 'h> {
     pub pattern: &'p str,
     pub handler: &'h (dyn Handler + Sync),
@@ -17,9 +38,6 @@ pub struct Match<'p, 'h> {
 
 impl<'p, 'h> Match<'p, 'h> {
     fn new(pattern: &'p str, handler: &'h (dyn Handler + Sync)) -> Self {
-        Self {
-            pattern: pattern,
-            handler,
-        }
+        Self { pattern, handler }
     }
 }
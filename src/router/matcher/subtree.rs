@@ -1,28 +1,26 @@
-use crate::server::Handler;
+use super::{Match, RouteHandler};
 
-use super::Match;
-
-pub struct Subtree<'a>(Vec<(String, &'a (dyn Handler + Sync))>);
+pub struct Subtree<'a>(Vec<(String, RouteHandler<'a>)>);
 
 impl<'a> Subtree<'a> {
     pub fn new() -> Self {
         Self(vec![])
     }
 
-    pub fn add_route(&mut self, pattern: impl Into<String>, handler: &'a (impl Handler + Sync)) {
+    pub fn add_route(&mut self, pattern: impl Into<String>, handler: impl Into<RouteHandler<'a>>) {
         let pattern = pattern.into();
         assert!(pattern != "/" && pattern.ends_with("/"));
-        self.0.push((pattern, handler));
+        self.0.push((pattern, handler.into()));
         self.0.sort_by(|(l, _), (r, _)| r.cmp(l));
     }
 
     pub fn pattern_match<'req_line>(
         &self,
         request_target: &'req_line str,
-    ) -> Option<(Match, &'req_line str)> {
+    ) -> Option<(Match<'_, '_>, &'req_line str)> {
         for (pattern, handler) in &self.0 {
             if let Some(param) = request_target.strip_prefix(pattern) {
-                return Some((Match::new(pattern, *handler), param));
+                return Some((Match::new(pattern, handler.get()), param));
             }
         }
         None
@@ -1,4 +1,4 @@
-use matcher::{Chain, Match};
+use matcher::{Chain, Match, RouteHandler};
 use tracing::info;
 
 use crate::{
@@ -30,11 +30,22 @@ impl<'a> Router<'a> {
         self.chains[http_method as usize].add_route(pattern, handler);
     }
 
+    /// Like [`Router::add_route`], for a handler the router keeps.
+    pub fn add_owned_route(
+        &mut self,
+        http_method: HttpMethod,
+        pattern: impl Into<String>,
+        handler: impl Handler + Send + Sync + 'static,
+    ) {
+        self.chains[http_method as usize]
+            .add_route(pattern, RouteHandler::Owned(Box::new(handler)));
+    }
+
     fn pattern_match<'req_line>(
         &self,
         http_method: HttpMethod,
         request_target: &'req_line str,
-    ) -> Option<(Match, Option<&'req_line str>)> {
+    ) -> Option<(Match<'_, '_>, Option<&'req_line str>)> {
         self.chains[http_method as usize].pattern_match(request_target)
     }
 
@@ -83,6 +94,20 @@ impl<'a> Handler for Router<'a> {
     fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
         self.handle(w, r);
     }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        let http_method =
+            HttpMethod::try_from(r.get_http_method()).map_err(|_| ReasonPhrase::BadRequest)?;
+        let request_target = r.get_request_target();
+
+        if let Some((m, _)) = self.pattern_match(http_method, request_target) {
+            return m.handler.check_request(r);
+        }
+        if !self.find_allowed_methods(request_target).is_empty() {
+            return Err(ReasonPhrase::MethodNotAllowed);
+        }
+        Err(ReasonPhrase::NotFound)
+    }
 }
 
 #[cfg(test)]
@@ -103,7 +128,7 @@ mod tests {
 
     #[test]
     fn test_method_not_allowed() {
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         thread::spawn(move || {
@@ -1,21 +1,36 @@
-#[cfg(test)]
-use std::net::SocketAddr;
 use std::{
-    io::Write,
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    collections::HashMap,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use clap::ValueEnum;
+use serde::Deserialize;
 use strum_macros::Display;
-use tracing::{debug, error, info, span, Level, Span};
+use tracing::{debug, error, field, info, span, warn, Level, Span};
 
 use crate::{
+    event_loop,
     headers::Headers,
-    request::{make_keys_lowercase, InvalidRequest, Request, RequestLine, RequestReader},
+    http2,
+    listener::Listener,
+    proxy::{self, TrustedProxies},
+    request::{
+        make_keys_lowercase, ClientIdentity, Connection, Framing, HttpVersion, InvalidRequest,
+        LimitExceeded, Limits, Request, RequestLine, RequestReader, Scheme,
+        UnsupportedTransferCoding, UnsupportedVersion,
+    },
     response_writer::ResponseWriter,
     status_code_registry::ReasonPhrase,
+    stream::{PeerAddr, Stream},
     stream_reader::EndOfFile,
+    timed_stream::{RequestTimeout, TimedStream},
 };
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Display)]
@@ -46,132 +61,963 @@ impl TryFrom<&str> for HttpMethod {
     }
 }
 
+/// Durations are written like `5s` or `1m 30s` in configuration files.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long a persistent connection waits for the next request.
+    #[serde(with = "humantime_serde")]
+    pub idle: Duration,
+    /// Read timeout while receiving the request line and headers.
+    #[serde(with = "humantime_serde")]
+    pub header: Duration,
+    /// Read timeout while receiving the body.
+    #[serde(with = "humantime_serde")]
+    pub body: Duration,
+    /// Time to receive the request line and headers.
+    #[serde(with = "humantime_serde")]
+    pub header_deadline: Duration,
+    /// Time to receive the body.
+    #[serde(with = "humantime_serde")]
+    pub body_deadline: Duration,
+    /// Minimum average transfer rate of the body in bytes per second.
+    /// Zero disables the check.
+    pub min_body_rate: u64,
+    /// Read timeout of connections switched to another protocol.
+    #[serde(with = "humantime_serde")]
+    pub upgraded: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(5),
+            header: Duration::from_secs(10),
+            body: Duration::from_secs(10),
+            header_deadline: Duration::from_secs(20),
+            body_deadline: Duration::from_secs(300),
+            min_body_rate: 1024,
+            upgraded: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How connections are served.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// One blocking thread per connection.
+    #[default]
+    Threaded,
+    /// A single thread multiplexing non-blocking connections.
+    EventLoop,
+    /// Tasks on a multi-threaded tokio runtime.
+    #[cfg(feature = "tokio")]
+    Async,
+}
+
+/// The handler, limits and timeouts connections are served with.
+pub struct Site {
+    pub handler: Box<dyn Handler + Send + Sync>,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+}
+
+/// A [`Site`] that can be replaced while the server runs. Connections keep
+/// the site they were accepted with until they close.
+#[derive(Clone)]
+pub struct SiteHandle(Arc<RwLock<Arc<Site>>>);
+
+impl SiteHandle {
+    pub fn new(site: Site) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(site))))
+    }
+
+    pub fn load(&self) -> Arc<Site> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Serves the connections accepted from now on with `site`.
+    pub fn store(&self, site: Site) {
+        *self.0.write().unwrap() = Arc::new(site);
+    }
+}
+
+/// Serves requests with the handler of a site it keeps.
+#[cfg(feature = "tokio")]
+struct SharedSite(Arc<Site>);
+
+#[cfg(feature = "tokio")]
+impl Handler for SharedSite {
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        self.0.handler.handle(w, r);
+    }
+
+    fn check_request(&self, r: &Request) -> Result<(), ReasonPhrase> {
+        self.0.handler.check_request(r)
+    }
+}
+
+/// What serves the connections of a listener.
+#[derive(Clone, Copy)]
+enum Serve<'s> {
+    Handler(&'s (dyn Handler + Sync)),
+    Site(&'s SiteHandle),
+}
+
 #[derive(Debug)]
 pub struct Server {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
+    backend: Backend,
+    limits: Limits,
+    timeouts: Timeouts,
+    conn_counter: ConnCounter,
+    proxy_protocol: bool,
+    trusted_proxies: Arc<TrustedProxies>,
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl Server {
-    pub fn new(addr: impl ToSocketAddrs) -> Self {
+    #[allow(dead_code)]
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Listener::bind_tcp(addr).map(Self::from_listener)
+    }
+
+    pub fn from_listener(listener: Listener) -> Self {
         Self {
-            listener: TcpListener::bind(addr).unwrap(),
+            listeners: vec![listener],
+            backend: Backend::default(),
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            conn_counter: ConnCounter::default(),
+            proxy_protocol: false,
+            trusted_proxies: Arc::default(),
+            #[cfg(feature = "tls")]
+            tls_config: None,
         }
     }
 
+    /// Accepts connections on `listener` too. Returns its index, which
+    /// selects its handler in [`Server::run_each`].
+    pub fn add_listener(&mut self, listener: Listener) -> usize {
+        self.listeners.push(listener);
+        self.listeners.len() - 1
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Expects connections to start with a PROXY protocol header, which
+    /// tells the address of the client. Connections without are closed.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
+    /// Believes the client addresses and schemes the given proxies report in
+    /// `Forwarded`, `X-Forwarded-For` and `X-Forwarded-Proto`.
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: TrustedProxies) {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+    }
+
+    /// Serves HTTPS instead of plain HTTP.
+    #[cfg(feature = "tls")]
+    pub fn set_tls_config(&mut self, tls_config: Arc<rustls::ServerConfig>) {
+        self.tls_config = Some(tls_config);
+    }
+
+    /// The address of the first listener.
     #[cfg(test)]
     pub fn local_addr(&self) -> SocketAddr {
-        self.listener.local_addr().unwrap()
+        self.listeners[0].local_addr().unwrap()
     }
 
+    /// Serves all listeners with `handler`.
+    #[allow(dead_code)]
     pub fn run(&self, handler: impl Handler + Sync) {
-        let read_timeout = Some(Duration::from_secs(10));
+        let handlers = vec![&handler as &(dyn Handler + Sync); self.listeners.len()];
+        self.run_each(&handlers);
+    }
+
+    /// Serves the `i`th listener with the `i`th handler.
+    #[allow(dead_code)]
+    pub fn run_each(&self, handlers: &[&(dyn Handler + Sync)]) {
+        if handlers.len() != self.listeners.len() {
+            error!(
+                listeners = self.listeners.len(),
+                handlers = handlers.len(),
+                "every listener needs a handler"
+            );
+            return;
+        }
+        let serves: Vec<_> = handlers.iter().map(|&h| Serve::Handler(h)).collect();
+        self.serve(&serves);
+    }
+
+    /// Serves all listeners with the current site of `site`, instead of the
+    /// limits and timeouts set on the server. Only the threaded backend picks
+    /// up sites stored later; the others keep serving the first one.
+    pub fn run_site(&self, site: &SiteHandle) {
+        self.serve(&vec![Serve::Site(site); self.listeners.len()]);
+    }
+
+    fn serve(&self, serves: &[Serve]) {
+        match self.backend {
+            Backend::Threaded => self.run_threaded(serves),
+            #[cfg(feature = "tokio")]
+            Backend::Async => self.run_async(serves[0]),
+            Backend::EventLoop => {
+                let Some(listener) = self.single_tcp_listener() else {
+                    return;
+                };
+                let site;
+                let (handler, limits, timeouts) = match serves[0] {
+                    Serve::Handler(handler) => (handler, &self.limits, &self.timeouts),
+                    Serve::Site(handle) => {
+                        site = handle.load();
+                        (&*site.handler as _, &site.limits, &site.timeouts)
+                    }
+                };
+                let res = event_loop::run(
+                    listener,
+                    limits,
+                    timeouts,
+                    &self.conn_counter,
+                    &self.trusted_proxies,
+                    handler,
+                );
+                if let Err(err) = res {
+                    error!(?err);
+                }
+            }
+        }
+    }
+
+    /// The listener of the backends that serve a single TCP listener
+    /// without TLS and the PROXY protocol.
+    fn single_tcp_listener(&self) -> Option<&std::net::TcpListener> {
+        if self.proxy_protocol {
+            error!("the PROXY protocol is only supported by the threaded backend");
+            return None;
+        }
+        #[cfg(feature = "tls")]
+        if self.tls_config.is_some() {
+            error!("TLS is only supported by the threaded backend");
+            return None;
+        }
+        let [Listener::Tcp(listener)] = &self.listeners[..] else {
+            error!("multiple and Unix listeners are only supported by the threaded backend");
+            return None;
+        };
+        Some(listener)
+    }
+
+    /// Serves the site on a new tokio runtime.
+    #[cfg(feature = "tokio")]
+    fn run_async(&self, serve: Serve) {
+        use crate::async_server::{AsyncServer, Blocking};
+
+        let Some(listener) = self.single_tcp_listener() else {
+            return;
+        };
+        // Tasks can't borrow the handler.
+        let Serve::Site(handle) = serve else {
+            error!("the async backend only serves sites, see Server::run_site");
+            return;
+        };
+        let site = handle.load();
+        let res = tokio::runtime::Runtime::new().and_then(|runtime| {
+            runtime.block_on(async {
+                let mut server = AsyncServer::from_std(listener.try_clone()?)?;
+                server.set_limits(site.limits);
+                server.set_timeouts(site.timeouts);
+                server.set_trusted_proxies((*self.trusted_proxies).clone());
+                server.run(Blocking(SharedSite(site))).await;
+                Ok(())
+            })
+        });
+        if let Err(err) = res {
+            error!(?err);
+        }
+    }
+
+    fn run_threaded(&self, serves: &[Serve]) {
         thread::scope(|s| {
-            for stream in self.listener.incoming() {
-                let stream = match stream {
+            for (listener, &serve) in self.listeners.iter().zip(serves) {
+                info!(%listener, "listening");
+                s.spawn(move || self.accept_loop(s, listener, serve));
+            }
+        });
+    }
+
+    fn accept_loop<'s>(
+        &'s self,
+        s: &'s thread::Scope<'s, '_>,
+        listener: &'s Listener,
+        serve: Serve<'s>,
+    ) {
+        loop {
+            let (stream, peer_addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(?err);
+                    continue;
+                }
+            };
+
+            let id = next_conn_id();
+            s.spawn(move || {
+                let span = create_conn_span(id, Ok(peer_addr));
+                let _guard = span.enter();
+                let site;
+                let (handler, limits, timeouts) = match serve {
+                    Serve::Handler(handler) => (handler, &self.limits, &self.timeouts),
+                    Serve::Site(handle) => {
+                        site = handle.load();
+                        (&*site.handler as _, &site.limits, &site.timeouts)
+                    }
+                };
+                let client_addr = match self.read_proxy_header(&stream, timeouts) {
+                    Ok(Some(client_addr)) => {
+                        span.record("client_addr", field::display(client_addr));
+                        PeerAddr::Tcp(client_addr)
+                    }
+                    Ok(None) => peer_addr,
+                    Err(err) => {
+                        debug!(?err, "missing PROXY protocol header");
+                        return;
+                    }
+                };
+                let slot = self.conn_counter.acquire(client_addr, limits.conns_per_ip);
+                let stream = match self.accept_stream(stream, timeouts) {
                     Ok(stream) => stream,
                     Err(err) => {
-                        error!(?err);
-                        continue;
+                        debug!(?err, "handshake failed");
+                        return;
                     }
                 };
+                let Some(_slot) = slot else {
+                    reject_connection(stream);
+                    return;
+                };
+                info!("new conn");
 
-                s.spawn(|| {
-                    let span = create_conn_span(&stream);
-                    let _guard = span.enter();
-                    info!("new conn");
+                let conn = ConnInfo {
+                    id,
+                    peer_addr: Some(peer_addr),
+                    local_addr: stream.local_addr(),
+                    client_addr: client_addr.ip(),
+                    scheme: if stream.is_tls() {
+                        Scheme::Https
+                    } else {
+                        Scheme::Http
+                    },
+                    client_identity: stream.client_identity(),
+                    trusted_proxies: self.trusted_proxies.clone(),
+                };
+                if let Err(err) = handle_connection(stream, &conn, timeouts, limits, handler) {
+                    error!(?err);
+                }
 
-                    if let Err(err) = handle_connection(stream, read_timeout, &handler) {
-                        error!(?err);
-                    }
+                info!("conn end");
+            });
+        }
+    }
 
-                    info!("conn end");
-                });
+    /// Returns the client address of the PROXY protocol header, if expected.
+    fn read_proxy_header(
+        &self,
+        stream: &Stream,
+        timeouts: &Timeouts,
+    ) -> anyhow::Result<Option<SocketAddr>> {
+        if !self.proxy_protocol {
+            return Ok(None);
+        }
+        let mut timed_stream = TimedStream::new(stream);
+        timed_stream.set_timeouts(timeouts.header, Some(timeouts.header_deadline));
+        proxy::read_header(timed_stream)
+    }
+
+    /// Completes the TLS handshake if HTTPS is served. Unix sockets are
+    /// always served in plain text.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn accept_stream(&self, stream: Stream, timeouts: &Timeouts) -> io::Result<Stream> {
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = &self.tls_config {
+            if let Stream::Tcp(stream) = stream {
+                stream.set_read_timeout(Some(timeouts.header))?;
+                let stream = crate::tls::TlsStream::new(stream, tls_config.clone())?;
+                stream.handshake()?;
+                return Ok(Stream::Tls(Box::new(stream)));
             }
+        }
+        Ok(stream)
+    }
+}
+
+/// Counts the open connections per client address.
+#[derive(Debug, Default)]
+pub struct ConnCounter(Mutex<HashMap<IpAddr, usize>>);
+
+impl ConnCounter {
+    /// Returns `None` if the address already has `max` connections open.
+    /// Peers without an IP address aren't limited.
+    pub fn acquire(&self, peer_addr: PeerAddr, max: usize) -> Option<ConnSlot<'_>> {
+        let Some(ip) = peer_addr.ip() else {
+            return Some(ConnSlot {
+                counter: self,
+                ip: None,
+            });
+        };
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnSlot {
+            counter: self,
+            ip: Some(ip),
+        })
+    }
+}
+
+/// An open connection, released on drop.
+pub struct ConnSlot<'a> {
+    counter: &'a ConnCounter,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnSlot<'_> {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut counts = self.counter.0.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ip);
+            }
+        }
+    }
+}
+
+fn reject_connection(stream: Stream) {
+    warn!(peer_addr = ?stream.peer_addr().ok(), "too many connections");
+    let mut w = ResponseWriter::new_empty();
+    w.set_reason_phrase(ReasonPhrase::ServiceUnavailable);
+    w.add_connection_header("close");
+    if let Err(err) = w.write_to(&mut &stream) {
+        debug!(?err);
+    }
+    linger(&stream);
+}
+
+/// Numbers connections for [`Connection::id`].
+pub fn next_conn_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What requests learn about the connection they arrived on.
+#[derive(Debug, Clone, Default)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub peer_addr: Option<PeerAddr>,
+    pub local_addr: Option<SocketAddr>,
+    /// The client, as told by the PROXY protocol or else the peer.
+    pub client_addr: Option<IpAddr>,
+    pub scheme: Scheme,
+    pub client_identity: Option<ClientIdentity>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+}
+
+impl ConnInfo {
+    /// Passes the details on to `r`, the `request_index`th request of the
+    /// connection. The client address and scheme forwarded by trusted
+    /// proxies take precedence.
+    pub fn apply(&self, r: &mut Request, request_index: usize) {
+        r.set_connection(Connection {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            request_index,
         });
+        if let Some(client_identity) = &self.client_identity {
+            r.set_client_identity(client_identity.clone());
+        }
+        let forwarded = self
+            .trusted_proxies
+            .resolve(self.client_addr, r.get_headers());
+        if let Some(client_addr) = forwarded.client_addr.or(self.client_addr) {
+            r.set_client_addr(client_addr);
+        }
+        r.set_scheme(forwarded.scheme.unwrap_or(self.scheme));
     }
 }
 
-#[derive(Debug)]
-enum ConnCtrl {
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConnCtrl {
     KeepAlive,
     Close,
 }
 
 fn handle_connection(
-    stream: TcpStream,
-    read_timeout: Option<Duration>,
-    handler: &impl Handler,
+    stream: Stream,
+    conn: &ConnInfo,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    handler: &(impl Handler + Sync + ?Sized),
 ) -> anyhow::Result<()> {
-    let (reader, mut writer) = (&stream, &stream);
-    reader.set_read_timeout(read_timeout)?;
+    let mut writer = &stream;
 
-    let mut request_reader = RequestReader::new(reader);
+    let mut request_reader = RequestReader::with_limits(TimedStream::new(&stream), *limits);
     let mut reader_buf = String::with_capacity(8 * 1024);
 
-    loop {
-        match handle_request(&mut request_reader, &mut reader_buf, writer, handler) {
+    if stream.alpn_protocol().as_deref() == Some(b"h2") {
+        request_reader.get_mut().set_timeouts(timeouts.idle, None);
+        let res = http2::serve(
+            request_reader.stream_reader(),
+            &stream,
+            http2::PREFACE,
+            None,
+            conn,
+            timeouts,
+            limits,
+            handler,
+        );
+        linger(&stream);
+        return res;
+    }
+
+    let res = loop {
+        match handle_request(
+            &mut request_reader,
+            &mut reader_buf,
+            writer,
+            conn,
+            timeouts,
+            limits,
+            handler,
+        ) {
             Ok(ConnCtrl::KeepAlive) => continue,
-            Ok(ConnCtrl::Close) => return Ok(()),
+            Ok(ConnCtrl::Close) => break Ok(()),
             Err(err) => {
-                if err.downcast_ref::<InvalidRequest>().is_some() {
-                    debug!(?err);
-                    let mut w = ResponseWriter::new_empty();
-                    w.set_reason_phrase(ReasonPhrase::BadRequest);
-                    writer.write_all(&w.write())?;
-                    return Ok(());
-                }
-                return Err(err);
+                let Some(reason_phrase) = error_reason_phrase(&err) else {
+                    return Err(err);
+                };
+                debug!(?err);
+                let mut w = ResponseWriter::new_empty();
+                w.set_reason_phrase(reason_phrase);
+                w.add_connection_header("close");
+                break w.write_to(&mut writer).map_err(Into::into);
             }
         }
+    };
+
+    linger(&stream);
+    res
+}
+
+/// Returns the status of the response to a request that failed with `err`,
+/// or `None` if the connection is closed without a response.
+pub fn error_reason_phrase(err: &anyhow::Error) -> Option<ReasonPhrase> {
+    if err.is::<InvalidRequest>() {
+        Some(ReasonPhrase::BadRequest)
+    } else if err.is::<UnsupportedTransferCoding>() {
+        Some(ReasonPhrase::NotImplemented)
+    } else if err.is::<UnsupportedVersion>() {
+        Some(ReasonPhrase::HTTPVersionNotSupported)
+    } else if let Some(limit_exceeded) = err.downcast_ref::<LimitExceeded>() {
+        Some(limit_exceeded.reason_phrase())
+    } else if err.is::<RequestTimeout>() {
+        warn!("request timeout");
+        Some(ReasonPhrase::RequestTimeout)
+    } else {
+        None
+    }
+}
+
+/// Closes the sending side and drains what the client still sends, so that
+/// unread requests don't make the peer reset the connection before it has
+/// read the last response.
+fn linger(stream: &Stream) {
+    const MAX_LINGER: usize = 256 * 1024;
+    const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+
+    if stream.shutdown_write().is_err() {
+        return;
+    }
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut buf = [0; 4096];
+    let mut total = 0;
+    while total < MAX_LINGER {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => total += n,
+        }
     }
 }
 
 fn handle_request(
-    request_reader: &mut RequestReader<&TcpStream>,
+    request_reader: &mut RequestReader<TimedStream>,
     reader_buf: &mut String,
-    mut writer: &TcpStream,
-    handler: &impl Handler,
+    mut writer: &Stream,
+    conn: &ConnInfo,
+    timeouts: &Timeouts,
+    limits: &Limits,
+    handler: &(impl Handler + Sync + ?Sized),
 ) -> anyhow::Result<ConnCtrl> {
+    request_reader.get_mut().set_timeouts(timeouts.idle, None);
+    match request_reader.wait_for_request() {
+        Ok(true) => {}
+        Ok(false) => return Ok(ConnCtrl::Close),
+        Err(err) if is_timeout(&err) => {
+            debug!("idle timeout");
+            return Ok(ConnCtrl::Close);
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    request_reader
+        .get_mut()
+        .set_timeouts(timeouts.header, Some(timeouts.header_deadline));
+    reader_buf.clear();
     let request_line_end = match request_reader.read_metadata(reader_buf) {
         Ok(request_line_end) => request_line_end,
         Err(err) => {
-            if err.downcast_ref::<EndOfFile>().is_some() {
+            if request_reader.get_ref().timed_out() {
+                Err(RequestTimeout)?
+            }
+            if err.is::<EndOfFile>() {
                 return Ok(ConnCtrl::Close);
             }
+            if err.is::<LimitExceeded>() {
+                return Err(err);
+            }
             Err(InvalidRequest)?
         }
     };
 
+    // prior knowledge (RFC 9113, section 3.3)
+    if http2::PREFACE.starts_with(reader_buf.as_bytes()) {
+        request_reader.get_mut().set_timeouts(timeouts.idle, None);
+        http2::serve(
+            request_reader.stream_reader(),
+            writer,
+            &http2::PREFACE[reader_buf.len()..],
+            None,
+            conn,
+            timeouts,
+            limits,
+            handler,
+        )?;
+        return Ok(ConnCtrl::Close);
+    }
+
+    let request_count = request_reader.request_count();
+    let mut r = parse_head(reader_buf, request_line_end)?;
+    conn.apply(&mut r, request_count - 1);
+    let http_version = r.get_http_version();
+
+    let span = create_req_span(&r);
+    let _guard = span.enter();
+
+    let pipeline_depth = request_reader.pipeline_depth();
+    let framing = Framing::parse(r.get_headers())?;
+    // Requests with a body aren't upgraded, as it would have to be read first.
+    let upgrade = if writer.is_tls() || framing != Framing::None {
+        None
+    } else {
+        http2::h2c_upgrade(&r)
+    };
+    if let Some(upgrade) = upgrade {
+        info!(?r, "h2c upgrade");
+        drop(r);
+        let mut w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::SwitchingProtocols);
+        w.add_connection_header("Upgrade");
+        w.add_upgrade_header("h2c");
+        w.write_to(&mut writer)?;
+
+        request_reader.get_mut().set_timeouts(timeouts.idle, None);
+        http2::serve(
+            request_reader.stream_reader(),
+            writer,
+            http2::PREFACE,
+            Some(upgrade),
+            conn,
+            timeouts,
+            limits,
+            handler,
+        )?;
+        return Ok(ConnCtrl::Close);
+    }
+
+    match check_expectation(&r, framing, limits, |r| handler.check_request(r)) {
+        Ok(false) => {}
+        Ok(true) => {
+            let mut w = ResponseWriter::new_empty();
+            w.set_reason_phrase(ReasonPhrase::Continue);
+            w.write_to(&mut writer)?;
+        }
+        Err(reason_phrase) => {
+            // The client may or may not send the body, so the connection
+            // cannot be reused.
+            info!(?r, ?reason_phrase, "expectation rejected");
+            let mut w = ResponseWriter::new_empty();
+            w.set_http_version(http_version);
+            w.set_reason_phrase(reason_phrase);
+            w.add_connection_header("close");
+            w.write_to(&mut writer)?;
+            return Ok(ConnCtrl::Close);
+        }
+    }
+
+    let timed_stream = request_reader.get_mut();
+    timed_stream.set_timeouts(timeouts.body, Some(timeouts.body_deadline));
+    timed_stream.set_min_rate(timeouts.min_body_rate);
+    if let Some(body) = request_reader.read_body(framing)? {
+        r.set_body_reader(Box::new(body));
+    }
+    info!(?r);
+
+    let mut conn_ctrl = request_conn_ctrl(&r, request_count, pipeline_depth, limits);
+
+    let mut w = ResponseWriter::new_empty();
+    handler.handle(&mut w, &mut r);
+    let param = r.get_param().map(str::to_owned);
+    drop(r);
+
+    if request_reader.get_ref().timed_out() {
+        warn!("request timeout while reading the body");
+        w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::RequestTimeout);
+        conn_ctrl = ConnCtrl::Close;
+    }
+
+    // Only requests without a body are upgraded, so that the connection
+    // carries nothing but the new protocol afterwards.
+    if http_version == HttpVersion::Http11 && framing == Framing::None {
+        if let Some(on_upgrade) = w.take_upgrade() {
+            w.write_to(&mut writer)?;
+            info!("connection upgraded");
+
+            // The request borrowed the buffer the connection is read with.
+            let mut r = parse_head(reader_buf, request_line_end)?;
+            conn.apply(&mut r, request_count - 1);
+            if let Some(param) = &param {
+                r.set_param(param);
+            }
+            request_reader
+                .get_mut()
+                .set_timeouts(timeouts.upgraded, None);
+            let conn = Upgraded {
+                reader: request_reader.stream_reader(),
+                writer,
+            };
+            on_upgrade.call(&r, conn);
+            return Ok(ConnCtrl::Close);
+        }
+    }
+
+    // The response tells whether the connection stays open, which depends
+    // on getting past the unread body.
+    match request_reader.discard_body() {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!("unread body too large to discard");
+            conn_ctrl = ConnCtrl::Close;
+        }
+        Err(err) if is_timeout(&err) => {
+            warn!("request timeout while discarding the body");
+            conn_ctrl = ConnCtrl::Close;
+        }
+        Err(err) => {
+            debug!(?err, "cannot discard the body");
+            conn_ctrl = ConnCtrl::Close;
+        }
+    }
+
+    let conn_ctrl = finish_response(
+        &mut w,
+        http_version,
+        conn_ctrl,
+        request_count,
+        timeouts,
+        limits,
+    );
+    w.write_to(&mut writer)?;
+    Ok(conn_ctrl)
+}
+
+/// Parses the request line and headers in `reader_buf` into a request
+/// without a body.
+pub fn parse_head(reader_buf: &mut str, request_line_end: usize) -> anyhow::Result<Request<'_>> {
     let (_, headers_raw) = reader_buf.split_at_mut(request_line_end);
     make_keys_lowercase(unsafe { headers_raw.as_bytes_mut() });
-    let request_line = RequestLine::parse(&reader_buf[..request_line_end])?;
+    let request_line = RequestLine::parse(&reader_buf[..request_line_end]).map_err(|err| {
+        if err.is::<UnsupportedVersion>() {
+            err
+        } else {
+            InvalidRequest.into()
+        }
+    })?;
     info!(?request_line);
     let headers = Headers::parse(&reader_buf[request_line_end..]).map_err(|_| InvalidRequest)?;
-    let body = request_reader.read_body(&request_line, &headers)?;
-    let mut r = Request::new(request_line, None, headers, body);
+    Ok(Request::new(request_line, None, headers, None))
+}
 
-    let span = create_req_span(&r);
-    let _guard = span.enter();
-    info!(?r);
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Decides whether the connection is kept open after answering the
+/// `request_count`th request, received at `pipeline_depth`.
+pub fn request_conn_ctrl(
+    r: &Request,
+    request_count: usize,
+    pipeline_depth: usize,
+    limits: &Limits,
+) -> ConnCtrl {
+    if pipeline_depth >= limits.pipeline_depth {
+        debug!("pipeline depth limit reached");
+        return ConnCtrl::Close;
+    }
+    if request_count >= limits.max_requests {
+        debug!("max requests reached");
+        return ConnCtrl::Close;
+    }
+    negotiate_conn_ctrl(r)
+}
+
+/// Adds the connection management headers to the response of the handler
+/// and returns whether the connection is kept open.
+pub fn finish_response(
+    w: &mut ResponseWriter,
+    http_version: HttpVersion,
+    mut conn_ctrl: ConnCtrl,
+    request_count: usize,
+    timeouts: &Timeouts,
+    limits: &Limits,
+) -> ConnCtrl {
+    if w.take_upgrade().is_some() {
+        warn!("protocol upgrade not possible on this connection");
+        *w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotImplemented);
+    }
+
+    let forced_close = w
+        .get_connection_header()
+        .is_some_and(|v| v.split(',').any(|o| o.trim().eq_ignore_ascii_case("close")));
+    if forced_close {
+        conn_ctrl = ConnCtrl::Close;
+    }
+
+    // Without chunked transfer coding, a streamed HTTP/1.0 body ends with
+    // the connection.
+    if http_version == HttpVersion::Http10 && w.get_body_len().is_none() {
+        conn_ctrl = ConnCtrl::Close;
+    }
+    w.set_http_version(http_version);
+    match (http_version, conn_ctrl) {
+        (HttpVersion::Http10, ConnCtrl::KeepAlive) => w.add_connection_header("keep-alive"),
+        (HttpVersion::Http11, ConnCtrl::Close) => w.add_connection_header("close"),
+        _ => {}
+    }
+    if conn_ctrl == ConnCtrl::KeepAlive {
+        w.add_keep_alive_header(timeouts.idle, limits.max_requests - request_count);
+    }
+    conn_ctrl
+}
 
-    let conn_ctrl = match r
+/// Event streams don't end, so backends buffering whole responses answer
+/// them with `501 Not Implemented` instead.
+pub fn reject_event_stream(w: &mut ResponseWriter) {
+    if w.is_event_stream() {
+        warn!("event streams are only supported by the threaded backend");
+        *w = ResponseWriter::new_empty();
+        w.set_reason_phrase(ReasonPhrase::NotImplemented);
+    }
+}
+
+/// Decides whether the connection is kept open after the response.
+/// HTTP/1.1 defaults to persistent connections, HTTP/1.0 only persists on
+/// request (RFC 9112, section 9.3).
+fn negotiate_conn_ctrl(r: &Request) -> ConnCtrl {
+    let options: Vec<_> = r
         .get_headers()
         .get_connection()
-        .map(|mut it| it.any(|val| val == "close"))
-    {
-        Some(true) => ConnCtrl::Close,
-        _ => ConnCtrl::KeepAlive,
+        .map(|it| it.collect())
+        .unwrap_or_default();
+    let has_option = |option: &str| options.iter().any(|o| o.eq_ignore_ascii_case(option));
+
+    match r.get_http_version() {
+        _ if has_option("close") => ConnCtrl::Close,
+        HttpVersion::Http10 if has_option("keep-alive") => ConnCtrl::KeepAlive,
+        HttpVersion::Http10 => ConnCtrl::Close,
+        HttpVersion::Http11 | HttpVersion::Http2 => ConnCtrl::KeepAlive,
+    }
+}
+
+/// Decides how to answer the `Expect` header of `r` before its body is read.
+/// Returns whether an interim `100 Continue` response has to be sent.
+/// `check_request` is the handler's [`Handler::check_request`].
+pub fn check_expectation(
+    r: &Request,
+    framing: Framing,
+    limits: &Limits,
+    check_request: impl FnOnce(&Request) -> Result<(), ReasonPhrase>,
+) -> Result<bool, ReasonPhrase> {
+    // HTTP/1.0 clients don't know about interim responses (RFC 9110,
+    // section 10.1.1).
+    if r.get_http_version() == HttpVersion::Http10 {
+        return Ok(false);
+    }
+    let Some(expect) = r
+        .get_headers()
+        .get_expect()
+        .map_err(|_| ReasonPhrase::BadRequest)?
+    else {
+        return Ok(false);
     };
+    if !expect.eq_ignore_ascii_case("100-continue") {
+        return Err(ReasonPhrase::ExpectationFailed);
+    }
 
-    let mut w = ResponseWriter::new_empty();
-    handler.handle(&mut w, &mut r);
-    let response = w.write();
-    writer.write_all(&response)?;
-    Ok(conn_ctrl)
+    match framing {
+        Framing::None | Framing::Length(0) => return Ok(false),
+        Framing::Length(length) if length > limits.body_size => {
+            return Err(ReasonPhrase::ContentTooLarge)
+        }
+        Framing::Length(_) | Framing::Chunked => {}
+    }
+
+    check_request(r)?;
+    Ok(true)
 }
 
-fn create_conn_span(stream: &TcpStream) -> Span {
-    let peer_addr = match stream.peer_addr() {
+pub fn create_conn_span(id: u64, peer_addr: io::Result<PeerAddr>) -> Span {
+    let peer_addr = match peer_addr {
         Ok(addr) => &addr.to_string(),
         Err(err) => {
             error!(?err);
@@ -179,22 +1025,44 @@ fn create_conn_span(stream: &TcpStream) -> Span {
         }
     };
 
-    span!(Level::INFO, "conn", peer_addr)
+    span!(
+        Level::INFO,
+        "conn",
+        id,
+        peer_addr,
+        client_addr = field::Empty
+    )
 }
 
-fn create_req_span(r: &Request) -> Span {
+pub fn create_req_span(r: &Request) -> Span {
     let http_method = r.get_http_method();
     let request_target = r.get_request_target();
+    let client_addr = r.get_client_addr().map(field::display);
     span!(
         Level::INFO,
         "req",
         method = http_method,
-        target = request_target
+        target = request_target,
+        client_addr
     )
 }
 
+/// A connection taken over with [`ResponseWriter::set_upgrade`].
+pub struct Upgraded<'a> {
+    /// Also yields what the client sent right after the request.
+    pub reader: &'a mut (dyn Read + Send + 'a),
+    pub writer: &'a Stream,
+}
+
 pub trait Handler {
     fn handle(&self, w: &mut ResponseWriter, r: &mut Request);
+
+    /// Called with the request metadata before the body of a request with
+    /// `Expect: 100-continue` is read. Returning an error rejects the request
+    /// with that status instead of asking the client for the body.
+    fn check_request(&self, _r: &Request) -> Result<(), ReasonPhrase> {
+        Ok(())
+    }
 }
 
 impl<T> Handler for T
@@ -214,34 +1082,50 @@ pub fn noop_handler() -> impl Handler {
 #[cfg(test)]
 pub mod tests {
     use std::{
-        io::{BufReader, Read, Write},
-        net::{TcpListener, TcpStream},
+        io::{self, BufReader, Cursor, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
         thread,
         time::Duration,
     };
 
     use crate::{
-        request::Request, response_writer::ResponseWriter, status_code_registry::ReasonPhrase,
+        request::{Limits, Request},
+        response_writer::ResponseWriter,
+        router::Router,
+        status_code_registry::ReasonPhrase,
     };
 
-    use super::{handle_connection, noop_handler, Server};
+    use super::{
+        handle_connection, noop_handler, ConnInfo, HttpMethod, Server, Site, SiteHandle, Timeouts,
+    };
 
     #[test]
     fn test_request_reader_timeout() {
-        let timeout = Some(Duration::from_millis(100));
+        let timeouts = Timeouts {
+            header: Duration::from_millis(100),
+            ..Default::default()
+        };
 
         let listener = TcpListener::bind("localhost:0").unwrap();
         let addr = listener.local_addr().unwrap();
 
         let server_handle = thread::spawn(move || {
             let (stream, _) = listener.accept().unwrap();
-            handle_connection(stream, timeout, &noop_handler())
+            handle_connection(
+                stream.into(),
+                &ConnInfo::default(),
+                &timeouts,
+                &Limits::default(),
+                &noop_handler(),
+            )
         });
 
         let _client_handle = thread::spawn(move || {
             let mut stream = TcpStream::connect(addr).unwrap();
             stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
-            loop {}
+            loop {
+                thread::sleep(Duration::from_secs(1));
+            }
         });
 
         server_handle.join().unwrap().unwrap();
@@ -251,7 +1135,7 @@ pub mod tests {
     fn test_persistent_connection() {
         let timeout = Some(Duration::from_millis(100));
 
-        let server = Server::new("localhost:0");
+        let server = Server::bind("localhost:0").unwrap();
         let addr = server.local_addr();
 
         thread::spawn(move || {
@@ -274,4 +1158,693 @@ pub mod tests {
         let res = reader.read_to_end(&mut buf);
         res.unwrap_err();
     }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // expect
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    fn start_upload_server() -> TcpStream {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            let upload = |w: &mut ResponseWriter, r: &mut Request| {
+                let body = r.read_body().unwrap().unwrap().to_vec();
+                w.set_body(body, "text/plain");
+                w.set_reason_phrase(ReasonPhrase::OK);
+            };
+            let mut router = Router::new();
+            router.add_route(HttpMethod::Post, "/upload", &upload);
+            server.run(router);
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+    }
+
+    fn read_response(stream: &mut TcpStream) -> String {
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let mut stream = start_upload_server();
+        stream
+            .write_all(
+                b"POST /upload HTTP/1.1\r\n\
+                  Content-Length: 5\r\n\
+                  Expect: 100-continue\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let want = "HTTP/1.1 100 Continue\r\n\r\n";
+        let mut buf = vec![0; want.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, want.as_bytes());
+
+        stream.write_all(b"hello").unwrap();
+        let resp = read_response(&mut stream);
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_expect_continue_rejected() {
+        let tests = [
+            (
+                "/missing",
+                "100-continue",
+                "5",
+                "HTTP/1.1 404 Not Found\r\n",
+            ),
+            (
+                "/upload",
+                "100-continue",
+                "1000000000",
+                "HTTP/1.1 413 Content Too Large\r\n",
+            ),
+            (
+                "/upload",
+                "something-else",
+                "5",
+                "HTTP/1.1 417 Expectation Failed\r\n",
+            ),
+        ];
+
+        for (target, expect, content_length, want) in tests {
+            let mut stream = start_upload_server();
+            let req = format!(
+                "POST {} HTTP/1.1\r\nContent-Length: {}\r\nExpect: {}\r\n\r\n",
+                target, content_length, expect
+            );
+            stream.write_all(req.as_bytes()).unwrap();
+
+            // The server answers without waiting for the body and closes the connection.
+            let resp = read_response(&mut stream);
+            assert!(resp.starts_with(want), "{}", resp);
+        }
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // body
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    #[test]
+    fn test_unread_body_is_discarded() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                w.set_body_str(r.get_request_target());
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+            .write_all(
+                b"POST /fst HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                  GET /snd HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let resp = read_response(&mut stream);
+        assert!(resp.ends_with("\r\n\r\n/snd"), "{}", resp);
+        assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn test_unread_body_too_large() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, _: &mut Request| {
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\nhello")
+            .unwrap();
+
+        let resp = read_response(&mut stream);
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+        assert!(!resp.contains("Keep-Alive"), "{}", resp);
+    }
+
+    #[test]
+    fn test_body_framing() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                let body = r.read_body().unwrap().unwrap_or_default().to_vec();
+                w.set_body_str(&format!("{} {}", r.get_request_target(), body.len()));
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let tests: [(&[u8], &str); 3] = [
+            // a GET body must not be parsed as the next request
+            (
+                b"GET /fst HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+                "/fst 5",
+            ),
+            (
+                b"PUT /fst HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+                "/fst 5",
+            ),
+            (b"DELETE /fst HTTP/1.1\r\n\r\n", "/fst 0"),
+        ];
+
+        for (req, want) in tests {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            stream.write_all(req).unwrap();
+            stream
+                .write_all(b"GET /snd HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            let resp = read_response(&mut stream);
+            assert!(resp.contains(&format!("\r\n\r\n{}HTTP", want)), "{}", resp);
+            assert!(resp.ends_with("\r\n\r\n/snd 0"), "{}", resp);
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_framing() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || server.run(noop_handler()));
+
+        let tests: [(&[u8], &str); 3] = [
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                "HTTP/1.1 501 Not Implemented",
+            ),
+        ];
+
+        for (req, want) in tests {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            stream.write_all(req).unwrap();
+            let resp = read_response(&mut stream);
+            assert!(resp.starts_with(want), "{}", resp);
+        }
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // pipelining
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    fn send(addr: SocketAddr, req: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        stream.write_all(req).unwrap();
+        read_response(&mut stream)
+    }
+
+    fn start_echo_target_server(limits: Limits) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_limits(limits);
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                w.set_body_str(r.get_request_target());
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+        addr
+    }
+
+    fn response_bodies(resp: &str) -> Vec<&str> {
+        resp.split("HTTP/1.1 ")
+            .skip(1)
+            .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let addr = start_echo_target_server(Limits::default());
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              POST /snd HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              GET /trd HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(response_bodies(&resp), ["/fst", "/snd", "/trd"]);
+    }
+
+    #[test]
+    fn test_pipelining_depth_limit() {
+        let addr = start_echo_target_server(Limits {
+            pipeline_depth: 2,
+            ..Default::default()
+        });
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              GET /snd HTTP/1.1\r\n\r\n\
+              GET /trd HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(response_bodies(&resp), ["/fst", "/snd"]);
+        assert_eq!(resp.matches("Connection: close\r\n").count(), 1);
+    }
+
+    #[test]
+    fn test_pipelining_malformed_request() {
+        let addr = start_echo_target_server(Limits::default());
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              GET /snd\r\n\r\n\
+              GET /trd HTTP/1.1\r\n\r\n",
+        );
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(
+            resp.contains("\r\n\r\n/fstHTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            resp
+        );
+        assert_eq!(resp.matches("HTTP/1.1 ").count(), 2, "{}", resp);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // keep-alive
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    #[test]
+    fn test_max_requests() {
+        let addr = start_echo_target_server(Limits {
+            max_requests: 2,
+            ..Default::default()
+        });
+        let resp = send(
+            addr,
+            b"GET /fst HTTP/1.1\r\n\r\n\
+              GET /snd HTTP/1.1\r\n\r\n\
+              GET /trd HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(response_bodies(&resp), ["/fst", "/snd"]);
+
+        let (fst, snd) = resp.split_once("/fst").unwrap();
+        assert!(fst.contains("Keep-Alive: timeout=5, max=1\r\n"), "{}", fst);
+        assert!(snd.contains("Connection: close\r\n"), "{}", snd);
+        assert!(!snd.contains("Keep-Alive"), "{}", snd);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_timeouts(Timeouts {
+            idle: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let addr = server.local_addr();
+
+        thread::spawn(move || server.run(noop_handler()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = vec![];
+        // The server closes the connection before the client times out.
+        stream.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_handler_forces_close() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                w.set_body_str(r.get_request_target());
+                w.set_reason_phrase(ReasonPhrase::OK);
+                w.add_connection_header("close");
+            });
+        });
+
+        let resp = send(addr, b"GET /fst HTTP/1.1\r\n\r\nGET /snd HTTP/1.1\r\n\r\n");
+        assert_eq!(response_bodies(&resp), ["/fst"]);
+        assert!(!resp.contains("Keep-Alive"), "{}", resp);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // slow clients
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    fn start_slow_client_server(timeouts: Timeouts) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_timeouts(timeouts);
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                if r.read_body().is_ok() {
+                    w.set_reason_phrase(ReasonPhrase::OK);
+                } else {
+                    w.set_reason_phrase(ReasonPhrase::BadRequest);
+                }
+            });
+        });
+        addr
+    }
+
+    /// Sends `head` and then trickles one byte of `filler` at a time.
+    fn send_slowly(addr: SocketAddr, head: &'static [u8], filler: &'static [u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            writer.write_all(head)?;
+            for b in filler.iter().cycle() {
+                writer.write_all(&[*b])?;
+                thread::sleep(Duration::from_millis(50));
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        read_response(&mut stream)
+    }
+
+    #[test]
+    fn test_header_deadline() {
+        let addr = start_slow_client_server(Timeouts {
+            header_deadline: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let resp = send_slowly(addr, b"GET / HTTP/1.1\r\n", b"x-slow: loris\r\n");
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_body_deadline() {
+        let addr = start_slow_client_server(Timeouts {
+            body_deadline: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let resp = send_slowly(
+            addr,
+            b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n",
+            b"x",
+        );
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_min_body_rate() {
+        let addr = start_slow_client_server(Timeouts {
+            min_body_rate: 100,
+            ..Default::default()
+        });
+        let resp = send_slowly(
+            addr,
+            b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n",
+            b"x",
+        );
+        assert!(
+            resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_conns_per_ip() {
+        let addr = start_echo_target_server(Limits {
+            conns_per_ip: 1,
+            ..Default::default()
+        });
+
+        let mut fst = TcpStream::connect(addr).unwrap();
+        fst.write_all(b"GET /fst HTTP/1.1\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        let n = fst.read(&mut buf).unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        let resp = send(addr, b"GET /snd HTTP/1.1\r\n\r\n");
+        assert!(
+            resp.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            resp
+        );
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                let conn = r.get_connection();
+                let peer_addr = conn.peer_addr.unwrap();
+                let local_addr = conn.local_addr.unwrap();
+                w.set_body_str(&format!(
+                    "{} {} {} {} {}",
+                    conn.id,
+                    conn.request_index,
+                    peer_addr,
+                    local_addr,
+                    r.get_scheme()
+                ));
+                w.set_reason_phrase(ReasonPhrase::OK);
+            })
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let client_addr = stream.local_addr().unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        let bodies = response_bodies(&resp);
+        let fields: Vec<Vec<_>> = bodies.iter().map(|b| b.split(' ').collect()).collect();
+        assert_eq!(fields.len(), 2, "{}", resp);
+        assert_eq!(fields[0][0], fields[1][0]);
+        assert_eq!([fields[0][1], fields[1][1]], ["0", "1"]);
+        for fields in &fields {
+            assert_eq!(
+                fields[2..],
+                [&client_addr.to_string(), &addr.to_string(), "http"]
+            );
+        }
+
+        // another connection
+        let resp = send(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let id = response_bodies(&resp)[0].split(' ').next().unwrap();
+        assert_ne!(id, fields[0][0]);
+    }
+
+    fn new_site(body: &'static str, limits: Limits) -> Site {
+        Site {
+            handler: Box::new(move |w: &mut ResponseWriter, _: &mut Request| {
+                w.set_body_str(body);
+                w.set_reason_phrase(ReasonPhrase::OK);
+            }),
+            limits,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    #[test]
+    fn test_replace_site() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+        let site = SiteHandle::new(new_site("old", Limits::default()));
+        let handle = site.clone();
+        thread::spawn(move || server.run_site(&handle));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut resp = vec![];
+        while !resp.ends_with(b"old") {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0);
+            resp.extend_from_slice(&buf[..n]);
+        }
+
+        let limits = Limits {
+            max_requests: 1,
+            ..Default::default()
+        };
+        site.store(new_site("new", limits));
+        let resp = send(addr, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(response_bodies(&resp), ["new"]);
+        assert!(resp.contains("Connection: close"), "{}", resp);
+
+        // the open connection keeps the old site
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let resp = read_response(&mut stream);
+        assert_eq!(response_bodies(&resp), ["old"]);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // http versions
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    #[test]
+    fn test_http_versions() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                if r.get_request_target() == "/stream" {
+                    w.set_body_reader(Cursor::new("streamed"), "text/plain");
+                } else {
+                    w.set_body_str("ok");
+                }
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        // HTTP/1.0 closes by default
+        let resp = send(addr, b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+        assert_eq!(
+            resp,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nok"
+        );
+
+        // HTTP/1.0 keep-alive
+        let resp = send(
+            addr,
+            b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        );
+        assert_eq!(resp.matches("HTTP/1.0 200 OK").count(), 2, "{}", resp);
+        assert_eq!(resp.matches("Connection: keep-alive\r\n").count(), 1);
+
+        // HTTP/1.0 streamed body is delimited by closing the connection
+        let resp = send(
+            addr,
+            b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+        );
+        assert_eq!(
+            resp,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nstreamed"
+        );
+
+        // HTTP/1.1 announces close
+        let resp = send(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("Connection: close\r\n"), "{}", resp);
+
+        // higher minor versions are answered with HTTP/1.1
+        let resp = send(addr, b"GET / HTTP/1.9\r\nConnection: close\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+
+        let resp = send(addr, b"GET / HTTP/2.0\r\n\r\n");
+        assert!(
+            resp.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            "{}",
+            resp
+        );
+
+        let resp = send(addr, b"GET / HTTP/x\r\n\r\n");
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+    }
+
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+    // limits
+    // - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+    #[test]
+    fn test_limits() {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_limits(Limits {
+            request_line: 64,
+            header_bytes: 64,
+            header_count: 4,
+            body_size: 64,
+            ..Default::default()
+        });
+        let addr = server.local_addr();
+
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, _: &mut Request| {
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let tests = [
+            (
+                format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64)),
+                "HTTP/1.1 414 URI Too Long\r\n",
+            ),
+            (
+                format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(64)),
+                "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+            ),
+            (
+                "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\nd: 4\r\ne: 5\r\n\r\n".to_owned(),
+                "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 65\r\n\r\n".to_owned(),
+                "HTTP/1.1 413 Content Too Large\r\n",
+            ),
+        ];
+
+        for (req, want) in tests {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            stream.write_all(req.as_bytes()).unwrap();
+            let resp = read_response(&mut stream);
+            assert!(resp.starts_with(want), "{}", resp);
+        }
+    }
 }
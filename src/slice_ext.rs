@@ -21,7 +21,7 @@ impl<'a, 'b, T> SplitPatternMut<'a, 'b, T> {
 impl<'a, 'b, T: PartialEq> Iterator for SplitPatternMut<'a, 'b, T> {
     type Item = &'a mut [T];
     fn next(&mut self) -> Option<Self::Item> {
-        let Some(s) = self.s.take() else { return None };
+        let s = self.s.take()?;
 
         let Some(pos) = position_pattern(s, self.p) else {
             return Some(s);
@@ -35,9 +35,9 @@ impl<'a, 'b, T: PartialEq> Iterator for SplitPatternMut<'a, 'b, T> {
 }
 
 /// Like `str::split_once` but returns mutable references.
-pub fn split_once_mut<'a, 'b, T: PartialEq>(
+pub fn split_once_mut<'a, T: PartialEq>(
     me: &'a mut [T],
-    pattern: &'b [T],
+    pattern: &[T],
 ) -> Option<(&'a mut [T], &'a mut [T])> {
     let (left, mut right) = me.split_at_mut(position_pattern(me, pattern)?);
     right = &mut right[pattern.len()..];
@@ -0,0 +1,248 @@
+//! Server-sent events (HTML Living Standard, section 9.2).
+
+use std::{
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+/// How many encoded events are queued before sending blocks.
+const CAPACITY: usize = 64;
+
+#[derive(Error, Debug)]
+#[error("event stream closed")]
+pub struct Closed;
+
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self {
+            data: data.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Sent back by reconnecting clients as `Last-Event-ID`.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = Some(strip_line_breaks(id).replace('\0', ""));
+    }
+
+    /// The event type, `message` if unset.
+    #[allow(dead_code)]
+    pub fn set_event(&mut self, event: &str) {
+        self.event = Some(strip_line_breaks(event));
+    }
+
+    /// How long clients wait before reconnecting.
+    pub fn set_retry(&mut self, retry: Duration) {
+        self.retry = Some(retry);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = String::new();
+        if let Some(id) = &self.id {
+            buf.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(event) = &self.event {
+            buf.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(retry) = self.retry {
+            buf.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        let data = self.data.replace("\r\n", "\n").replace('\r', "\n");
+        for line in data.split('\n') {
+            buf.push_str(&format!("data: {}\n", line));
+        }
+        buf.push('\n');
+        buf.into_bytes()
+    }
+}
+
+fn strip_line_breaks(s: &str) -> String {
+    s.replace(['\r', '\n'], "")
+}
+
+fn encode_comment(comment: &str) -> Vec<u8> {
+    let comment = comment.replace("\r\n", "\n").replace('\r', "\n");
+    let mut buf: String = comment
+        .split('\n')
+        .map(|line| format!(": {}\n", line))
+        .collect();
+    buf.push('\n');
+    buf.into_bytes()
+}
+
+/// Pushes events into an [`EventStream`]. Sending fails once the client is
+/// gone.
+#[derive(Debug, Clone)]
+pub struct EventSender(SyncSender<Vec<u8>>);
+
+impl EventSender {
+    pub fn send(&self, event: &Event) -> Result<(), Closed> {
+        self.0.send(event.encode()).map_err(|_| Closed)
+    }
+
+    #[allow(dead_code)]
+    pub fn send_comment(&self, comment: &str) -> Result<(), Closed> {
+        self.0.send(encode_comment(comment)).map_err(|_| Closed)
+    }
+}
+
+/// A `text/event-stream` body, ending once all senders are dropped.
+#[derive(Debug)]
+pub struct EventStream {
+    receiver: Receiver<Vec<u8>>,
+    heartbeat: Duration,
+    pending: Vec<u8>,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.receiver.recv_timeout(self.heartbeat) {
+                Ok(event) => event,
+                // keeps proxies from closing the idle connection
+                Err(RecvTimeoutError::Timeout) => encode_comment("heartbeat"),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+/// Creates an event stream and its sender. A heartbeat comment is sent
+/// whenever no event was sent for `heartbeat`.
+pub fn channel(heartbeat: Duration) -> (EventSender, EventStream) {
+    let (sender, receiver) = mpsc::sync_channel(CAPACITY);
+    let stream = EventStream {
+        receiver,
+        heartbeat,
+        pending: vec![],
+    };
+    (EventSender(sender), stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpStream,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use reqwest::header;
+
+    use crate::{
+        middleware::gzip_compressor, request::Request, response_writer::ResponseWriter,
+        router::Router, server::HttpMethod, server::Server, status_code_registry::ReasonPhrase,
+        ticks,
+    };
+
+    use super::{channel, Event};
+
+    #[test]
+    fn test_encode() {
+        let mut event = Event::new("first\nsecond\r\nthird");
+        event.set_id("4\n2");
+        event.set_event("update");
+        event.set_retry(Duration::from_secs(3));
+        assert_eq!(
+            String::from_utf8(event.encode()).unwrap(),
+            "id: 42\nevent: update\nretry: 3000\ndata: first\ndata: second\ndata: third\n\n"
+        );
+        assert_eq!(Event::new("").encode(), b"data: \n\n");
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let (sender, mut stream) = channel(Duration::from_millis(10));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(&Event::new("hi")).unwrap();
+        });
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert!(body.starts_with(": heartbeat\n\n"));
+        assert!(body.ends_with(": heartbeat\n\ndata: hi\n\n"));
+    }
+
+    fn events(w: &mut ResponseWriter, _: &mut Request) {
+        let (sender, stream) = channel(Duration::from_secs(10));
+        thread::spawn(move || {
+            sender.send(&Event::new("first")).unwrap();
+            thread::sleep(Duration::from_secs(5));
+            let _ = sender.send_comment("bye");
+        });
+        w.set_event_stream(stream);
+        w.set_reason_phrase(ReasonPhrase::OK);
+    }
+
+    #[test]
+    fn test_not_buffered() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            let events = gzip_compressor::new(events);
+            let mut router = Router::new();
+            router.add_route(HttpMethod::Get, "/events", &events);
+            server.run(router);
+        });
+
+        let resp = reqwest::blocking::Client::new()
+            .get(format!("http://{}/events", addr))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-cache");
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+
+        // arrives long before the stream ends
+        let start = Instant::now();
+        let mut reader = BufReader::new(resp);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "data: first\n");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let server = Server::bind("localhost:0").unwrap();
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            let mut router = Router::new();
+            router.add_route(HttpMethod::Get, "/ticks", &ticks);
+            server.run(router);
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /ticks HTTP/1.0\r\nLast-Event-ID: 41\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        let mut event = String::new();
+        while !event.ends_with("\n\n") {
+            reader.read_line(&mut event).unwrap();
+        }
+        assert_eq!(event, "id: 42\nretry: 1000\ndata: 42\n\n");
+    }
+}
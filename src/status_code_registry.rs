@@ -182,7 +182,7 @@ impl StatusCodeRegistry {
     }
 
     fn get_reason_phrase(&self, status_code: u16) -> Option<ReasonPhrase> {
-        self.reason_phrase_lookup.get(&status_code).map(|x| *x)
+        self.reason_phrase_lookup.get(&status_code).copied()
     }
 
     fn get_status_code(&self, reason_phrase: ReasonPhrase) -> u16 {
@@ -17,10 +17,36 @@ impl<R: Read> StreamReader<R> {
         }
     }
 
+    pub fn get_ref(&self) -> &R {
+        self.buf_reader.get_ref().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.buf_reader.get_mut().get_mut()
+    }
+
     pub fn set_limit(&mut self, limit: u64) {
         self.buf_reader.set_limit(limit);
     }
 
+    pub fn limit(&self) -> u64 {
+        self.buf_reader.limit()
+    }
+
+    pub fn limit_reached(&self) -> bool {
+        self.limit() == 0
+    }
+
+    /// Returns whether data has been received but not read yet.
+    pub fn has_buffered_data(&self) -> bool {
+        !self.buf_reader.get_ref().buffer().is_empty()
+    }
+
+    /// Waits for data if none is buffered and returns the buffered data.
+    pub fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.buf_reader.fill_buf()
+    }
+
     pub fn read_line(&mut self, buf: &mut String) -> anyhow::Result<()> {
         let n = self.buf_reader.read_line(buf)?;
         if n == 0 {
@@ -28,9 +54,11 @@ impl<R: Read> StreamReader<R> {
         }
         Ok(())
     }
+}
 
-    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        self.buf_reader.read_exact(buf)
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.buf_reader.read(buf)
     }
 }
 
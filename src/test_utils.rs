@@ -1,5 +1,5 @@
 use std::{
-    io::{self, ErrorKind, Read},
+    io::{self, Read},
     iter::{self, Chain, Repeat},
 };
 
@@ -25,7 +25,7 @@ impl<'a, I: Iterator<Item = &'a u8>> Read for ErrReader<I> {
             buf[0] = *byte;
             return Ok(1);
         }
-        Err(io::Error::new(ErrorKind::Other, "error"))
+        Err(io::Error::other("error"))
     }
 }
 
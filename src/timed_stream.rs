@@ -0,0 +1,186 @@
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::stream::Stream;
+
+/// The minimum transfer rate is only enforced after this much time, so that
+/// slow starts don't count.
+pub const MIN_RATE_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+#[error("request timeout")]
+pub struct RequestTimeout;
+
+#[derive(Debug)]
+struct MinRate {
+    bytes_per_sec: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+/// Reads from a client connection within an overall deadline and above a minimum
+/// transfer rate, in addition to the timeout for each read.
+#[derive(Debug)]
+pub struct TimedStream<'a> {
+    stream: &'a Stream,
+    read_timeout: Duration,
+    deadline: Option<Instant>,
+    min_rate: Option<MinRate>,
+    timed_out: bool,
+}
+
+impl<'a> TimedStream<'a> {
+    pub fn new(stream: &'a Stream) -> Self {
+        Self {
+            stream,
+            read_timeout: Duration::from_secs(10),
+            deadline: None,
+            min_rate: None,
+            timed_out: false,
+        }
+    }
+
+    /// Applies `read_timeout` to each read and, if given, an overall
+    /// `deadline` counted from now. Resets the minimum transfer rate.
+    pub fn set_timeouts(&mut self, read_timeout: Duration, deadline: Option<Duration>) {
+        self.read_timeout = read_timeout;
+        self.deadline = deadline.map(|deadline| Instant::now() + deadline);
+        self.min_rate = None;
+        self.timed_out = false;
+    }
+
+    /// Requires at least `bytes_per_sec` on average from now on.
+    /// Zero disables the check.
+    pub fn set_min_rate(&mut self, bytes_per_sec: u64) {
+        self.min_rate = (bytes_per_sec > 0).then(|| MinRate {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        });
+    }
+
+    /// Returns whether a read failed because a timeout expired.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    fn check_rate(&self) -> bool {
+        let Some(min_rate) = &self.min_rate else {
+            return true;
+        };
+        let elapsed = min_rate.start.elapsed();
+        elapsed < MIN_RATE_GRACE
+            || min_rate.bytes as f64 >= min_rate.bytes_per_sec as f64 * elapsed.as_secs_f64()
+    }
+
+    fn read_timed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeout = self.read_timeout;
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, RequestTimeout));
+            }
+            timeout = timeout.min(remaining);
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+
+        let n = self.stream.read(buf)?;
+        if let Some(min_rate) = &mut self.min_rate {
+            min_rate.bytes += n as u64;
+        }
+        if !self.check_rate() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, RequestTimeout));
+        }
+        Ok(n)
+    }
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_timed(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                self.timed_out = true;
+                io::Error::new(io::ErrorKind::TimedOut, RequestTimeout)
+            }
+            _ => err,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::stream::Stream;
+
+    use super::TimedStream;
+
+    fn connect() -> (TcpStream, Stream) {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server.into())
+    }
+
+    fn trickle(mut client: TcpStream, interval: Duration) {
+        thread::spawn(move || {
+            while client.write_all(b"x").is_ok() {
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    #[test]
+    fn test_deadline() {
+        let (client, server) = connect();
+        trickle(client, Duration::from_millis(20));
+
+        let mut timed_stream = TimedStream::new(&server);
+        timed_stream.set_timeouts(Duration::from_secs(1), Some(Duration::from_millis(200)));
+
+        let start = Instant::now();
+        let err = io::copy(&mut timed_stream, &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(timed_stream.timed_out());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_min_rate() {
+        let (client, server) = connect();
+        trickle(client, Duration::from_millis(100));
+
+        let mut timed_stream = TimedStream::new(&server);
+        timed_stream.set_timeouts(Duration::from_secs(1), None);
+        timed_stream.set_min_rate(100);
+
+        let err = io::copy(&mut timed_stream, &mut io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(timed_stream.timed_out());
+    }
+
+    #[test]
+    fn test_read_within_limits() {
+        let (mut client, server) = connect();
+        client.write_all(b"hello").unwrap();
+        drop(client);
+
+        let mut timed_stream = TimedStream::new(&server);
+        timed_stream.set_timeouts(Duration::from_secs(1), Some(Duration::from_secs(1)));
+        timed_stream.set_min_rate(1);
+
+        let mut buf = String::new();
+        timed_stream.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello");
+        assert!(!timed_stream.timed_out());
+    }
+}
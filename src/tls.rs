@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use rustls::{
+    crypto::ring,
+    pki_types::CertificateDer,
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig, ServerConnection,
+};
+use serde::Deserialize;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::request::{ClientIdentity, SubjectAltName};
+
+/// Largest TLS record, including its header and the encryption overhead.
+const MAX_RECORD_LEN: usize = 5 + 16 * 1024 + 2048;
+
+/// Protocols advertised with ALPN, in order of preference.
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// Paths of a PEM certificate chain and its private key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A certificate for a server name, given as `<NAME>=<CERT>,<KEY>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniCert {
+    pub server_name: String,
+    pub paths: CertPaths,
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (server_name, paths) = s.split_once('=').ok_or("expected <NAME>=<CERT>,<KEY>")?;
+        let (cert, key) = paths.split_once(',').ok_or("expected <CERT>,<KEY>")?;
+        Ok(Self {
+            server_name: server_name.to_owned(),
+            paths: CertPaths {
+                cert: cert.into(),
+                key: key.into(),
+            },
+        })
+    }
+}
+
+/// Whether clients have to authenticate with a certificate.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    #[default]
+    Required,
+    /// Clients without a certificate are served anonymously.
+    Optional,
+}
+
+/// Verification of client certificates (mutual TLS).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    /// PEM bundle of the CAs that issue client certificates.
+    pub ca: PathBuf,
+    pub mode: ClientAuthMode,
+}
+
+/// Selects the certificate by the server name the client asked for (SNI),
+/// falling back to the default one.
+#[derive(Default)]
+pub struct CertResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("by_name", &self.by_name.keys())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+impl CertResolver {
+    pub fn set_default(&mut self, paths: &CertPaths) -> anyhow::Result<()> {
+        self.default = Some(load_certified_key(paths)?);
+        Ok(())
+    }
+
+    pub fn add_sni_cert(&mut self, sni_cert: &SniCert) -> anyhow::Result<()> {
+        let key = load_certified_key(&sni_cert.paths)?;
+        self.by_name
+            .insert(sni_cert.server_name.to_ascii_lowercase(), key);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty() && self.default.is_none()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("cannot open {}", path.display()))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(paths: &CertPaths) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(&paths.cert)?;
+    let key = rustls_pemfile::private_key(&mut open(&paths.key)?)
+        .with_context(|| format!("invalid private key {}", paths.key.display()))?
+        .ok_or_else(|| anyhow!("no private key in {}", paths.key.display()))?;
+    let key = ring::sign::any_supported_type(&key)
+        .with_context(|| format!("unsupported private key {}", paths.key.display()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+pub fn server_config(
+    resolver: CertResolver,
+    client_auth: Option<&ClientAuth>,
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_auth {
+        Some(client_auth) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&client_auth.ca)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA {}", client_auth.ca.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match client_auth.mode {
+                ClientAuthMode::Required => verifier,
+                ClientAuthMode::Optional => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// A TLS connection that can be read and written on different threads. The
+/// socket is never used while `conn` is locked, so a blocked reader doesn't
+/// hold up writers.
+#[derive(Debug)]
+pub struct TlsStream {
+    stream: TcpStream,
+    conn: Mutex<ServerConnection>,
+    /// Keeps the records of concurrent writers in order. Taken before
+    /// `conn`.
+    write_lock: Mutex<()>,
+}
+
+impl TlsStream {
+    pub fn new(stream: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self {
+            stream,
+            conn: Mutex::new(conn),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn handshake(&self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut &self.stream)?;
+        }
+        Ok(())
+    }
+
+    /// The protocol negotiated with ALPN.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .alpn_protocol()
+            .map(<[u8]>::to_vec)
+    }
+
+    /// The server name the client asked for.
+    #[allow(dead_code)]
+    pub fn server_name(&self) -> Option<String> {
+        self.conn.lock().unwrap().server_name().map(str::to_owned)
+    }
+
+    /// The verified certificate of the client, if it sent one.
+    pub fn client_identity(&self) -> Option<ClientIdentity> {
+        let conn = self.conn.lock().unwrap();
+        let cert = conn.peer_certificates()?.first()?;
+        parse_client_identity(cert)
+    }
+
+    pub fn send_close_notify(&self) -> io::Result<()> {
+        self.write_with(|conn| {
+            conn.send_close_notify();
+            Ok(())
+        })
+    }
+
+    /// Calls `f` with the connection and sends the records it produced.
+    fn write_with<T>(
+        &self,
+        f: impl FnOnce(&mut ServerConnection) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let _write_guard = self.write_lock.lock().unwrap();
+        let mut records = vec![];
+        let res = {
+            let mut conn = self.conn.lock().unwrap();
+            let res = f(&mut conn)?;
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+            res
+        };
+        (&self.stream).write_all(&records)?;
+        Ok(res)
+    }
+
+    /// Reads from the socket and decrypts what arrived.
+    fn receive(&self) -> io::Result<()> {
+        let mut buf = [0; MAX_RECORD_LEN];
+        let n = (&self.stream).read(&mut buf)?;
+        let mut input = &buf[..n];
+        let mut conn = self.conn.lock().unwrap();
+        loop {
+            // Reading nothing tells the connection about the end of input.
+            conn.read_tls(&mut input)?;
+            if let Err(err) = conn.process_new_packets() {
+                drop(conn);
+                // Best effort to send the alert.
+                let _ = self.write_with(|_| Ok(()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            if input.is_empty() {
+                break;
+            }
+        }
+        // Handshake messages like key updates are answered.
+        let wants_write = conn.wants_write();
+        drop(conn);
+        if wants_write {
+            self.write_with(|_| Ok(()))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_client_identity(cert: &CertificateDer) -> Option<ClientIdentity> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext.value.general_names.iter().filter_map(to_san).collect(),
+        _ => vec![],
+    };
+    Some(ClientIdentity {
+        subject: cert.subject().to_string(),
+        subject_alt_names,
+    })
+}
+
+fn to_san(name: &GeneralName) -> Option<SubjectAltName> {
+    let san = match name {
+        GeneralName::DNSName(name) => SubjectAltName::Dns(name.to_string()),
+        GeneralName::RFC822Name(name) => SubjectAltName::Email(name.to_string()),
+        GeneralName::URI(name) => SubjectAltName::Uri(name.to_string()),
+        GeneralName::IPAddress(ip) => SubjectAltName::Ip(match ip.len() {
+            4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?)),
+            16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?)),
+            _ => return None,
+        }),
+        _ => return None,
+    };
+    Some(san)
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                // The client closed the connection without close_notify.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(err) => return Err(err),
+            }
+            self.receive()?;
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with(|conn| conn.writer().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_with(|conn| conn.writer().flush())?;
+        (&self.stream).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{self, Read, Write},
+        net::{SocketAddr, TcpStream},
+        path::Path,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        SanType,
+    };
+    use rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
+    use tempdir::TempDir;
+
+    use crate::{
+        http2::tests::Client, request::Request, response_writer::ResponseWriter, server::Server,
+        status_code_registry::ReasonPhrase,
+    };
+
+    use super::{server_config, CertPaths, CertResolver, ClientAuth, ClientAuthMode, SniCert};
+
+    /// Writes a self-signed certificate for `server_name` and adds it to
+    /// `roots`.
+    fn generate_cert(dir: &TempDir, server_name: &str, roots: &mut RootCertStore) -> CertPaths {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec![server_name.to_owned()]).unwrap();
+        roots.add(cert.der().clone()).unwrap();
+
+        let paths = CertPaths {
+            cert: dir.path().join(format!("{}.crt", server_name)),
+            key: dir.path().join(format!("{}.key", server_name)),
+        };
+        fs::write(&paths.cert, cert.pem()).unwrap();
+        fs::write(&paths.key, key_pair.serialize_pem()).unwrap();
+        paths
+    }
+
+    /// Writes a CA certificate and returns a client certificate issued by it.
+    fn generate_client_cert(ca_path: &Path) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "test ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+        fs::write(ca_path, ca.pem()).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["client.internal".to_owned()]).unwrap();
+        let uri = "spiffe://test/client".try_into().unwrap();
+        params.subject_alt_names.push(SanType::URI(uri));
+        params.distinguished_name.push(DnType::CommonName, "client");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+        (cert.der().clone(), key)
+    }
+
+    struct TestServer {
+        addr: SocketAddr,
+        /// Client config without a client certificate.
+        anonymous: Arc<ClientConfig>,
+        /// Client config with a client certificate issued by the CA the
+        /// server trusts, if client authentication is enabled.
+        authenticated: Arc<ClientConfig>,
+    }
+
+    fn start_server(client_auth_mode: Option<ClientAuthMode>) -> TestServer {
+        let dir = TempDir::new("tls").unwrap();
+        let mut roots = RootCertStore::empty();
+        let mut resolver = CertResolver::default();
+        let paths = generate_cert(&dir, "localhost", &mut roots);
+        resolver.set_default(&paths).unwrap();
+        let paths = generate_cert(&dir, "example.com", &mut roots);
+        resolver
+            .add_sni_cert(&SniCert {
+                server_name: "example.com".into(),
+                paths,
+            })
+            .unwrap();
+
+        let ca = dir.path().join("ca.crt");
+        let (client_cert, client_key) = generate_client_cert(&ca);
+        let client_auth = client_auth_mode.map(|mode| ClientAuth { ca, mode });
+
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_tls_config(server_config(resolver, client_auth.as_ref()).unwrap());
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            server.run(|w: &mut ResponseWriter, r: &mut Request| {
+                let host = r
+                    .get_headers()
+                    .get_scalar("host")
+                    .unwrap()
+                    .unwrap_or_default();
+                let mut body = format!("{} {}", host, r.get_request_target());
+                if let Some(client_identity) = r.get_client_identity() {
+                    body += &format!(" {:?}", client_identity);
+                }
+                w.set_body_str(&body);
+                w.set_reason_phrase(ReasonPhrase::OK);
+            });
+        });
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let mut anonymous = builder.clone().with_no_client_auth();
+        anonymous.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let authenticated = builder
+            .with_client_auth_cert(vec![client_cert], client_key)
+            .unwrap();
+        TestServer {
+            addr,
+            anonymous: Arc::new(anonymous),
+            authenticated: Arc::new(authenticated),
+        }
+    }
+
+    /// Sends a GET request for `/` and returns the response.
+    fn get(addr: SocketAddr, config: &Arc<ClientConfig>) -> io::Result<String> {
+        let mut stream = connect(addr, config, "localhost");
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        Ok(resp)
+    }
+
+    fn connect(
+        addr: SocketAddr,
+        config: &Arc<ClientConfig>,
+        server_name: &str,
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let server_name = ServerName::try_from(server_name.to_owned()).unwrap();
+        let conn = ClientConnection::new(config.clone(), server_name).unwrap();
+        StreamOwned::new(conn, TcpStream::connect(addr).unwrap())
+    }
+
+    fn response_bodies(resp: &str) -> Vec<&str> {
+        resp.split("HTTP/1.1 ")
+            .skip(1)
+            .map(|resp| resp.split_once("\r\n\r\n").unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_https() {
+        let TestServer {
+            addr,
+            anonymous: config,
+            ..
+        } = start_server(None);
+
+        for server_name in ["localhost", "example.com"] {
+            let mut stream = connect(addr, &config, server_name);
+            let req = format!(
+                "GET /fst HTTP/1.1\r\nHost: {0}\r\n\r\n\
+                 GET /snd HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+                server_name
+            );
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+
+            assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+            assert_eq!(
+                response_bodies(&resp),
+                [
+                    format!("{} /fst", server_name),
+                    format!("{} /snd", server_name)
+                ]
+            );
+        }
+
+        // unknown names get the default certificate
+        let mut stream = connect(addr, &config, "other.example.com");
+        let err = stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap_err();
+        assert!(err.to_string().contains("certificate"), "{}", err);
+    }
+
+    #[test]
+    fn test_https_h2() {
+        let TestServer {
+            addr, anonymous, ..
+        } = start_server(None);
+        let mut config = (*anonymous).clone();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let mut stream = connect(addr, &Arc::new(config), "example.com");
+        stream.conn.complete_io(&mut stream.sock).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+
+        // Stream threads write while the connection thread waits for
+        // frames, which used to stall until the read timed out.
+        let start = Instant::now();
+        let mut client = Client::new(stream, true);
+        let fst = client.get("example.com", "/fst");
+        let snd = client.get("example.com", "/snd");
+        let mut bodies: Vec<_> = client
+            .read_responses(&[fst, snd])
+            .into_iter()
+            .map(|(_, response)| String::from_utf8(response.body).unwrap())
+            .collect();
+        bodies.sort();
+        assert_eq!(bodies, ["example.com /fst", "example.com /snd"]);
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_https_h2_many_streams() {
+        let TestServer {
+            addr, anonymous, ..
+        } = start_server(None);
+        let mut config = (*anonymous).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let start = Instant::now();
+        let mut client = Client::new(connect(addr, &Arc::new(config), "localhost"), true);
+        let ids: Vec<_> = (0..32)
+            .map(|i| client.get("localhost", &format!("/{}", i)))
+            .collect();
+        let responses = client.read_responses(&ids);
+        assert_eq!(responses.len(), ids.len());
+        for (id, response) in responses {
+            let i = ids.iter().position(|&x| x == id).unwrap();
+            assert_eq!(response.body, format!("localhost /{}", i).as_bytes());
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_client_auth_required() {
+        let TestServer {
+            addr,
+            anonymous,
+            authenticated,
+        } = start_server(Some(ClientAuthMode::Required));
+
+        let resp = get(addr, &authenticated).unwrap();
+        assert!(
+            resp.ends_with(
+                "localhost / ClientIdentity { subject: \"CN=client\", subject_alt_names: \
+             [Dns(\"client.internal\"), Uri(\"spiffe://test/client\")] }"
+            ),
+            "{}",
+            resp
+        );
+
+        let err = get(addr, &anonymous).unwrap_err();
+        assert!(err.to_string().contains("CertificateRequired"), "{}", err);
+    }
+
+    #[test]
+    fn test_client_auth_optional() {
+        let TestServer {
+            addr,
+            anonymous,
+            authenticated,
+        } = start_server(Some(ClientAuthMode::Optional));
+
+        let resp = get(addr, &authenticated).unwrap();
+        assert!(resp.contains("ClientIdentity"), "{}", resp);
+
+        let resp = get(addr, &anonymous).unwrap();
+        assert!(resp.ends_with("\r\n\r\nlocalhost /"), "{}", resp);
+    }
+
+    #[test]
+    fn test_sni_cert_from_str() {
+        let sni_cert: SniCert = "example.com=a.crt,a.key".parse().unwrap();
+        assert_eq!(sni_cert.server_name, "example.com");
+        assert_eq!(sni_cert.paths.cert.to_str(), Some("a.crt"));
+        assert_eq!(sni_cert.paths.key.to_str(), Some("a.key"));
+
+        assert!("example.com".parse::<SniCert>().is_err());
+        assert!("example.com=a.crt".parse::<SniCert>().is_err());
+    }
+}
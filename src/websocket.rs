@@ -0,0 +1,657 @@
+//! WebSocket connections (RFC 6455).
+
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{
+    request::{HttpVersion, Request},
+    response_writer::ResponseWriter,
+    server::{Handler, Upgraded},
+    status_code_registry::ReasonPhrase,
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A violation of the protocol, failing the connection with a close frame
+/// carrying the status code.
+#[derive(Error, Debug)]
+#[error("websocket protocol violation: {0}")]
+pub struct ProtocolViolation(pub u16);
+
+fn violation(code: u16) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ProtocolViolation(code))
+}
+
+/// The `Sec-WebSocket-Accept` value for `key` (RFC 6455, section 4.2.2).
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Checks the opening handshake of `r` and returns its `Sec-WebSocket-Key`
+/// (RFC 6455, section 4.2.1).
+fn check_handshake<'r>(r: &'r Request) -> Result<&'r str, ReasonPhrase> {
+    let headers = r.get_headers();
+    if r.get_http_version() != HttpVersion::Http11
+        || !has_token(headers.get_upgrade(), "websocket")
+        || !has_token(headers.get_connection(), "upgrade")
+        || headers.get_sec_websocket_version().ok().flatten() != Some(VERSION)
+    {
+        return Err(ReasonPhrase::UpgradeRequired);
+    }
+    if !r.get_http_method().eq_ignore_ascii_case("get") {
+        return Err(ReasonPhrase::BadRequest);
+    }
+
+    let Ok(Some(key)) = headers.get_sec_websocket_key() else {
+        return Err(ReasonPhrase::BadRequest);
+    };
+    match STANDARD.decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(key),
+        _ => Err(ReasonPhrase::BadRequest),
+    }
+}
+
+fn has_token<'v>(values: Option<impl Iterator<Item = &'v str>>, token: &str) -> bool {
+    values.is_some_and(|mut it| it.any(|v| v.eq_ignore_ascii_case(token)))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(fin: bool, opcode: u8, payload: Vec<u8>) -> Self {
+        Self {
+            fin,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Reads a frame whose payload is at most `max_size` bytes. Frames of
+    /// clients have to be `masked`, those of servers must not be. Returns
+    /// `None` if the peer closed the connection between frames.
+    pub fn read_from(
+        reader: &mut impl Read,
+        masked: bool,
+        max_size: usize,
+    ) -> io::Result<Option<Self>> {
+        let mut head = [0; 2];
+        if reader.read(&mut head[..1])? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut head[1..])?;
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 || (head[1] & 0x80 != 0) != masked {
+            return Err(violation(PROTOCOL_ERROR));
+        }
+        let is_control = opcode & 0x8 != 0;
+        if !matches!(opcode, CONTINUATION | TEXT | BINARY | CLOSE | PING | PONG) {
+            return Err(violation(PROTOCOL_ERROR));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if is_control && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(violation(PROTOCOL_ERROR));
+        }
+        if len > max_size as u64 {
+            return Err(violation(MESSAGE_TOO_BIG));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+        apply_mask(&mut payload, mask);
+        Ok(Some(Self::new(fin, opcode, payload)))
+    }
+
+    /// Servers send unmasked frames, clients mask them with `mask`.
+    pub fn write_to(&self, writer: &mut impl Write, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let len = self.payload.len();
+        let mut buf = Vec::with_capacity(14 + len);
+        buf.push((self.fin as u8) << 7 | self.opcode);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        if len < 126 {
+            buf.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            buf.push(mask_bit | 126);
+            buf.extend((len as u16).to_be_bytes());
+        } else {
+            buf.push(mask_bit | 127);
+            buf.extend((len as u64).to_be_bytes());
+        }
+
+        let start = buf.len();
+        if let Some(mask) = mask {
+            buf.extend(mask);
+        }
+        buf.extend(&self.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut buf[start + 4..], mask);
+        }
+        writer.write_all(&buf)
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Already answered with a pong.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason the peer closed the connection with.
+    Close(Option<(u16, String)>),
+}
+
+/// The server side of a WebSocket connection.
+pub struct WebSocket<'a> {
+    conn: Upgraded<'a>,
+    max_message_size: usize,
+    max_frame_size: usize,
+    /// The opcode and payload of a fragmented message being received.
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+    /// Reading failed, so no close frame of the peer is waited for.
+    broken: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(conn: Upgraded<'a>) -> Self {
+        Self {
+            conn,
+            max_message_size: 16 * 1024 * 1024,
+            max_frame_size: 64 * 1024,
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+            broken: false,
+        }
+    }
+
+    /// Larger messages fail the connection with `1009 Message Too Big`.
+    #[allow(dead_code)]
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Larger messages are sent in fragments.
+    #[allow(dead_code)]
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size.max(1);
+    }
+
+    /// Receives the next message, answering pings and close frames. Returns
+    /// `None` once the connection is closed.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.close_received || self.broken {
+            return Ok(None);
+        }
+        let res = self.read_message();
+        if let Err(err) = &res {
+            self.broken = true;
+            if let Some(violation) = err
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<ProtocolViolation>())
+            {
+                debug!(code = violation.0, "failing the websocket connection");
+                if let Err(err) = self.send_close(violation.0, "") {
+                    debug!(?err);
+                }
+            }
+        }
+        res
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            let Some(frame) = Frame::read_from(&mut self.conn.reader, true, self.max_message_size)?
+            else {
+                self.broken = true;
+                return Ok(None);
+            };
+            match (frame.opcode, &mut self.fragments) {
+                (PING, _) => {
+                    self.write_frame(&Frame::new(true, PONG, frame.payload.clone()))?;
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                (PONG, _) => return Ok(Some(Message::Pong(frame.payload))),
+                (CLOSE, _) => {
+                    self.close_received = true;
+                    let close = parse_close(&frame.payload)?;
+                    if !self.close_sent {
+                        let code = close.as_ref().map_or(NORMAL_CLOSURE, |(code, _)| *code);
+                        self.send_close(code, "")?;
+                    }
+                    return Ok(Some(Message::Close(close)));
+                }
+                (TEXT | BINARY, None) if frame.fin => {
+                    return to_message(frame.opcode, frame.payload).map(Some)
+                }
+                (TEXT | BINARY, None) => self.fragments = Some((frame.opcode, frame.payload)),
+                (CONTINUATION, Some((_, buf))) => {
+                    if buf.len() + frame.payload.len() > self.max_message_size {
+                        return Err(violation(MESSAGE_TOO_BIG));
+                    }
+                    buf.extend(frame.payload);
+                    if frame.fin {
+                        let (opcode, payload) = self.fragments.take().unwrap();
+                        return to_message(opcode, payload).map(Some);
+                    }
+                }
+                _ => return Err(violation(PROTOCOL_ERROR)),
+            }
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send_message(TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_message(BINARY, data)
+    }
+
+    #[allow(dead_code)]
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.write_frame(&Frame::new(true, PING, payload.to_vec()))
+    }
+
+    fn send_message(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut chunks = payload.chunks(self.max_frame_size).peekable();
+        if chunks.peek().is_none() {
+            return self.write_frame(&Frame::new(true, opcode, vec![]));
+        }
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            let fin = chunks.peek().is_none();
+            self.write_frame(&Frame::new(fin, opcode, chunk.to_vec()))?;
+            opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    /// Starts the closing handshake and waits for the close frame of the
+    /// peer, discarding the messages received meanwhile.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.close_sent {
+            self.send_close(code, reason)?;
+        }
+        while self.recv()?.is_some() {}
+        Ok(())
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend(reason.bytes().take(MAX_CONTROL_PAYLOAD - 2));
+        self.write_frame(&Frame::new(true, CLOSE, payload))?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        frame.write_to(&mut self.conn.writer, None)
+    }
+}
+
+fn to_message(opcode: u8, payload: Vec<u8>) -> io::Result<Message> {
+    match opcode {
+        TEXT => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| violation(INVALID_PAYLOAD)),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+/// Parses the payload of a close frame (RFC 6455, section 5.5.1).
+fn parse_close(payload: &[u8]) -> io::Result<Option<(u16, String)>> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err(violation(PROTOCOL_ERROR)),
+        [hi, lo, reason @ ..] => {
+            let code = u16::from_be_bytes([*hi, *lo]);
+            let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+            if !valid {
+                return Err(violation(PROTOCOL_ERROR));
+            }
+            let reason =
+                String::from_utf8(reason.to_vec()).map_err(|_| violation(INVALID_PAYLOAD))?;
+            Ok(Some((code, reason)))
+        }
+    }
+}
+
+pub trait WebSocketHandler {
+    fn handle(&self, ws: &mut WebSocket, r: &Request);
+}
+
+impl<T> WebSocketHandler for T
+where
+    T: Fn(&mut WebSocket, &Request),
+{
+    fn handle(&self, ws: &mut WebSocket, r: &Request) {
+        self(ws, r)
+    }
+}
+
+/// A route accepting WebSocket connections. After the handshake, the
+/// connection is handed to the handler and closed once it returns.
+pub struct WebSocketRoute<H>(Arc<H>);
+
+impl<H> WebSocketRoute<H> {
+    pub fn new(handler: H) -> Self {
+        Self(Arc::new(handler))
+    }
+}
+
+impl<H> Handler for WebSocketRoute<H>
+where
+    H: WebSocketHandler + Send + Sync + 'static,
+{
+    fn handle(&self, w: &mut ResponseWriter, r: &mut Request) {
+        let key = match check_handshake(r) {
+            Ok(key) => key,
+            Err(reason_phrase) => {
+                if reason_phrase == ReasonPhrase::UpgradeRequired {
+                    w.add_connection_header("Upgrade");
+                    w.add_upgrade_header("websocket");
+                    w.add_sec_websocket_version_header(VERSION);
+                }
+                w.set_reason_phrase(reason_phrase);
+                return;
+            }
+        };
+
+        let handler = self.0.clone();
+        w.set_upgrade("websocket", move |r, conn| {
+            let mut ws = WebSocket::new(conn);
+            handler.handle(&mut ws, r);
+            if let Err(err) = ws.close(NORMAL_CLOSURE, "") {
+                debug!(?err);
+            }
+        });
+        w.add_sec_websocket_accept_header(&accept_key(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+    };
+
+    use crate::{
+        echo_ws,
+        router::Router,
+        server::{Backend, HttpMethod, Server},
+    };
+
+    use super::{
+        accept_key, Frame, WebSocketRoute, BINARY, CLOSE, CONTINUATION, MESSAGE_TOO_BIG, PING,
+        PONG, PROTOCOL_ERROR, TEXT,
+    };
+
+    const MASK: Option<[u8; 4]> = Some([1, 2, 3, 4]);
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let frame = Frame::new(true, BINARY, vec![7; len]);
+            let mut buf = vec![];
+            frame.write_to(&mut buf, MASK).unwrap();
+            let read = Frame::read_from(&mut Cursor::new(&buf), true, 1 << 20).unwrap();
+            assert_eq!(read.unwrap(), frame);
+
+            let mut buf = vec![];
+            frame.write_to(&mut buf, None).unwrap();
+            let read = Frame::read_from(&mut Cursor::new(&buf), false, 1 << 20).unwrap();
+            assert_eq!(read.unwrap(), frame);
+        }
+
+        // RFC 6455, section 5.7
+        let mut buf = vec![];
+        Frame::new(true, TEXT, b"Hello".to_vec())
+            .write_to(&mut buf, Some([0x37, 0xfa, 0x21, 0x3d]))
+            .unwrap();
+        assert_eq!(buf, b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58");
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let violation = |bytes: &[u8], max_size| {
+            let err = Frame::read_from(&mut Cursor::new(bytes), true, max_size).unwrap_err();
+            err.into_inner()
+                .unwrap()
+                .downcast::<super::ProtocolViolation>()
+                .unwrap()
+                .0
+        };
+        // unmasked
+        assert_eq!(violation(b"\x81\x00", 10), PROTOCOL_ERROR);
+        // reserved bit
+        assert_eq!(violation(b"\xc1\x80\0\0\0\0", 10), PROTOCOL_ERROR);
+        // unknown opcode
+        assert_eq!(violation(b"\x83\x80\0\0\0\0", 10), PROTOCOL_ERROR);
+        // fragmented control frame
+        assert_eq!(violation(b"\x09\x80\0\0\0\0", 10), PROTOCOL_ERROR);
+        assert_eq!(violation(b"\x82\x8b\0\0\0\0", 10), MESSAGE_TOO_BIG);
+    }
+
+    fn start_server() -> SocketAddr {
+        start_server_with_backend(Backend::Threaded)
+    }
+
+    fn start_server_with_backend(backend: Backend) -> SocketAddr {
+        let mut server = Server::bind("localhost:0").unwrap();
+        server.set_backend(backend);
+        let addr = server.local_addr();
+        thread::spawn(move || {
+            let route = WebSocketRoute::new(echo_ws);
+            let mut router = Router::new();
+            router.add_route(HttpMethod::Get, "/ws", &route);
+            server.run(router);
+        });
+        addr
+    }
+
+    /// Sends `request` and returns the response head.
+    fn send_head(stream: &mut TcpStream, request: &str) -> String {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut head = vec![];
+        let mut byte = [0];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn handshake(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = send_head(
+            &mut stream,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Upgrade: websocket\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        stream
+    }
+
+    fn send(stream: &mut TcpStream, fin: bool, opcode: u8, payload: &[u8]) {
+        Frame::new(fin, opcode, payload.to_vec())
+            .write_to(stream, MASK)
+            .unwrap();
+    }
+
+    fn recv(stream: &mut TcpStream) -> Frame {
+        Frame::read_from(stream, false, 1 << 20).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_echo() {
+        let addr = start_server();
+        let mut stream = handshake(addr);
+
+        send(&mut stream, true, TEXT, b"hello");
+        assert_eq!(recv(&mut stream), Frame::new(true, TEXT, b"hello".to_vec()));
+
+        send(&mut stream, true, PING, b"p");
+        assert_eq!(recv(&mut stream), Frame::new(true, PONG, b"p".to_vec()));
+
+        // a ping between the fragments of a message
+        send(&mut stream, false, BINARY, b"ab");
+        send(&mut stream, true, PING, b"");
+        send(&mut stream, false, CONTINUATION, b"cd");
+        send(&mut stream, true, CONTINUATION, b"ef");
+        assert_eq!(recv(&mut stream).opcode, PONG);
+        assert_eq!(
+            recv(&mut stream),
+            Frame::new(true, BINARY, b"abcdef".to_vec())
+        );
+
+        send(&mut stream, true, CLOSE, b"\x03\xe8bye");
+        assert_eq!(
+            recv(&mut stream),
+            Frame::new(true, CLOSE, b"\x03\xe8".to_vec())
+        );
+        assert!(Frame::read_from(&mut stream, false, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_protocol_violation() {
+        let addr = start_server();
+
+        let mut stream = handshake(addr);
+        Frame::new(true, TEXT, b"unmasked".to_vec())
+            .write_to(&mut stream, None)
+            .unwrap();
+        assert_eq!(
+            recv(&mut stream),
+            Frame::new(true, CLOSE, b"\x03\xea".to_vec())
+        );
+
+        let mut stream = handshake(addr);
+        send(&mut stream, true, CONTINUATION, b"no message");
+        assert_eq!(
+            recv(&mut stream),
+            Frame::new(true, CLOSE, b"\x03\xea".to_vec())
+        );
+
+        let mut stream = handshake(addr);
+        send(&mut stream, true, TEXT, b"\xff");
+        assert_eq!(
+            recv(&mut stream),
+            Frame::new(true, CLOSE, b"\x03\xef".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_handshake_rejected() {
+        let addr = start_server();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = send_head(
+            &mut stream,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 8\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(head.contains("Sec-WebSocket-Version: 13\r\n"));
+
+        // the connection stays in HTTP/1.1
+        let head = send_head(
+            &mut stream,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: c2hvcnQ=\r\n\r\n",
+        );
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_upgrade_not_supported() {
+        let addr = start_server_with_backend(Backend::EventLoop);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = send_head(
+            &mut stream,
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        assert!(head.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(!head.contains("Upgrade"));
+    }
+}